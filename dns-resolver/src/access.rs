@@ -0,0 +1,146 @@
+use crate::config::AccessConfig;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// A block of addresses in CIDR notation, such as 10.0.0.0/8 or fd00::/8.
+struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    // Parse a block, or a single address, which stands for a block of just itself.
+    fn parse(cidr: &str) -> Result<Cidr, String> {
+        let invalid = || format!("Invalid address block {}", cidr);
+        let (address, prefix_len) = match cidr.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (cidr, None),
+        };
+        let network: IpAddr = address.parse().map_err(|_| invalid())?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().map_err(|_| invalid())?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+        Ok(Cidr {
+            network,
+            prefix_len,
+        })
+    }
+
+    fn contains(&self, address: IpAddr) -> bool {
+        let (network, address, bits) = match (self.network, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => (
+                u128::from(u32::from(network)),
+                u128::from(u32::from(address)),
+                32,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                (u128::from(network), u128::from(address), 128)
+            }
+            _ => return false,
+        };
+        let host_bits = bits - u32::from(self.prefix_len);
+        network.checked_shr(host_bits).unwrap_or(0) == address.checked_shr(host_bits).unwrap_or(0)
+    }
+}
+
+// How many queries a client may still send right away, refilled at a steady rate up to
+// the burst size (a token bucket).
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+    // Whether the client's last query was over the limit, so that only the start of a
+    // run of dropped queries is logged.
+    limited: bool,
+}
+
+// Which clients may use the resolver, and how fast.
+//
+// A client is allowed if the most specific block it is in, among both the allowed and
+// the denied ones, is an allowed one; clients in none of them are refused. Each client
+// address gets its own query rate limit.
+pub struct Access {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    queries_per_sec: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl Access {
+    pub fn new(config: &AccessConfig) -> Result<Access, String> {
+        let parse = |blocks: &[String]| -> Result<Vec<Cidr>, String> {
+            blocks.iter().map(|b| Cidr::parse(b)).collect()
+        };
+        Ok(Access {
+            allow: parse(&config.allow)?,
+            deny: parse(&config.deny)?,
+            queries_per_sec: f64::from(config.rate_limit_qps),
+            burst: f64::from(config.rate_limit_burst.max(1)),
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Whether a client may have its queries resolved.
+    pub fn allows(&self, client: IpAddr) -> bool {
+        let most_specific = |blocks: &[Cidr]| {
+            blocks
+                .iter()
+                .filter(|block| block.contains(client))
+                .map(|block| block.prefix_len)
+                .max()
+        };
+        match (most_specific(&self.allow), most_specific(&self.deny)) {
+            (Some(allowed), Some(denied)) => allowed > denied,
+            (allowed, _) => allowed.is_some(),
+        }
+    }
+
+    // Take a query from the client's allowance, or return false if it has used it up.
+    // Always true when rate limiting is off.
+    pub fn within_rate(&self, client: IpAddr) -> bool {
+        if self.queries_per_sec == 0.0 {
+            return true;
+        }
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: self.burst,
+            refilled_at: now,
+            limited: false,
+        });
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.queries_per_sec).min(self.burst);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.limited = false;
+            return true;
+        }
+        if !bucket.limited {
+            eprintln!(
+                "Rate limiting {}: over {} queries per second",
+                client, self.queries_per_sec
+            );
+            bucket.limited = true;
+        }
+        false
+    }
+
+    // Forget the clients that have been quiet long enough to be back to a full
+    // allowance, so that their buckets do not pile up.
+    pub fn sweep(&self) {
+        let now = Instant::now();
+        self.buckets.lock().unwrap().retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+            bucket.tokens + elapsed * self.queries_per_sec < self.burst
+        });
+    }
+}
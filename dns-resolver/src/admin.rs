@@ -0,0 +1,299 @@
+use crate::dnssec::Security;
+use crate::forwarder::Forwarder;
+use crate::message::{
+    Answer, Record, RecordData, TYPE_A, TYPE_AAAA, TYPE_CNAME, TYPE_DNSKEY, TYPE_DS, TYPE_MX,
+    TYPE_NS, TYPE_PTR, TYPE_SOA,
+};
+use crate::trace::{self, Step};
+use crate::{lookup, reload_config, Server};
+use serde::Serialize;
+use std::fmt::Write;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+// The largest request read; the admin requests have no body, so this is plenty.
+const MAX_REQUEST: usize = 4096;
+
+const TEXT: &str = "text/plain";
+const JSON: &str = "application/json";
+
+// A response to an admin request: the status line's code and reason, the content type
+// and the body.
+type Response = (u16, &'static str, &'static str, String);
+
+// How a question was answered, step by step, as returned by GET /trace.
+#[derive(Serialize)]
+struct TraceReport {
+    name: String,
+    qtype: u16,
+    // The outcome: the number of records, NODATA or NXDOMAIN, or SERVFAIL if the
+    // question could not be answered, in which case `error` says why.
+    answer: String,
+    records: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    security: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    duration_ms: f64,
+    steps: Vec<Step>,
+}
+
+// Serve the admin interface, a small HTTP API meant to be reached from the machine the
+// resolver runs on:
+//
+//   GET  /cache                       every cache entry with its remaining TTL
+//   POST /cache/flush                 flush the whole cache
+//   POST /cache/flush?name=<name>     flush the entries of one name
+//   POST /cache/flush?suffix=<name>   flush the entries of a name and every name below it
+//   GET  /upstreams                   the health and round-trip times of the upstreams
+//                                     and of the authoritative servers
+//   GET  /policies                    how often each response policy has matched
+//   POST /reload                      reload the configuration
+//   GET  /trace?name=<name>&type=<t>  answer a question as a client would be answered,
+//                                     returning each step taken as JSON: local answers,
+//                                     policy rewrites, cache hits, and every upstream or
+//                                     authoritative server asked, with its response code
+//                                     and round-trip time. The type is a number or a
+//                                     name such as AAAA, and defaults to A.
+pub async fn run(server: Arc<Server>, listener: TcpListener) {
+    loop {
+        let (stream, client_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("Failed to accept admin connection: {}", e);
+                continue;
+            }
+        };
+        let server = server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(&server, stream).await {
+                eprintln!("Admin connection from {} failed: {}", client_addr, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(server: &Arc<Server>, mut stream: TcpStream) -> std::io::Result<()> {
+    // Read up to the end of the headers
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+    let method = request_line.next().unwrap_or("");
+    let target = request_line.next().unwrap_or("/");
+    println!("Admin request: {} {}", method, target);
+
+    let (status, reason, content_type, body) = handle_request(server, method, target).await;
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+async fn handle_request(server: &Arc<Server>, method: &str, target: &str) -> Response {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let param = |key: &str| {
+        query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(k, _)| *k == key)
+            .map(|(_, value)| value.trim_end_matches('.').to_ascii_lowercase())
+    };
+
+    match (method, path) {
+        ("GET", "/cache") => {
+            let mut body = String::new();
+            for line in server.cache.dump() {
+                body.push_str(&line);
+                body.push('\n');
+            }
+            (200, "OK", TEXT, body)
+        }
+        ("POST", "/cache/flush") => {
+            let (flushed, what) = match (param("name"), param("suffix")) {
+                (Some(name), _) => (server.cache.flush(&name, false), name),
+                (None, Some(suffix)) => {
+                    let flushed = server.cache.flush(&suffix, true);
+                    (flushed, format!("{} and below", suffix))
+                }
+                (None, None) => (server.cache.flush_all(), "the whole cache".to_string()),
+            };
+            println!("Flushed {} cache entries for {}", flushed, what);
+            (200, "OK", TEXT, format!("Flushed {} entries\n", flushed))
+        }
+        ("GET", "/upstreams") => {
+            let settings = server.settings();
+            let mut body = String::new();
+            describe_upstreams(&mut body, "default", &settings.forwarder);
+            for (zone, forwarder) in &settings.forward_zones {
+                describe_upstreams(&mut body, zone, forwarder);
+            }
+            // The authoritative servers met while resolving iteratively, with the
+            // round-trip time expected of each including any penalty for failures
+            let _ = writeln!(body, "authoritative servers:");
+            for (address, rtt) in server.resolver.server_rtts() {
+                let smoothed = match rtt.smoothed() {
+                    Some(smoothed) => format!("rtt {:.1?}", smoothed),
+                    None => "no answers yet".to_string(),
+                };
+                let _ = writeln!(
+                    body,
+                    "  {}: {}, expected {:.1?}",
+                    address,
+                    smoothed,
+                    rtt.estimate()
+                );
+            }
+            (200, "OK", TEXT, body)
+        }
+        ("GET", "/policies") => {
+            let mut body = String::new();
+            for (name, hits) in server.settings().policies.hits() {
+                let _ = writeln!(body, "{}: {} hits", name, hits);
+            }
+            (200, "OK", TEXT, body)
+        }
+        ("POST", "/reload") => match reload_config(server).await {
+            Ok(()) => (200, "OK", TEXT, "Reloaded the configuration\n".to_string()),
+            Err(e) => {
+                eprintln!("Failed to reload the configuration: {}", e);
+                (
+                    500,
+                    "Internal Server Error",
+                    TEXT,
+                    format!("Failed to reload the configuration: {}\n", e),
+                )
+            }
+        },
+        ("GET", "/trace") => {
+            let Some(name) = param("name") else {
+                return (400, "Bad Request", TEXT, "Missing name\n".to_string());
+            };
+            let qtype = match param("type").map(|qtype| parse_type(&qtype)) {
+                None => TYPE_A,
+                Some(Some(qtype)) => qtype,
+                Some(None) => return (400, "Bad Request", TEXT, "Unknown type\n".to_string()),
+            };
+            let report = trace_lookup(server, name, qtype).await;
+            match serde_json::to_string_pretty(&report) {
+                Ok(body) => (200, "OK", JSON, body + "\n"),
+                Err(e) => (500, "Internal Server Error", TEXT, format!("{}\n", e)),
+            }
+        }
+        (_, "/cache" | "/cache/flush" | "/upstreams" | "/policies" | "/reload" | "/trace") => (
+            405,
+            "Method Not Allowed",
+            TEXT,
+            "Method not allowed\n".to_string(),
+        ),
+        _ => (404, "Not Found", TEXT, "Not found\n".to_string()),
+    }
+}
+
+fn describe_upstreams(body: &mut String, zone: &str, forwarder: &Forwarder) {
+    let _ = writeln!(body, "{}:", zone);
+    for upstream in forwarder.health() {
+        let state = match upstream.backed_off_for {
+            Some(remaining) => format!("backed off for {}s", remaining.as_secs()),
+            None => "healthy".to_string(),
+        };
+        let rtt = match upstream.rtt {
+            Some(rtt) => format!("rtt {:.1?}", rtt),
+            None => "no answers yet".to_string(),
+        };
+        let _ = writeln!(
+            body,
+            "  {}: {}, {} consecutive failures, {}",
+            upstream.address, state, upstream.consecutive_failures, rtt
+        );
+    }
+}
+
+// Look a question up the way a client's question is, without a client subnet, while
+// tracing it. Answers already cached show up as cache hits, so flush the name first to
+// see how it is resolved.
+async fn trace_lookup(server: &Arc<Server>, name: String, qtype: u16) -> TraceReport {
+    println!("Tracing {} (type {})", name, qtype);
+    let (result, steps, elapsed) = trace::traced(lookup(server, &name, qtype, None)).await;
+    let (answer, records, security, error) = match result {
+        Ok((chain, answer, security)) => {
+            let records = match &answer {
+                Answer::Records(records) => records.iter().map(describe_record).collect(),
+                Answer::NoData(soa) | Answer::NxDomain(soa) => {
+                    chain.iter().chain(soa).map(describe_record).collect()
+                }
+            };
+            let security = match security {
+                Security::Secure => "secure".to_string(),
+                Security::Insecure => "insecure".to_string(),
+                Security::Bogus(reason) => format!("bogus: {}", reason),
+            };
+            (answer.to_string(), records, Some(security), None)
+        }
+        Err(e) => ("SERVFAIL".to_string(), Vec::new(), None, Some(e)),
+    };
+    TraceReport {
+        name,
+        qtype,
+        answer,
+        records,
+        security,
+        error,
+        duration_ms: trace::millis(elapsed),
+        steps,
+    }
+}
+
+// A record type given by number or by name.
+fn parse_type(qtype: &str) -> Option<u16> {
+    let qtype = match qtype {
+        "a" => TYPE_A,
+        "ns" => TYPE_NS,
+        "cname" => TYPE_CNAME,
+        "soa" => TYPE_SOA,
+        "ptr" => TYPE_PTR,
+        "mx" => TYPE_MX,
+        "aaaa" => TYPE_AAAA,
+        "ds" => TYPE_DS,
+        "dnskey" => TYPE_DNSKEY,
+        _ => return qtype.parse().ok(),
+    };
+    Some(qtype)
+}
+
+// A record on one line, in roughly the presentation format.
+fn describe_record(record: &Record) -> String {
+    let data = match &record.data {
+        RecordData::A(address) => address.to_string(),
+        RecordData::AAAA(address) => address.to_string(),
+        RecordData::NS(name) | RecordData::CNAME(name) | RecordData::PTR(name) => name.clone(),
+        RecordData::SOA(soa) => format!(
+            "{} {} {} {} {} {} {}",
+            soa.mname, soa.rname, soa.serial, soa.refresh, soa.retry, soa.expire, soa.minimum
+        ),
+        RecordData::MX {
+            preference,
+            exchange,
+        } => format!("{} {}", preference, exchange),
+        RecordData::Other(rdata) => format!("({} bytes)", rdata.len()),
+    };
+    format!(
+        "{} {} type {} {}",
+        record.name, record.ttl, record.record_type, data
+    )
+}
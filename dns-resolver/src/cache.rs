@@ -0,0 +1,750 @@
+use crate::config::CacheConfig;
+use crate::message::{
+    group_rrsets, is_subdomain, read_name, read_record, write_name, Answer, Message, Record,
+    RecordData, CLASS_IN, TYPE_CNAME,
+};
+use crate::subnet::ClientSubnet;
+use std::collections::{BTreeMap, HashMap};
+use std::mem::size_of;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Marks the start of a cache snapshot, followed by the format version.
+const SNAPSHOT_MAGIC: &[u8] = b"DNSCACHE";
+const SNAPSHOT_VERSION: u8 = 1;
+
+// Entries are RRsets (or the absence of one), keyed by name, type and class.
+type Key = (String, u16, u16);
+
+// The type that NXDOMAIN is cached under. A name that does not exist has no records of
+// any type (RFC 2308 section 5), so it is cached once for the name, under a type that no
+// question asks for.
+const TYPE_NXDOMAIN: u16 = 0;
+
+// How far cached data can be trusted, following the ranking in RFC 2181 section 5.4.1,
+// lowest first. Data only replaces cached data of the same or a lower rank.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Trust {
+    // Glue and other additional section data, and the authority section of
+    // non-authoritative responses such as referrals. Never returned as an answer.
+    Additional,
+    // The answer section of a non-authoritative response.
+    NonAuthAnswer,
+    // The answer section of an authoritative response.
+    AuthAnswer,
+    // An answer validated with DNSSEC (RFC 4035 section 4.3).
+    Secure,
+}
+
+impl Trust {
+    fn from_u8(value: u8) -> Option<Trust> {
+        match value {
+            0 => Some(Trust::Additional),
+            1 => Some(Trust::NonAuthAnswer),
+            2 => Some(Trust::AuthAnswer),
+            3 => Some(Trust::Secure),
+            _ => None,
+        }
+    }
+
+    // How far the answer section of a response can be trusted.
+    pub fn of_answer(response: &Message) -> Trust {
+        if response.is_authoritative() {
+            Trust::AuthAnswer
+        } else {
+            Trust::NonAuthAnswer
+        }
+    }
+}
+
+enum Cached {
+    RRset(Vec<Record>),
+    // The name has no records of this type; the SOA says for how long.
+    NoData(Record),
+    // The name does not exist.
+    NxDomain(Record),
+}
+
+struct CacheEntry {
+    // The data as it was cached, with the TTLs it had then.
+    data: Cached,
+    trust: Trust,
+    stored_at: u64,
+    valid_until: u64,
+    // Past its expiry the entry is kept until then, to be served stale if need be.
+    stale_until: u64,
+    // How often the entry has been used since it was stored.
+    hits: u64,
+    // When the entry was last used, its key in the LRU order.
+    last_used: u64,
+    // Roughly how much memory the entry takes up.
+    size: usize,
+}
+
+struct Entries {
+    map: HashMap<Key, CacheEntry>,
+    // Keys by when they were last used, least recently used first.
+    lru: BTreeMap<u64, Key>,
+    // Ticks up on every use, so each use gets a distinct place in the LRU order.
+    clock: u64,
+    memory: usize,
+}
+
+// An answer to a question asked for a client subnet, which holds for the clients in
+// the subnet the upstream scoped it to (RFC 7871). These are whole answers rather than
+// RRsets, as the answer for a subnet cannot be put together from records cached for
+// other clients.
+struct ScopedEntry {
+    subnet: ClientSubnet,
+    answer: Answer,
+    secure: bool,
+    stored_at: u64,
+    valid_until: u64,
+}
+
+// An answer put together from the cache.
+pub struct Hit {
+    pub answer: Answer,
+    // Every entry the answer came from was validated with DNSSEC.
+    pub secure: bool,
+    // The answer is popular and close enough to expiring that it should be prefetched.
+    pub prefetch: bool,
+}
+
+// Shared by every request being handled, so the entries sit behind a lock. The lock is
+// only held while an entry is read or written, never across a lookup.
+//
+// The cache is bounded both in entries and in (estimated) memory. When either limit is
+// reached the least recently used entries are evicted, and expired entries are removed
+// by a periodic cleanup as well as when they are looked up.
+//
+// Expired entries are kept for a while longer so that they can be served stale when
+// resolution fails (RFC 8767), and popular entries are flagged for prefetching shortly
+// before they expire, so that they are refreshed before anyone misses them.
+//
+// Answers to questions asked for a client subnet are kept apart, by name and type, each
+// for the subnet the upstream scoped it to (an answer for every subnet being scoped to
+// a prefix of 0). They are neither served stale, prefetched nor saved in snapshots.
+pub struct DnsCache {
+    entries: Mutex<Entries>,
+    scoped: Mutex<HashMap<(String, u16), Vec<ScopedEntry>>>,
+    // The longest CNAME chain an answer is put together from.
+    max_chain: usize,
+    max_entries: usize,
+    max_memory: usize,
+    // TTLs of cached records are raised to at least min_ttl and capped at max_ttl.
+    min_ttl: u32,
+    max_ttl: u32,
+    // The longest a negative answer is kept, whatever its SOA says.
+    max_negative_ttl: u32,
+    // How long past its expiry an entry may still be served stale, and with what TTL.
+    stale_window: u64,
+    stale_ttl: u32,
+    // Entries used at least this often are prefetched once less than this percentage
+    // of their TTL remains.
+    prefetch_min_hits: u64,
+    prefetch_percent: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+// DNS Cache implementation, holding RRsets and negative answers (RFC 2308) that the
+// answers to questions are put together from
+impl DnsCache {
+    pub fn new(config: &CacheConfig, max_chain: usize) -> Self {
+        DnsCache {
+            entries: Mutex::new(Entries {
+                map: HashMap::new(),
+                lru: BTreeMap::new(),
+                clock: 0,
+                memory: 0,
+            }),
+            scoped: Mutex::new(HashMap::new()),
+            max_chain,
+            max_entries: config.max_entries,
+            max_memory: config.max_memory_bytes,
+            min_ttl: config.min_ttl_secs,
+            max_ttl: config.max_ttl_secs.max(config.min_ttl_secs),
+            max_negative_ttl: config.max_negative_ttl_secs,
+            stale_window: config.stale_window_secs,
+            stale_ttl: config.stale_answer_ttl_secs,
+            prefetch_min_hits: config.prefetch_min_hits,
+            prefetch_percent: config.prefetch_percent,
+        }
+    }
+
+    // Put together the answer to a question from the cache, following cached CNAMEs, with
+    // TTLs reduced by the time the records have spent in the cache. Returns None unless
+    // the whole answer is cached with answer-grade trust.
+    pub fn get(&self, domain: &str, qtype: u16) -> Option<Hit> {
+        self.assemble(domain, qtype, None)
+    }
+
+    // Like get, but also serving expired entries within the stale window, with the stale
+    // answer TTL. Used when the answer could not be resolved.
+    pub fn get_stale(&self, domain: &str, qtype: u16) -> Option<Answer> {
+        if self.stale_window == 0 {
+            return None;
+        }
+        self.assemble(domain, qtype, Some(self.stale_ttl))
+            .map(|hit| hit.answer)
+    }
+
+    fn assemble(&self, domain: &str, qtype: u16, stale_ttl: Option<u32>) -> Option<Hit> {
+        let now = now();
+        let mut entries = self.entries.lock().unwrap();
+        let mut records = Vec::new();
+        let mut name = domain.to_string();
+        let mut secure = true;
+        let mut prefetch = false;
+        let mut used = |entries: &Entries, key: &Key| {
+            secure &= entries
+                .map
+                .get(key)
+                .is_some_and(|e| e.trust == Trust::Secure);
+            prefetch |= self.due_for_prefetch(entries, key, now);
+        };
+
+        for _ in 0..=self.max_chain {
+            let nxdomain_key = (name.clone(), TYPE_NXDOMAIN, CLASS_IN);
+            let (key, found) =
+                match entries.get(&nxdomain_key, Trust::NonAuthAnswer, now, stale_ttl) {
+                    Some(found) => (nxdomain_key, Some(found)),
+                    None => {
+                        let key = (name.clone(), qtype, CLASS_IN);
+                        let found = entries.get(&key, Trust::NonAuthAnswer, now, stale_ttl);
+                        (key, found)
+                    }
+                };
+            if found.is_some() {
+                used(&entries, &key);
+            }
+            let answer = match found {
+                Some(Cached::RRset(rrset)) => {
+                    records.extend(rrset);
+                    Answer::Records(records)
+                }
+                // A negative answer at the end of a CNAME chain only says the chain ends
+                Some(Cached::NoData(_) | Cached::NxDomain(_)) if !records.is_empty() => {
+                    Answer::Records(records)
+                }
+                Some(Cached::NoData(soa)) => Answer::NoData(Some(soa)),
+                Some(Cached::NxDomain(soa)) => Answer::NxDomain(Some(soa)),
+                None if qtype == TYPE_CNAME => return None,
+                None => {
+                    let key = (name.clone(), TYPE_CNAME, CLASS_IN);
+                    let Some(Cached::RRset(cname)) =
+                        entries.get(&key, Trust::NonAuthAnswer, now, stale_ttl)
+                    else {
+                        return None;
+                    };
+                    used(&entries, &key);
+                    let Some(RecordData::CNAME(target)) = cname.first().map(|r| r.data.clone())
+                    else {
+                        return None;
+                    };
+                    records.extend(cname);
+                    name = target;
+                    continue;
+                }
+            };
+            return Some(Hit {
+                answer,
+                secure,
+                prefetch,
+            });
+        }
+        None
+    }
+
+    // Whether an entry has been used often enough, and has little enough of its TTL
+    // left, that it should be refreshed ahead of expiring.
+    fn due_for_prefetch(&self, entries: &Entries, key: &Key, now: u64) -> bool {
+        let Some(entry) = entries.map.get(key) else {
+            return false;
+        };
+        let ttl = entry.valid_until - entry.stored_at;
+        entry.hits >= self.prefetch_min_hits
+            && entry.valid_until > now
+            && (entry.valid_until - now) * 100 < ttl * self.prefetch_percent
+    }
+
+    // Look up a single RRset of any trust, such as the name servers of a zone or their
+    // addresses, with TTLs reduced by the time it has spent in the cache.
+    pub fn get_rrset(&self, name: &str, record_type: u16) -> Option<Vec<Record>> {
+        let key = (name.to_string(), record_type, CLASS_IN);
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(&key, Trust::Additional, now(), None) {
+            Some(Cached::RRset(records)) => Some(records),
+            _ => None,
+        }
+    }
+
+    // Cache the answer to a question, with the trust of the response it came in. Returns
+    // the answer with its TTLs clamped as they were cached.
+    pub fn insert(&self, domain: &str, qtype: u16, mut answer: Answer, trust: Trust) -> Answer {
+        match &mut answer {
+            Answer::Records(records) => {
+                self.clamp(records);
+                for rrset in group_rrsets(records) {
+                    self.store(record_key(&rrset[0]), Cached::RRset(rrset), trust);
+                }
+            }
+            // Negative answers are kept for the SOA's negative TTL, up to the ceiling
+            Answer::NoData(Some(soa)) => {
+                soa.ttl = soa.ttl.min(self.max_negative_ttl);
+                let key = (domain.to_string(), qtype, CLASS_IN);
+                self.store(key, Cached::NoData(soa.clone()), trust);
+            }
+            Answer::NxDomain(Some(soa)) => {
+                soa.ttl = soa.ttl.min(self.max_negative_ttl);
+                let key = (domain.to_string(), TYPE_NXDOMAIN, CLASS_IN);
+                self.store(key, Cached::NxDomain(soa.clone()), trust);
+            }
+            // Without an SOA there is no telling how long a negative answer holds
+            Answer::NoData(None) | Answer::NxDomain(None) => {}
+        }
+        answer
+    }
+
+    // Cache RRsets that are not the answer to a question, such as the name servers and
+    // glue from a referral.
+    pub fn insert_rrsets(&self, mut records: Vec<Record>, trust: Trust) {
+        self.clamp(&mut records);
+        for rrset in group_rrsets(&records) {
+            self.store(record_key(&rrset[0]), Cached::RRset(rrset), trust);
+        }
+    }
+
+    // The answer cached for the most specific subnet that the client's subnet is in
+    // (RFC 7871, section 7.3.1), with TTLs reduced by the time it has been cached.
+    pub fn get_scoped(&self, domain: &str, qtype: u16, client: &ClientSubnet) -> Option<Hit> {
+        let now = now();
+        let scoped = self.scoped.lock().unwrap();
+        let entry = scoped
+            .get(&(domain.to_string(), qtype))?
+            .iter()
+            .filter(|entry| entry.valid_until > now && entry.subnet.contains(client))
+            .max_by_key(|entry| entry.subnet.prefix_len)?;
+        let elapsed = now.saturating_sub(entry.stored_at) as u32;
+        let age = |record: &Record| Record {
+            ttl: record.ttl.saturating_sub(elapsed),
+            ..record.clone()
+        };
+        let answer = match &entry.answer {
+            Answer::Records(records) => Answer::Records(records.iter().map(age).collect()),
+            Answer::NoData(soa) => Answer::NoData(soa.as_ref().map(age)),
+            Answer::NxDomain(soa) => Answer::NxDomain(soa.as_ref().map(age)),
+        };
+        Some(Hit {
+            answer,
+            secure: entry.secure,
+            prefetch: false,
+        })
+    }
+
+    // Cache the answer to a question for the clients of a subnet, replacing what was
+    // cached for the same subnet, noting whether it was validated with DNSSEC. Returns
+    // the answer with its TTLs clamped as cached.
+    pub fn insert_scoped(
+        &self,
+        domain: &str,
+        qtype: u16,
+        mut answer: Answer,
+        subnet: ClientSubnet,
+        secure: bool,
+    ) -> Answer {
+        let ttl = match &mut answer {
+            Answer::Records(records) => {
+                self.clamp(records);
+                records.iter().map(|r| r.ttl).min().unwrap_or(0)
+            }
+            Answer::NoData(Some(soa)) | Answer::NxDomain(Some(soa)) => {
+                soa.ttl = soa.ttl.min(self.max_negative_ttl);
+                soa.ttl
+            }
+            Answer::NoData(None) | Answer::NxDomain(None) => 0,
+        };
+        if ttl == 0 {
+            return answer;
+        }
+
+        let now = now();
+        let mut scoped = self.scoped.lock().unwrap();
+        // Make room by dropping expired answers, and give up on caching this one if
+        // that is not enough
+        if scoped.values().map(Vec::len).sum::<usize>() >= self.max_entries {
+            remove_expired_scoped(&mut scoped, now);
+            if scoped.values().map(Vec::len).sum::<usize>() >= self.max_entries {
+                return answer;
+            }
+        }
+        let entries = scoped.entry((domain.to_string(), qtype)).or_default();
+        entries.retain(|entry| entry.subnet != subnet);
+        entries.push(ScopedEntry {
+            subnet,
+            answer: answer.clone(),
+            secure,
+            stored_at: now,
+            valid_until: now + u64::from(ttl),
+        });
+        answer
+    }
+
+    fn clamp(&self, records: &mut [Record]) {
+        for record in records {
+            record.ttl = record.ttl.clamp(self.min_ttl, self.max_ttl);
+        }
+    }
+
+    // Store an entry, unless an unexpired entry of higher trust is already cached. A
+    // stale entry is always replaced.
+    fn store(&self, key: Key, data: Cached, trust: Trust) {
+        let ttl = match &data {
+            // An RRset is only as fresh as its shortest-lived record
+            Cached::RRset(records) => records.iter().map(|r| r.ttl).min().unwrap_or(0),
+            Cached::NoData(soa) | Cached::NxDomain(soa) => soa.ttl,
+        };
+        if ttl == 0 {
+            return;
+        }
+
+        let now = now();
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(existing) = entries.map.get(&key) {
+            if existing.valid_until > now && existing.trust > trust {
+                return;
+            }
+        }
+
+        // Records of a name mean it exists after all
+        let nxdomain_key = (key.0.clone(), TYPE_NXDOMAIN, key.2);
+        if key != nxdomain_key
+            && entries
+                .map
+                .get(&nxdomain_key)
+                .is_some_and(|existing| existing.trust <= trust)
+        {
+            entries.remove(&nxdomain_key);
+        }
+
+        let size = estimated_size(&key, &data);
+        entries.remove(&key);
+        entries.clock += 1;
+        let last_used = entries.clock;
+        entries.lru.insert(last_used, key.clone());
+        entries.memory += size;
+        entries.map.insert(
+            key,
+            CacheEntry {
+                data,
+                trust,
+                stored_at: now,
+                valid_until: now + u64::from(ttl),
+                stale_until: now + u64::from(ttl) + self.stale_window,
+                hits: 0,
+                last_used,
+                size,
+            },
+        );
+
+        // Evict the least recently used entries until the cache is within its limits
+        while entries.map.len() > self.max_entries || entries.memory > self.max_memory {
+            let Some((_, oldest)) = entries.lru.pop_first() else {
+                break;
+            };
+            entries.remove(&oldest);
+        }
+    }
+
+    // Remove every entry that has expired and is past the stale window.
+    pub fn remove_expired(&self) {
+        let now = now();
+        remove_expired_scoped(&mut self.scoped.lock().unwrap(), now);
+        let mut entries = self.entries.lock().unwrap();
+        let expired: Vec<Key> = entries
+            .map
+            .iter()
+            .filter(|(_, entry)| entry.stale_until <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        if expired.is_empty() {
+            return;
+        }
+        for key in &expired {
+            entries.remove(key);
+        }
+        println!(
+            "Removed {} expired cache entries ({} entries, about {} bytes cached)",
+            expired.len(),
+            entries.map.len(),
+            entries.memory
+        );
+    }
+
+    // Remove every entry. Returns how many there were.
+    pub fn flush_all(&self) -> usize {
+        let mut scoped = self.scoped.lock().unwrap();
+        let mut flushed: usize = scoped.values().map(Vec::len).sum();
+        scoped.clear();
+        let mut entries = self.entries.lock().unwrap();
+        flushed += entries.map.len();
+        entries.map.clear();
+        entries.lru.clear();
+        entries.memory = 0;
+        flushed
+    }
+
+    // Remove the entries of a name, of every type, and with `below` those of every name
+    // below it too. Returns how many were removed.
+    pub fn flush(&self, name: &str, below: bool) -> usize {
+        let matches = |owner: &str| owner == name || (below && is_subdomain(owner, name));
+        let mut scoped_flushed = 0;
+        self.scoped.lock().unwrap().retain(|(owner, _), entries| {
+            let flush = matches(owner);
+            if flush {
+                scoped_flushed += entries.len();
+            }
+            !flush
+        });
+
+        let mut entries = self.entries.lock().unwrap();
+        let flushed: Vec<Key> = entries
+            .map
+            .keys()
+            .filter(|(owner, _, _)| matches(owner))
+            .cloned()
+            .collect();
+        for key in &flushed {
+            entries.remove(key);
+        }
+        flushed.len() + scoped_flushed
+    }
+
+    // A line for each entry, sorted by name: its key, what it holds, its trust, how often
+    // it has been used, and how long it has left, or how long ago it expired if it is
+    // only kept to be served stale. Then the answers scoped to client subnets.
+    pub fn dump(&self) -> Vec<String> {
+        let now = now();
+        let entries = self.entries.lock().unwrap();
+        let mut keys: Vec<&Key> = entries.map.keys().collect();
+        keys.sort();
+        let mut lines: Vec<String> = keys
+            .into_iter()
+            .map(|key| {
+                let entry = &entries.map[key];
+                let data = match &entry.data {
+                    Cached::RRset(records) => format!("{} records", records.len()),
+                    Cached::NoData(_) => "NODATA".to_string(),
+                    Cached::NxDomain(_) => "NXDOMAIN".to_string(),
+                };
+                let ttl = if entry.valid_until > now {
+                    format!("ttl {}", entry.valid_until - now)
+                } else {
+                    format!("stale for {}", now - entry.valid_until)
+                };
+                let (name, record_type, class) = key;
+                format!(
+                    "{} type {} class {}: {}, {:?}, {} hits, {}",
+                    name, record_type, class, data, entry.trust, entry.hits, ttl
+                )
+            })
+            .collect();
+
+        let scoped = self.scoped.lock().unwrap();
+        let mut keys: Vec<&(String, u16)> = scoped.keys().collect();
+        keys.sort();
+        for key in keys {
+            let (name, record_type) = key;
+            for entry in scoped[key].iter().filter(|entry| entry.valid_until > now) {
+                lines.push(format!(
+                    "{} type {} for {}: {}, ttl {}",
+                    name,
+                    record_type,
+                    entry.subnet,
+                    entry.answer,
+                    entry.valid_until - now
+                ));
+            }
+        }
+        lines
+    }
+
+    // Write out every unexpired entry, so that the cache can be restored after a restart.
+    //
+    // The snapshot is the magic and version, then for each entry: the kind of entry (0 for
+    // an RRset, 1 for NODATA, 2 for NXDOMAIN), its trust, when it was stored and when it
+    // expires as seconds since the Unix epoch, its key, and its records in wire format
+    // preceded by their count. The records keep the TTLs they were cached with.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let now = now();
+        let entries = self.entries.lock().unwrap();
+        let mut buf = SNAPSHOT_MAGIC.to_vec();
+        buf.push(SNAPSHOT_VERSION);
+        for ((name, record_type, class), entry) in &entries.map {
+            if entry.valid_until <= now {
+                continue;
+            }
+            let (kind, records) = match &entry.data {
+                Cached::RRset(records) => (0, records.as_slice()),
+                Cached::NoData(soa) => (1, std::slice::from_ref(soa)),
+                Cached::NxDomain(soa) => (2, std::slice::from_ref(soa)),
+            };
+            buf.push(kind);
+            buf.push(entry.trust as u8);
+            buf.extend_from_slice(&entry.stored_at.to_be_bytes());
+            buf.extend_from_slice(&entry.valid_until.to_be_bytes());
+            write_name(&mut buf, name);
+            buf.extend_from_slice(&record_type.to_be_bytes());
+            buf.extend_from_slice(&class.to_be_bytes());
+            buf.extend_from_slice(&(records.len() as u16).to_be_bytes());
+            for record in records {
+                record.write(&mut buf);
+            }
+        }
+        buf
+    }
+
+    // Load the entries of a snapshot, with their TTLs reduced by the time that has passed
+    // since they were cached. Entries that have expired since are dropped. Returns how
+    // many entries were restored.
+    pub fn restore(&self, snapshot: &[u8]) -> Result<usize, &'static str> {
+        if snapshot.get(..SNAPSHOT_MAGIC.len()) != Some(SNAPSHOT_MAGIC) {
+            return Err("Not a cache snapshot");
+        }
+        if snapshot[SNAPSHOT_MAGIC.len()..].first() != Some(&SNAPSHOT_VERSION) {
+            return Err("Unsupported cache snapshot version");
+        }
+
+        let now = now();
+        let mut restored = 0;
+        let mut position = SNAPSHOT_MAGIC.len() + 1;
+        while position < snapshot.len() {
+            let fixed = snapshot
+                .get(position..position + 18)
+                .ok_or("Cache snapshot is truncated")?;
+            let kind = fixed[0];
+            let trust = Trust::from_u8(fixed[1]).ok_or("Invalid trust in cache snapshot")?;
+            let stored_at = u64::from_be_bytes(fixed[2..10].try_into().unwrap());
+            let valid_until = u64::from_be_bytes(fixed[10..18].try_into().unwrap());
+            let (name, next) = read_name(snapshot, position + 18)?;
+            let fixed = snapshot
+                .get(next..next + 6)
+                .ok_or("Cache snapshot is truncated")?;
+            let record_type = u16::from_be_bytes([fixed[0], fixed[1]]);
+            let class = u16::from_be_bytes([fixed[2], fixed[3]]);
+            let count = u16::from_be_bytes([fixed[4], fixed[5]]);
+            position = next + 6;
+            let mut records = Vec::with_capacity(usize::from(count));
+            for _ in 0..count {
+                let (record, next) = read_record(snapshot, position)?;
+                records.push(record);
+                position = next;
+            }
+
+            if valid_until <= now {
+                continue;
+            }
+            let elapsed = now.saturating_sub(stored_at).min(u64::from(u32::MAX)) as u32;
+            for record in &mut records {
+                record.ttl = record.ttl.saturating_sub(elapsed);
+            }
+            let data = match kind {
+                0 if !records.is_empty() => Cached::RRset(records),
+                1 if records.len() == 1 => Cached::NoData(records.remove(0)),
+                2 if records.len() == 1 => Cached::NxDomain(records.remove(0)),
+                _ => return Err("Invalid entry in cache snapshot"),
+            };
+            self.store((name, record_type, class), data, trust);
+            restored += 1;
+        }
+        Ok(restored)
+    }
+}
+
+impl Entries {
+    // An unexpired entry of at least the given trust, with TTLs reduced by the time it
+    // has been cached. With a stale TTL, an expired entry still within the stale window
+    // is returned too, with that TTL. Using an entry moves it to the back of the LRU order.
+    fn get(
+        &mut self,
+        key: &Key,
+        min_trust: Trust,
+        now: u64,
+        stale_ttl: Option<u32>,
+    ) -> Option<Cached> {
+        let entry = self.map.get_mut(key)?;
+        if entry.stale_until <= now {
+            self.remove(key);
+            return None;
+        }
+        let expired = entry.valid_until <= now;
+        if (expired && stale_ttl.is_none()) || entry.trust < min_trust {
+            return None;
+        }
+
+        self.lru.remove(&entry.last_used);
+        self.clock += 1;
+        entry.last_used = self.clock;
+        entry.hits += 1;
+        self.lru.insert(entry.last_used, key.clone());
+
+        let elapsed = now.saturating_sub(entry.stored_at) as u32;
+        let age = |record: &Record| Record {
+            ttl: match stale_ttl {
+                Some(stale_ttl) if expired => stale_ttl,
+                _ => record.ttl.saturating_sub(elapsed),
+            },
+            ..record.clone()
+        };
+        Some(match &entry.data {
+            Cached::RRset(records) => Cached::RRset(records.iter().map(age).collect()),
+            Cached::NoData(soa) => Cached::NoData(age(soa)),
+            Cached::NxDomain(soa) => Cached::NxDomain(age(soa)),
+        })
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.map.remove(key) {
+            self.lru.remove(&entry.last_used);
+            self.memory -= entry.size;
+        }
+    }
+}
+
+fn remove_expired_scoped(scoped: &mut HashMap<(String, u16), Vec<ScopedEntry>>, now: u64) {
+    scoped.retain(|_, entries| {
+        entries.retain(|entry| entry.valid_until > now);
+        !entries.is_empty()
+    });
+}
+
+fn record_key(record: &Record) -> Key {
+    (record.name.clone(), record.record_type, record.class)
+}
+
+// A rough count of the bytes an entry takes up: the entry itself, its key (held in both
+// the map and the LRU order) and its records.
+fn estimated_size(key: &Key, data: &Cached) -> usize {
+    let records: usize = match data {
+        Cached::RRset(records) => records.iter().map(record_size).sum(),
+        Cached::NoData(soa) | Cached::NxDomain(soa) => record_size(soa),
+    };
+    size_of::<CacheEntry>() + 2 * (size_of::<Key>() + key.0.len()) + records
+}
+
+fn record_size(record: &Record) -> usize {
+    let data = match &record.data {
+        RecordData::NS(name) | RecordData::CNAME(name) | RecordData::PTR(name) => name.len(),
+        RecordData::MX { exchange, .. } => exchange.len(),
+        RecordData::SOA(soa) => soa.mname.len() + soa.rname.len(),
+        RecordData::Other(rdata) => rdata.len(),
+        RecordData::A(_) | RecordData::AAAA(_) => 0,
+    };
+    size_of::<Record>() + record.name.len() + data
+}
@@ -0,0 +1,155 @@
+use serde::Deserialize;
+use std::net::SocketAddr;
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub mode: Mode,
+    // How long to wait for a server to answer before giving up on it.
+    pub query_timeout_ms: u64,
+    // Send query names in random case (0x20 encoding) and only accept responses that
+    // echo the same case back. Some servers do not preserve case, so this is opt-in.
+    pub randomize_case: bool,
+    // When resolving iteratively, show each server only as much of the query name as it
+    // needs to refer us onwards (QNAME minimisation, RFC 9156).
+    pub qname_minimisation: bool,
+    // The most client queries handled at once. Queries arriving beyond this are dropped.
+    pub max_outstanding_queries: usize,
+    // The most client TCP connections open at once. Connections beyond this are closed
+    // as soon as they are accepted.
+    pub max_tcp_connections: usize,
+    // The most CNAMEs followed from the name asked for. Longer chains fail with SERVFAIL.
+    pub max_cname_depth: usize,
+    // Where the admin interface listens. Without an address there is none.
+    pub admin_address: Option<SocketAddr>,
+    pub access: AccessConfig,
+    pub cache: CacheConfig,
+    pub forward: ForwardConfig,
+    // Domains whose names are forwarded to their own upstreams, whatever the mode.
+    pub forward_zones: Vec<ForwardZoneConfig>,
+    // A hosts file whose names are answered locally, without asking any server.
+    pub hosts_file: Option<String>,
+    pub client_subnet: ClientSubnetConfig,
+    pub dnssec: DnssecConfig,
+    pub policy: PolicyConfig,
+    // The root servers iterative resolution starts from.
+    pub root_hints: Vec<RootHint>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    // Walk the DNS hierarchy down from the root hints.
+    Iterative,
+    // Hand every query to the configured upstream servers.
+    Forward,
+}
+
+#[derive(Deserialize)]
+pub struct ForwardConfig {
+    // The servers queries are forwarded to, tried fastest first.
+    pub upstreams: Vec<String>,
+    // How many times each upstream is tried for a query before giving up.
+    pub attempts: usize,
+    // Consecutive failures after which an upstream is backed off.
+    pub failures_before_backoff: u32,
+    // The first backoff, doubled for each further failure up to the maximum.
+    pub backoff_secs: u64,
+    pub max_backoff_secs: u64,
+    // A PEM file of CA certificates that TLS and HTTPS upstreams may also be signed by,
+    // besides the system's.
+    pub ca_bundle: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ForwardZoneConfig {
+    // The zone's names are this domain and every name below it.
+    pub zone: String,
+    pub upstreams: Vec<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ClientSubnetConfig {
+    // Pass the subnet of each client on to the upstreams of forwarded queries (EDNS
+    // Client Subnet), so that they can answer for where the client is.
+    pub enabled: bool,
+    // How many leading bits of the client's address are passed on.
+    pub ipv4_prefix_len: u8,
+    pub ipv6_prefix_len: u8,
+}
+
+#[derive(Deserialize)]
+pub struct AccessConfig {
+    // Address blocks in CIDR notation whose clients may or may not recurse. The most
+    // specific block a client is in decides; clients in none are refused.
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    // The queries per second each client may send on average (0 for no limit), and how
+    // many it may send at once.
+    pub rate_limit_qps: u32,
+    pub rate_limit_burst: u32,
+}
+
+#[derive(Deserialize)]
+pub struct CacheConfig {
+    // The cache evicts its least recently used entries to stay within both limits.
+    pub max_entries: usize,
+    pub max_memory_bytes: usize,
+    // TTLs of cached records are clamped to this range.
+    pub min_ttl_secs: u32,
+    pub max_ttl_secs: u32,
+    // The longest NXDOMAIN and NODATA answers are cached, however long their SOA allows.
+    pub max_negative_ttl_secs: u32,
+    // How often expired entries are swept out of the cache.
+    pub cleanup_interval_secs: u64,
+    // How long past expiry entries may be served when resolution fails (0 disables
+    // serving stale), and the TTL given to such stale answers.
+    pub stale_window_secs: u64,
+    pub stale_answer_ttl_secs: u32,
+    // Entries used at least prefetch_min_hits times are refreshed in the background once
+    // less than prefetch_percent of their TTL remains.
+    pub prefetch_min_hits: u64,
+    pub prefetch_percent: u64,
+    // Where the cache is saved on shutdown and every snapshot_interval_secs, and loaded
+    // from on startup. Without a path the cache is not persisted.
+    pub snapshot_path: Option<String>,
+    pub snapshot_interval_secs: u64,
+}
+
+#[derive(Deserialize)]
+pub struct DnssecConfig {
+    // Validate answers, asking servers for DNSSEC records with the DO bit.
+    pub enabled: bool,
+    // The DS records chains of trust are built from.
+    pub trust_anchors: Vec<TrustAnchor>,
+}
+
+#[derive(Deserialize)]
+pub struct TrustAnchor {
+    pub zone: String,
+    pub key_tag: u16,
+    pub algorithm: u8,
+    pub digest_type: u8,
+    // The digest in hexadecimal.
+    pub digest: String,
+}
+
+#[derive(Deserialize)]
+pub struct PolicyConfig {
+    // How often the policy files are checked for changes and reloaded.
+    pub reload_interval_secs: u64,
+    // The policy files, checked in order.
+    pub zones: Vec<PolicyZoneConfig>,
+}
+
+#[derive(Deserialize)]
+pub struct PolicyZoneConfig {
+    // The name the policy is logged under.
+    pub name: String,
+    pub path: String,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct RootHint {
+    pub name: String,
+    pub address: SocketAddr,
+}
@@ -0,0 +1,398 @@
+use crate::dnssec_records::{ancestor, canonical_cmp, label_count, Nsec, Nsec3};
+use crate::message::{
+    is_subdomain, Record, TYPE_CNAME, TYPE_DS, TYPE_NS, TYPE_NSEC, TYPE_NSEC3, TYPE_SOA,
+};
+use std::cmp::Ordering;
+
+// Above this many NSEC3 iterations a denial is treated as insecure (RFC 9276), so that
+// a zone cannot make us spend arbitrary time hashing.
+const MAX_NSEC3_ITERATIONS: u16 = 150;
+
+// What a validated set of NSEC or NSEC3 records proves about a name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denial {
+    // The name or type does not exist.
+    Proven,
+    // The records cover the name with an NSEC3 opt-out span, or hash too expensively, so
+    // nothing can be proven either way and the answer is insecure.
+    Insecure,
+}
+
+// What the absence of a DS record for a name says about the name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoDs {
+    // The name is a delegation to an unsigned zone.
+    InsecureDelegation,
+    // The name is not a zone cut, so the zone above it carries on below it.
+    NotCut,
+}
+
+// The NSEC and NSEC3 records of a negative response, already checked against the keys of
+// the zone they are from.
+pub struct Proof {
+    nsecs: Vec<(String, Nsec)>,
+    nsec3s: Vec<(String, Nsec3)>,
+    zone: String,
+}
+
+impl Proof {
+    pub fn new(records: &[Record], zone: &str) -> Proof {
+        let nsecs = records
+            .iter()
+            .filter(|r| r.record_type == TYPE_NSEC)
+            .filter_map(|r| Some((r.name.clone(), Nsec::parse(r)?)))
+            .collect();
+        // NSEC3 owners are the hash as the first label, then the zone
+        let nsec3s = records
+            .iter()
+            .filter(|r| r.record_type == TYPE_NSEC3)
+            .filter_map(|r| {
+                let (hash, owner_zone) = r.name.split_once('.')?;
+                let nsec3 = Nsec3::parse(r)?;
+                (owner_zone == zone && nsec3.is_supported()).then(|| (hash.to_string(), nsec3))
+            })
+            .collect();
+        Proof {
+            nsecs,
+            nsec3s,
+            zone: zone.to_string(),
+        }
+    }
+
+    // Proof that the name does not exist: NXDOMAIN.
+    pub fn name_error(&self, name: &str) -> Option<Denial> {
+        if let Some((owner, covering)) = self.nsec_covering(name) {
+            let encloser = closest_encloser(name, owner, covering);
+            return self
+                .nsec_covering(&wildcard(&encloser))
+                .map(|_| Denial::Proven);
+        }
+
+        let (encloser, opt_out) = self.nsec3_closest_encloser(name)?;
+        if encloser == name {
+            return None;
+        }
+        self.nsec3_covering(&wildcard(&encloser))?;
+        Some(self.nsec3_denial(opt_out))
+    }
+
+    // Proof that the name has no records of the type: NODATA.
+    pub fn no_data(&self, name: &str, record_type: u16) -> Option<Denial> {
+        let lacks =
+            |has_type: &dyn Fn(u16) -> bool| !has_type(record_type) && !has_type(TYPE_CNAME);
+
+        if let Some(nsec) = self.nsec_at(name) {
+            return lacks(&|t| nsec.has_type(t)).then_some(Denial::Proven);
+        }
+        if let Some((owner, covering)) = self.nsec_covering(name) {
+            // Either an empty non-terminal, whose next name lies below it, or a wildcard
+            // that matches the name but lacks the type
+            if is_subdomain(&covering.next, name) {
+                return Some(Denial::Proven);
+            }
+            let encloser = closest_encloser(name, owner, covering);
+            let nsec = self.nsec_at(&wildcard(&encloser))?;
+            return lacks(&|t| nsec.has_type(t)).then_some(Denial::Proven);
+        }
+
+        if let Some(nsec3) = self.nsec3_at(name) {
+            if self.too_many_iterations() {
+                return Some(Denial::Insecure);
+            }
+            return lacks(&|t| nsec3.has_type(t)).then_some(Denial::Proven);
+        }
+        let (encloser, opt_out) = self.nsec3_closest_encloser(name)?;
+        // A DS in an opt-out span may belong to an unsigned delegation (RFC 5155 8.6)
+        if record_type == TYPE_DS && opt_out {
+            return Some(Denial::Insecure);
+        }
+        let nsec3 = self.nsec3_at(&wildcard(&encloser))?;
+        if lacks(&|t| nsec3.has_type(t)) {
+            Some(self.nsec3_denial(opt_out))
+        } else {
+            None
+        }
+    }
+
+    // What the proven absence of a DS record for a name says about it, or None if its
+    // absence is not proven.
+    pub fn no_ds(&self, name: &str) -> Option<NoDs> {
+        let delegation = |has_type: &dyn Fn(u16) -> bool| {
+            if has_type(TYPE_DS) {
+                None
+            } else if has_type(TYPE_NS) && !has_type(TYPE_SOA) {
+                Some(NoDs::InsecureDelegation)
+            } else {
+                Some(NoDs::NotCut)
+            }
+        };
+
+        if let Some(nsec) = self.nsec_at(name) {
+            return delegation(&|t| nsec.has_type(t));
+        }
+        if self.nsec_covering(name).is_some() {
+            return Some(NoDs::NotCut);
+        }
+        if let Some(nsec3) = self.nsec3_at(name) {
+            if self.too_many_iterations() {
+                return Some(NoDs::InsecureDelegation);
+            }
+            return delegation(&|t| nsec3.has_type(t));
+        }
+        // No NSEC3 for the name itself: either it does not exist, or it is an unsigned
+        // delegation skipped by an opt-out span
+        let (_, opt_out) = self.nsec3_closest_encloser(name)?;
+        if opt_out || self.too_many_iterations() {
+            Some(NoDs::InsecureDelegation)
+        } else {
+            Some(NoDs::NotCut)
+        }
+    }
+
+    // Proof that a name answered from a wildcard does not exist itself, given the number
+    // of labels the wildcard's signature was made over (RFC 4035 section 5.3.4).
+    pub fn wildcard_expansion(&self, name: &str, labels: u8) -> Option<Denial> {
+        if self.nsec_covering(name).is_some() {
+            return Some(Denial::Proven);
+        }
+        let next_closer = ancestor(name, usize::from(labels) + 1);
+        let nsec3 = self.nsec3_covering(next_closer)?;
+        Some(self.nsec3_denial(nsec3.opt_out()))
+    }
+
+    fn nsec_at(&self, name: &str) -> Option<&Nsec> {
+        self.nsecs
+            .iter()
+            .find(|(owner, _)| owner == name)
+            .map(|(_, nsec)| nsec)
+    }
+
+    // The NSEC whose span, strictly between its owner and the next name, holds the name.
+    // The last NSEC of a zone wraps around to the apex. The NSEC of a delegation says
+    // nothing about the names below it, which belong to the child zone.
+    fn nsec_covering(&self, name: &str) -> Option<(&str, &Nsec)> {
+        self.nsecs
+            .iter()
+            .find(|(owner, nsec)| {
+                let delegation = nsec.has_type(TYPE_NS) && !nsec.has_type(TYPE_SOA);
+                canonical_cmp(owner, name) == Ordering::Less
+                    && (canonical_cmp(name, &nsec.next) == Ordering::Less
+                        || canonical_cmp(&nsec.next, owner) != Ordering::Greater)
+                    && !(delegation && is_subdomain(name, owner))
+            })
+            .map(|(owner, nsec)| (owner.as_str(), nsec))
+    }
+
+    fn nsec3_at(&self, name: &str) -> Option<&Nsec3> {
+        self.nsec3s
+            .iter()
+            .find(|(hash, nsec3)| *hash == nsec3.hash(name))
+            .map(|(_, nsec3)| nsec3)
+    }
+
+    // The NSEC3 whose hash span holds the hash of the name, wrapping around at the end.
+    fn nsec3_covering(&self, name: &str) -> Option<&Nsec3> {
+        self.nsec3s
+            .iter()
+            .find(|(hash, nsec3)| {
+                let hashed = nsec3.hash(name);
+                if nsec3.next > *hash {
+                    *hash < hashed && hashed < nsec3.next
+                } else {
+                    hashed > *hash || hashed < nsec3.next
+                }
+            })
+            .map(|(_, nsec3)| nsec3)
+    }
+
+    // The closest encloser proof (RFC 5155 section 8.3): the longest existing ancestor
+    // of the name with a matching NSEC3, and an NSEC3 covering the next name down.
+    // Returns the closest encloser and whether that covering NSEC3 is opt-out.
+    fn nsec3_closest_encloser(&self, name: &str) -> Option<(String, bool)> {
+        if self.nsec3s.is_empty() || !is_subdomain(name, &self.zone) {
+            return None;
+        }
+        let zone_labels = label_count(&self.zone);
+        for labels in (zone_labels..=label_count(name)).rev() {
+            let candidate = ancestor(name, labels);
+            if self.nsec3_at(candidate).is_none() {
+                continue;
+            }
+            if candidate == name {
+                return Some((name.to_string(), false));
+            }
+            let next_closer = ancestor(name, labels + 1);
+            let covering = self.nsec3_covering(next_closer)?;
+            return Some((candidate.to_string(), covering.opt_out()));
+        }
+        None
+    }
+
+    fn too_many_iterations(&self) -> bool {
+        self.nsec3s
+            .iter()
+            .any(|(_, nsec3)| nsec3.iterations > MAX_NSEC3_ITERATIONS)
+    }
+
+    fn nsec3_denial(&self, opt_out: bool) -> Denial {
+        if opt_out || self.too_many_iterations() {
+            Denial::Insecure
+        } else {
+            Denial::Proven
+        }
+    }
+}
+
+// The closest encloser of a name covered by an NSEC: the deepest of the ancestors it
+// shares with the NSEC's owner and next name (RFC 4035 section 5.4).
+fn closest_encloser(name: &str, owner: &str, nsec: &Nsec) -> String {
+    let with_owner = common_ancestor(name, owner);
+    let with_next = common_ancestor(name, &nsec.next);
+    if label_count(&with_owner) >= label_count(&with_next) {
+        with_owner
+    } else {
+        with_next
+    }
+}
+
+fn common_ancestor(a: &str, b: &str) -> String {
+    let mut labels = 0;
+    while labels < label_count(a).min(label_count(b))
+        && ancestor(a, labels + 1) == ancestor(b, labels + 1)
+    {
+        labels += 1;
+    }
+    ancestor(a, labels).to_string()
+}
+
+fn wildcard(encloser: &str) -> String {
+    if encloser.is_empty() {
+        "*".to_string()
+    } else {
+        format!("*.{}", encloser)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dnssec_records::tests::{record, APEX_NSEC3, APEX_NSEC3_OPT_OUT};
+    use crate::message::{write_name, RecordData, CLASS_IN, TYPE_A, TYPE_AAAA, TYPE_RRSIG};
+
+    // The hash of "example" with no salt or extra iterations, which the apex NSEC3
+    // fixtures are owned by.
+    const APEX_HASH: &str = "3msev9usmd4br9s97v51r2tdvmr9iqo1";
+
+    // An NSEC from one name to the next with the given types, all in the first window.
+    fn nsec(owner: &str, next: &str, types: &[u16]) -> Record {
+        let mut bitmap = [0u8; 32];
+        for &t in types {
+            bitmap[usize::from(t / 8)] |= 0x80 >> (t % 8);
+        }
+        let length = bitmap.iter().rposition(|&b| b != 0).unwrap() + 1;
+        let mut rdata = Vec::new();
+        write_name(&mut rdata, next);
+        rdata.extend_from_slice(&[0, length as u8]);
+        rdata.extend_from_slice(&bitmap[..length]);
+        Record {
+            name: owner.to_string(),
+            record_type: TYPE_NSEC,
+            class: CLASS_IN,
+            ttl: 300,
+            data: RecordData::Other(rdata),
+        }
+    }
+
+    // The NSECs of a zone holding the apex, a.example with an A record, and an unsigned
+    // delegation to d.example, minus the ones named.
+    fn nsec_zone(without: &[&str]) -> Proof {
+        let records: Vec<Record> = [
+            nsec(
+                "example",
+                "a.example",
+                &[TYPE_NS, TYPE_SOA, TYPE_RRSIG, TYPE_NSEC],
+            ),
+            nsec("a.example", "d.example", &[TYPE_A, TYPE_RRSIG, TYPE_NSEC]),
+            nsec("d.example", "example", &[TYPE_NS, TYPE_RRSIG, TYPE_NSEC]),
+        ]
+        .into_iter()
+        .filter(|r| !without.contains(&r.name.as_str()))
+        .collect();
+        Proof::new(&records, "example")
+    }
+
+    fn nsec3_zone(rdata: &str) -> Proof {
+        let owner = format!("{}.example", APEX_HASH);
+        Proof::new(&[record(&owner, TYPE_NSEC3, 300, rdata)], "example")
+    }
+
+    #[test]
+    fn nsec_name_error() {
+        // b.example falls between a.example and d.example, and the wildcard between
+        // the apex and a.example
+        assert_eq!(nsec_zone(&[]).name_error("b.example"), Some(Denial::Proven));
+    }
+
+    #[test]
+    fn nsec_name_error_needs_the_wildcard_proof() {
+        assert_eq!(nsec_zone(&["example"]).name_error("b.example"), None);
+        assert_eq!(nsec_zone(&["a.example"]).name_error("b.example"), None);
+    }
+
+    #[test]
+    fn nsec_no_data() {
+        let proof = nsec_zone(&[]);
+        assert_eq!(proof.no_data("a.example", TYPE_AAAA), Some(Denial::Proven));
+        assert_eq!(proof.no_data("a.example", TYPE_A), None);
+    }
+
+    #[test]
+    fn nsec_wildcard_expansion() {
+        assert_eq!(
+            nsec_zone(&[]).wildcard_expansion("b.example", 1),
+            Some(Denial::Proven)
+        );
+        assert_eq!(
+            nsec_zone(&["a.example"]).wildcard_expansion("b.example", 1),
+            None
+        );
+    }
+
+    #[test]
+    fn nsec_no_ds() {
+        let proof = nsec_zone(&[]);
+        assert_eq!(proof.no_ds("d.example"), Some(NoDs::InsecureDelegation));
+        assert_eq!(proof.no_ds("a.example"), Some(NoDs::NotCut));
+        // The delegation's NSEC says nothing about the child zone's names
+        assert_eq!(proof.no_ds("www.d.example"), None);
+    }
+
+    #[test]
+    fn nsec3_name_error() {
+        let proof = nsec3_zone(APEX_NSEC3);
+        assert_eq!(proof.name_error("x.example"), Some(Denial::Proven));
+        assert_eq!(proof.no_ds("x.example"), Some(NoDs::NotCut));
+        // The apex exists, so it has no closest encloser proof
+        assert_eq!(proof.name_error("example"), None);
+    }
+
+    #[test]
+    fn nsec3_opt_out_is_insecure() {
+        let proof = nsec3_zone(APEX_NSEC3_OPT_OUT);
+        // The span may hold unsigned delegations, so nothing is proven about the name
+        assert_eq!(proof.name_error("x.example"), Some(Denial::Insecure));
+        assert_eq!(proof.no_data("x.example", TYPE_DS), Some(Denial::Insecure));
+        assert_eq!(proof.no_ds("x.example"), Some(NoDs::InsecureDelegation));
+        assert_eq!(
+            proof.wildcard_expansion("x.example", 1),
+            Some(Denial::Insecure)
+        );
+    }
+
+    #[test]
+    fn nsec3_from_another_zone_is_ignored() {
+        let owner = format!("{}.example", APEX_HASH);
+        let proof = Proof::new(&[record(&owner, TYPE_NSEC3, 300, APEX_NSEC3)], "other");
+        assert_eq!(proof.name_error("x.other"), None);
+    }
+}
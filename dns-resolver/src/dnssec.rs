@@ -0,0 +1,645 @@
+use crate::config::DnssecConfig;
+use crate::denial::{Denial, NoDs, Proof};
+use crate::dnssec_records::{ancestor, label_count, verify_rrsig, zone_keys, DnsKey, Ds, Rrsig};
+use crate::message::{
+    group_rrsets, is_subdomain, Answer, Message, Record, TYPE_DNSKEY, TYPE_DS, TYPE_NSEC,
+    TYPE_NSEC3, TYPE_RRSIG, TYPE_SOA,
+};
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub type FetchFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Message, Box<dyn Error + Send + Sync>>> + Send + 'a>>;
+
+// Looks up the DS and DNSKEY RRsets, with their signatures, that a chain of trust is
+// built from.
+pub trait Fetch: Sync {
+    fn fetch<'a>(&'a self, name: &'a str, qtype: u16) -> FetchFuture<'a>;
+}
+
+// The outcome of validating an answer (RFC 4035 section 4.3).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Security {
+    // A chain of trust from a trust anchor vouches for the answer.
+    Secure,
+    // The answer comes from an unsigned zone, or one no trust anchor covers.
+    Insecure,
+    // The answer should have been signed but did not validate, for the given reason.
+    Bogus(String),
+}
+
+impl Security {
+    // The security of an answer put together from two parts.
+    pub fn and(self, other: Security) -> Security {
+        match (self, other) {
+            (Security::Bogus(reason), _) | (_, Security::Bogus(reason)) => Security::Bogus(reason),
+            (Security::Secure, Security::Secure) => Security::Secure,
+            _ => Security::Insecure,
+        }
+    }
+}
+
+// What the chain of trust says about a name below a signed zone.
+#[derive(Clone)]
+enum Cut {
+    // A delegation to a signed zone, with its validated keys.
+    Secure(Vec<DnsKey>),
+    // Not a zone cut; the zone above carries on below the name.
+    Within,
+    // A delegation to an unsigned zone.
+    Insecure,
+}
+
+// The deepest zone enclosing a name, with its keys if the chain of trust reaches it.
+enum Zone {
+    Secure(String, Vec<DnsKey>),
+    Insecure,
+}
+
+// Validates answers with DNSSEC, building chains of trust from the configured trust
+// anchors down through the DS and DNSKEY RRsets of each zone cut. What is learned about
+// each zone cut is remembered for the TTL of the records that proved it.
+pub struct Validator {
+    // Trust anchors by zone, as DS records.
+    anchors: Vec<(String, Vec<Ds>)>,
+    cuts: Mutex<HashMap<String, (Cut, Instant)>>,
+}
+
+impl Validator {
+    pub fn new(config: &DnssecConfig) -> Result<Self, &'static str> {
+        let mut anchors: Vec<(String, Vec<Ds>)> = Vec::new();
+        for anchor in &config.trust_anchors {
+            let zone = anchor.zone.trim_end_matches('.').to_ascii_lowercase();
+            let ds = Ds {
+                key_tag: anchor.key_tag,
+                algorithm: anchor.algorithm,
+                digest_type: anchor.digest_type,
+                digest: decode_hex(&anchor.digest).ok_or("Invalid trust anchor digest")?,
+            };
+            match anchors.iter_mut().find(|(z, _)| *z == zone) {
+                Some((_, ds_set)) => ds_set.push(ds),
+                None => anchors.push((zone, vec![ds])),
+            }
+        }
+        Ok(Validator {
+            anchors,
+            cuts: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Validate the response to a question.
+    pub async fn validate(
+        &self,
+        fetch: &dyn Fetch,
+        response: &Message,
+        domain: &str,
+        qtype: u16,
+    ) -> Security {
+        if self.anchor_for(domain).is_none() {
+            return Security::Insecure;
+        }
+        match self.check_response(fetch, response, domain, qtype).await {
+            Ok(true) => Security::Secure,
+            Ok(false) => Security::Insecure,
+            Err(reason) => Security::Bogus(reason),
+        }
+    }
+
+    // Check every RRset the answer is made of, or the proof of a negative answer.
+    // Returns whether the answer is secure, or why it is bogus.
+    async fn check_response(
+        &self,
+        fetch: &dyn Fetch,
+        response: &Message,
+        domain: &str,
+        qtype: u16,
+    ) -> Result<bool, String> {
+        let name_error = match response.answer(domain, qtype) {
+            Answer::Records(records) => {
+                let mut secure = true;
+                for rrset in group_rrsets(&records) {
+                    let Some((zone, keys)) =
+                        self.signing_zone(fetch, &rrset, &response.answers).await?
+                    else {
+                        secure = false;
+                        continue;
+                    };
+                    let labels = verify(&rrset, &response.answers, &zone, &keys)?;
+
+                    // An answer expanded from a wildcard needs proof that the name itself
+                    // does not exist
+                    let owner = &rrset[0].name;
+                    if usize::from(labels) < label_count(owner) {
+                        match self
+                            .proof(response, &zone, &keys)
+                            .wildcard_expansion(owner, labels)
+                        {
+                            Some(Denial::Proven) => {}
+                            Some(Denial::Insecure) => secure = false,
+                            None => {
+                                return Err(format!("No proof for the wildcard answer {}", owner))
+                            }
+                        }
+                    }
+                }
+                return Ok(secure);
+            }
+            Answer::NoData(_) => false,
+            Answer::NxDomain(_) => true,
+        };
+
+        // A negative answer is signed by the zone whose SOA comes with it
+        let soa: Vec<Record> = response
+            .authority
+            .iter()
+            .filter(|r| r.record_type == TYPE_SOA && is_subdomain(domain, &r.name))
+            .cloned()
+            .collect();
+        if soa.is_empty() {
+            return match self.zone_of(fetch, domain).await? {
+                Zone::Insecure => Ok(false),
+                Zone::Secure(..) => Err(format!("Negative answer for {} has no SOA", domain)),
+            };
+        }
+        let Some((zone, keys)) = self.signing_zone(fetch, &soa, &response.authority).await? else {
+            return Ok(false);
+        };
+        verify(&soa, &response.authority, &zone, &keys)?;
+
+        let proof = self.proof(response, &zone, &keys);
+        let denial = if name_error {
+            proof.name_error(domain)
+        } else {
+            proof.no_data(domain, qtype)
+        };
+        match denial {
+            Some(Denial::Proven) => Ok(true),
+            Some(Denial::Insecure) => Ok(false),
+            None => Err(format!(
+                "No proof of the negative answer for {} (type {})",
+                domain, qtype
+            )),
+        }
+    }
+
+    // The signed zone whose keys an RRset should be checked with, or None if the RRset
+    // is in an unsigned zone. An RRset without signatures in a signed zone is bogus.
+    async fn signing_zone(
+        &self,
+        fetch: &dyn Fetch,
+        rrset: &[Record],
+        section: &[Record],
+    ) -> Result<Option<(String, Vec<DnsKey>)>, String> {
+        let owner = &rrset[0].name;
+        let record_type = rrset[0].record_type;
+        let signer = rrsigs(section, owner, record_type)
+            .into_iter()
+            .map(|rrsig| rrsig.signer)
+            .find(|signer| is_subdomain(owner, signer));
+
+        match self
+            .zone_of(fetch, signer.as_deref().unwrap_or(owner))
+            .await?
+        {
+            Zone::Insecure => Ok(None),
+            Zone::Secure(zone, keys) => match signer {
+                Some(signer) if signer == zone => Ok(Some((zone, keys))),
+                Some(signer) => Err(format!(
+                    "{} (type {}) is signed by {}, which is not a signed zone",
+                    owner,
+                    record_type,
+                    display(&signer)
+                )),
+                None => Err(format!("{} (type {}) is not signed", owner, record_type)),
+            },
+        }
+    }
+
+    // The NSEC and NSEC3 records of a response that verify against the zone's keys.
+    fn proof(&self, response: &Message, zone: &str, keys: &[DnsKey]) -> Proof {
+        let records: Vec<Record> = response
+            .authority
+            .iter()
+            .filter(|r| r.record_type == TYPE_NSEC || r.record_type == TYPE_NSEC3)
+            .cloned()
+            .collect();
+        let verified: Vec<Record> = group_rrsets(&records)
+            .into_iter()
+            .filter(|rrset| verify(rrset, &response.authority, zone, keys).is_ok())
+            .flatten()
+            .collect();
+        Proof::new(&verified, zone)
+    }
+
+    // Follow the chain of trust from the closest trust anchor down to the name, finding
+    // the deepest zone enclosing it.
+    async fn zone_of(&self, fetch: &dyn Fetch, name: &str) -> Result<Zone, String> {
+        let Some((anchor, anchor_ds)) = self.anchor_for(name) else {
+            return Ok(Zone::Insecure);
+        };
+
+        let anchor_cut = match self.cached(anchor) {
+            Some(cut) => cut,
+            None => {
+                let (cut, ttl) = self
+                    .keys_from_ds(fetch, anchor, anchor_ds, u32::MAX)
+                    .await?;
+                self.remember(anchor, &cut, ttl);
+                cut
+            }
+        };
+        let Cut::Secure(mut keys) = anchor_cut else {
+            return Ok(Zone::Insecure);
+        };
+
+        let mut zone = anchor.to_string();
+        for labels in label_count(anchor) + 1..=label_count(name) {
+            let child = ancestor(name, labels);
+            let cut = match self.cached(child) {
+                Some(cut) => cut,
+                None => {
+                    let (cut, ttl) = self.find_cut(fetch, child, &zone, &keys).await?;
+                    self.remember(child, &cut, ttl);
+                    cut
+                }
+            };
+            match cut {
+                Cut::Secure(child_keys) => {
+                    zone = child.to_string();
+                    keys = child_keys;
+                }
+                Cut::Within => {}
+                Cut::Insecure => return Ok(Zone::Insecure),
+            }
+        }
+        Ok(Zone::Secure(zone, keys))
+    }
+
+    // Find out whether a name below a signed zone is a zone cut, from its DS RRset or
+    // the signed proof that it has none. Returns what was found and how long it holds.
+    async fn find_cut(
+        &self,
+        fetch: &dyn Fetch,
+        name: &str,
+        zone: &str,
+        keys: &[DnsKey],
+    ) -> Result<(Cut, u32), String> {
+        let response = fetch
+            .fetch(name, TYPE_DS)
+            .await
+            .map_err(|e| format!("Failed to look up the DS of {}: {}", name, e))?;
+        let ds_records: Vec<Record> = response
+            .answers
+            .iter()
+            .filter(|r| r.name == name && r.record_type == TYPE_DS)
+            .cloned()
+            .collect();
+
+        if !ds_records.is_empty() {
+            verify(&ds_records, &response.answers, zone, keys)?;
+            let ds_set: Vec<Ds> = ds_records.iter().filter_map(Ds::parse).collect();
+            return self
+                .keys_from_ds(fetch, name, &ds_set, min_ttl(&ds_records))
+                .await;
+        }
+
+        let ttl = min_ttl(&response.authority);
+        match self.proof(&response, zone, keys).no_ds(name) {
+            Some(NoDs::InsecureDelegation) => {
+                println!("{} is an unsigned delegation from {}", name, display(zone));
+                Ok((Cut::Insecure, ttl))
+            }
+            Some(NoDs::NotCut) => Ok((Cut::Within, ttl)),
+            None => Err(format!("No proof that {} has no DS", name)),
+        }
+    }
+
+    // Fetch a zone's keys and check them against its DS RRset: a key the DS vouches for
+    // has to sign the zone's DNSKEY RRset. A zone whose DS RRset only uses algorithms we
+    // do not support is treated as unsigned (RFC 4035 section 5.2).
+    async fn keys_from_ds(
+        &self,
+        fetch: &dyn Fetch,
+        zone: &str,
+        ds_set: &[Ds],
+        ds_ttl: u32,
+    ) -> Result<(Cut, u32), String> {
+        let supported: Vec<&Ds> = ds_set.iter().filter(|ds| ds.is_supported()).collect();
+        if supported.is_empty() {
+            return Ok((Cut::Insecure, ds_ttl));
+        }
+
+        let response = fetch
+            .fetch(zone, TYPE_DNSKEY)
+            .await
+            .map_err(|e| format!("Failed to look up the DNSKEY of {}: {}", display(zone), e))?;
+        let key_records = zone_keys(zone, &response.answers);
+        let keys: Vec<DnsKey> = key_records.iter().filter_map(DnsKey::parse).collect();
+
+        let vouched = keys
+            .iter()
+            .filter(|key| supported.iter().any(|ds| ds.matches(zone, key)));
+        for key in vouched {
+            let signed = rrsigs(&response.answers, zone, TYPE_DNSKEY)
+                .iter()
+                .filter(|rrsig| rrsig.signer == zone)
+                .any(|rrsig| {
+                    verify_rrsig(&key_records, rrsig, std::slice::from_ref(key), now()).is_ok()
+                });
+            if signed {
+                println!("Validated the keys of {}", display(zone));
+                return Ok((Cut::Secure(keys), min_ttl(&key_records).min(ds_ttl)));
+            }
+        }
+        Err(format!(
+            "No DNSKEY of {} that its DS vouches for signs its keys",
+            display(zone)
+        ))
+    }
+
+    // The deepest trust anchor enclosing a name.
+    fn anchor_for(&self, name: &str) -> Option<(&str, &[Ds])> {
+        self.anchors
+            .iter()
+            .filter(|(zone, _)| is_subdomain(name, zone))
+            .max_by_key(|(zone, _)| label_count(zone))
+            .map(|(zone, ds_set)| (zone.as_str(), ds_set.as_slice()))
+    }
+
+    fn cached(&self, name: &str) -> Option<Cut> {
+        let mut cuts = self.cuts.lock().unwrap();
+        match cuts.get(name) {
+            Some((cut, until)) if *until > Instant::now() => Some(cut.clone()),
+            Some(_) => {
+                cuts.remove(name);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn remember(&self, name: &str, cut: &Cut, ttl: u32) {
+        let until = Instant::now() + Duration::from_secs(u64::from(ttl.min(86400)));
+        self.cuts
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), (cut.clone(), until));
+    }
+}
+
+// Check an RRset against the signatures over it by the zone, returning the number of
+// labels of the first one that verifies.
+fn verify(rrset: &[Record], section: &[Record], zone: &str, keys: &[DnsKey]) -> Result<u8, String> {
+    let owner = &rrset[0].name;
+    let record_type = rrset[0].record_type;
+    let mut reason = "No signature from the zone";
+    for rrsig in rrsigs(section, owner, record_type) {
+        if rrsig.signer != zone {
+            continue;
+        }
+        match verify_rrsig(rrset, &rrsig, keys, now()) {
+            Ok(labels) => return Ok(labels),
+            Err(e) => reason = e,
+        }
+    }
+    Err(format!("{} (type {}): {}", owner, record_type, reason))
+}
+
+// The signatures in a section over the RRset of a name and type.
+fn rrsigs(section: &[Record], owner: &str, record_type: u16) -> Vec<Rrsig> {
+    section
+        .iter()
+        .filter(|r| r.record_type == TYPE_RRSIG && r.name == owner)
+        .filter_map(Rrsig::parse)
+        .filter(|rrsig| rrsig.type_covered == record_type)
+        .collect()
+}
+
+fn min_ttl(records: &[Record]) -> u32 {
+    records.iter().map(|r| r.ttl).min().unwrap_or(0)
+}
+
+// The current time as DNSSEC signatures give it, in seconds modulo 2^32.
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32
+}
+
+fn display(zone: &str) -> &str {
+    if zone.is_empty() {
+        "."
+    } else {
+        zone
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    let hex: Vec<u8> = hex.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TrustAnchor;
+    use crate::dnssec_records::tests::*;
+    use crate::message::{TYPE_A, TYPE_NSEC};
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Serves the DS and DNSKEY RRsets of the fixtures' signed tree, and the proof that
+    // www.sub.example has no DS, counting the lookups.
+    struct Tree {
+        responses: Vec<(&'static str, u16, Message)>,
+        lookups: AtomicUsize,
+    }
+
+    impl Fetch for Tree {
+        fn fetch<'a>(&'a self, name: &'a str, qtype: u16) -> FetchFuture<'a> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            let found = self
+                .responses
+                .iter()
+                .find(|(n, t, _)| *n == name && *t == qtype)
+                .map(|(_, _, response)| response.clone());
+            Box::pin(async move { found.ok_or_else(|| "Not in the tree".into()) })
+        }
+    }
+
+    fn tree() -> Tree {
+        Tree {
+            responses: vec![
+                (
+                    "example",
+                    TYPE_DNSKEY,
+                    response(vec![
+                        record("example", TYPE_DNSKEY, 3600, EXAMPLE_KEY),
+                        record("example", TYPE_RRSIG, 3600, EXAMPLE_KEY_RRSIG),
+                    ]),
+                ),
+                (
+                    "sub.example",
+                    TYPE_DS,
+                    response(vec![
+                        record("sub.example", TYPE_DS, 3600, SUB_DS),
+                        record("sub.example", TYPE_RRSIG, 3600, SUB_DS_RRSIG),
+                    ]),
+                ),
+                (
+                    "www.sub.example",
+                    TYPE_DS,
+                    Message {
+                        authority: vec![
+                            record("www.sub.example", TYPE_NSEC, 300, WWW_NSEC),
+                            record("www.sub.example", TYPE_RRSIG, 300, WWW_NSEC_RRSIG),
+                        ],
+                        ..response(Vec::new())
+                    },
+                ),
+                (
+                    "sub.example",
+                    TYPE_DNSKEY,
+                    response(vec![
+                        record("sub.example", TYPE_DNSKEY, 3600, SUB_KEY),
+                        record("sub.example", TYPE_RRSIG, 3600, SUB_KEY_RRSIG),
+                    ]),
+                ),
+            ],
+            lookups: AtomicUsize::new(0),
+        }
+    }
+
+    fn response(answers: Vec<Record>) -> Message {
+        Message {
+            flags: 0x8400,
+            answers,
+            authority: Vec::new(),
+            additional: Vec::new(),
+        }
+    }
+
+    fn validator(digest: &str) -> Validator {
+        Validator::new(&DnssecConfig {
+            enabled: true,
+            trust_anchors: vec![TrustAnchor {
+                zone: "example.".to_string(),
+                key_tag: EXAMPLE_KEY_TAG,
+                algorithm: 15,
+                digest_type: 2,
+                digest: digest.to_string(),
+            }],
+        })
+        .unwrap()
+    }
+
+    // The answer for www.sub.example A with the given signature.
+    fn www(rrsig: &str) -> Message {
+        response(vec![
+            a("www.sub.example", Ipv4Addr::new(192, 0, 2, 1)),
+            record("www.sub.example", TYPE_RRSIG, 300, rrsig),
+        ])
+    }
+
+    async fn validate(response: &Message, domain: &str) -> Security {
+        validator(EXAMPLE_DS_DIGEST)
+            .validate(&tree(), response, domain, TYPE_A)
+            .await
+    }
+
+    fn assert_bogus(security: Security, expected: &str) {
+        match security {
+            Security::Bogus(reason) => assert!(reason.contains(expected), "{}", reason),
+            other => panic!("Expected a bogus answer, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn validates_a_chain_of_trust() {
+        let security = validate(&www(WWW_A_RRSIG), "www.sub.example").await;
+        assert_eq!(security, Security::Secure);
+    }
+
+    #[tokio::test]
+    async fn remembers_validated_zone_cuts() {
+        let validator = validator(EXAMPLE_DS_DIGEST);
+        let tree = tree();
+        let response = www(WWW_A_RRSIG);
+        for _ in 0..2 {
+            let security = validator
+                .validate(&tree, &response, "www.sub.example", TYPE_A)
+                .await;
+            assert_eq!(security, Security::Secure);
+        }
+        // The DNSKEY of each zone and the DS of the cut, looked up once
+        assert_eq!(tree.lookups.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn expired_signature_is_bogus() {
+        let security = validate(&www(WWW_A_RRSIG_EXPIRED), "www.sub.example").await;
+        assert_bogus(security, "Signature has expired");
+    }
+
+    #[tokio::test]
+    async fn signature_by_another_key_is_bogus() {
+        let security = validate(&www(WWW_A_RRSIG_STRANGER), "www.sub.example").await;
+        assert_bogus(security, "No key verifies the signature");
+        let security = validate(&www(WWW_A_RRSIG_PARENT_KEY), "www.sub.example").await;
+        assert_bogus(security, "No key verifies the signature");
+    }
+
+    #[tokio::test]
+    async fn signer_outside_the_name_is_bogus() {
+        let security = validate(&www(WWW_A_RRSIG_OTHER_SIGNER), "www.sub.example").await;
+        assert_bogus(security, "is not signed");
+
+        let unsigned = response(vec![a("www.sub.example", Ipv4Addr::new(192, 0, 2, 1))]);
+        let security = validate(&unsigned, "www.sub.example").await;
+        assert_bogus(security, "is not signed");
+    }
+
+    #[tokio::test]
+    async fn trust_anchor_mismatch_is_bogus() {
+        let security = validator(&"00".repeat(32))
+            .validate(&tree(), &www(WWW_A_RRSIG), "www.sub.example", TYPE_A)
+            .await;
+        assert_bogus(security, "No DNSKEY of example that its DS vouches for");
+    }
+
+    #[tokio::test]
+    async fn wildcard_answer_needs_proof() {
+        let mut response = response(vec![
+            a("host.sub.example", Ipv4Addr::new(192, 0, 2, 2)),
+            record("host.sub.example", TYPE_RRSIG, 300, WILDCARD_A_RRSIG),
+        ]);
+        let security = validate(&response, "host.sub.example").await;
+        assert_bogus(
+            security,
+            "No proof for the wildcard answer host.sub.example",
+        );
+
+        response.authority = vec![
+            record("*.sub.example", TYPE_NSEC, 300, WILDCARD_NSEC),
+            record("*.sub.example", TYPE_RRSIG, 300, WILDCARD_NSEC_RRSIG),
+        ];
+        let security = validate(&response, "host.sub.example").await;
+        assert_eq!(security, Security::Secure);
+    }
+
+    #[tokio::test]
+    async fn names_without_an_anchor_are_insecure() {
+        let response = response(vec![a("www.other", Ipv4Addr::new(192, 0, 2, 1))]);
+        assert_eq!(validate(&response, "www.other").await, Security::Insecure);
+    }
+}
@@ -0,0 +1,762 @@
+use crate::message::{read_name, write_name, Record, RecordData, TYPE_DNSKEY};
+use ring::digest;
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use std::cmp::Ordering;
+
+// DNSSEC algorithm numbers (RFC 8624) the validator can verify.
+const ALGORITHM_RSASHA1: u8 = 5;
+const ALGORITHM_RSASHA1_NSEC3_SHA1: u8 = 7;
+const ALGORITHM_RSASHA256: u8 = 8;
+const ALGORITHM_RSASHA512: u8 = 10;
+const ALGORITHM_ECDSAP256SHA256: u8 = 13;
+const ALGORITHM_ECDSAP384SHA384: u8 = 14;
+const ALGORITHM_ED25519: u8 = 15;
+
+// DS digest types.
+const DIGEST_SHA1: u8 = 1;
+const DIGEST_SHA256: u8 = 2;
+const DIGEST_SHA384: u8 = 4;
+
+// DNSKEY flags: the key signs the zone, and the key has been revoked (RFC 5011).
+const FLAG_ZONE_KEY: u16 = 0x0100;
+const FLAG_REVOKED: u16 = 0x0080;
+
+// The NSEC3 flag marking a span that may hold unsigned delegations (RFC 5155).
+const NSEC3_OPT_OUT: u8 = 0x01;
+// NSEC3 hash algorithm 1, SHA-1.
+const NSEC3_SHA1: u8 = 1;
+
+// The raw RDATA of a record the resolver does not decode.
+fn rdata(record: &Record) -> Option<&[u8]> {
+    match &record.data {
+        RecordData::Other(rdata) => Some(rdata),
+        _ => None,
+    }
+}
+
+// A signature over an RRset (RFC 4034 section 3).
+#[derive(Debug, Clone)]
+pub struct Rrsig {
+    pub type_covered: u16,
+    pub algorithm: u8,
+    pub labels: u8,
+    pub original_ttl: u32,
+    pub expiration: u32,
+    pub inception: u32,
+    pub key_tag: u16,
+    pub signer: String,
+    pub signature: Vec<u8>,
+}
+
+impl Rrsig {
+    pub fn parse(record: &Record) -> Option<Rrsig> {
+        let rdata = rdata(record)?;
+        let fixed = rdata.get(..18)?;
+        let (signer, next) = read_name(rdata, 18).ok()?;
+        Some(Rrsig {
+            type_covered: u16::from_be_bytes([fixed[0], fixed[1]]),
+            algorithm: fixed[2],
+            labels: fixed[3],
+            original_ttl: u32::from_be_bytes(fixed[4..8].try_into().unwrap()),
+            expiration: u32::from_be_bytes(fixed[8..12].try_into().unwrap()),
+            inception: u32::from_be_bytes(fixed[12..16].try_into().unwrap()),
+            key_tag: u16::from_be_bytes([fixed[16], fixed[17]]),
+            signer,
+            signature: rdata[next..].to_vec(),
+        })
+    }
+}
+
+// A zone's public key (RFC 4034 section 2).
+#[derive(Debug, Clone)]
+pub struct DnsKey {
+    pub flags: u16,
+    pub algorithm: u8,
+    pub public_key: Vec<u8>,
+    pub key_tag: u16,
+    rdata: Vec<u8>,
+}
+
+impl DnsKey {
+    pub fn parse(record: &Record) -> Option<DnsKey> {
+        let rdata = rdata(record)?;
+        if rdata.len() < 4 || rdata[2] != 3 {
+            return None;
+        }
+        Some(DnsKey {
+            flags: u16::from_be_bytes([rdata[0], rdata[1]]),
+            algorithm: rdata[3],
+            public_key: rdata[4..].to_vec(),
+            key_tag: key_tag(rdata),
+            rdata: rdata.to_vec(),
+        })
+    }
+
+    // Whether the key may be used to verify signatures over the zone's data.
+    fn signs_zone(&self) -> bool {
+        self.flags & FLAG_ZONE_KEY != 0 && self.flags & FLAG_REVOKED == 0
+    }
+}
+
+// A digest of a child zone's key, held by the parent (RFC 4034 section 5).
+#[derive(Debug, Clone)]
+pub struct Ds {
+    pub key_tag: u16,
+    pub algorithm: u8,
+    pub digest_type: u8,
+    pub digest: Vec<u8>,
+}
+
+impl Ds {
+    pub fn parse(record: &Record) -> Option<Ds> {
+        let rdata = rdata(record)?;
+        if rdata.len() < 5 {
+            return None;
+        }
+        Some(Ds {
+            key_tag: u16::from_be_bytes([rdata[0], rdata[1]]),
+            algorithm: rdata[2],
+            digest_type: rdata[3],
+            digest: rdata[4..].to_vec(),
+        })
+    }
+
+    // Whether both the key algorithm and the digest type are ones we can check.
+    pub fn is_supported(&self) -> bool {
+        algorithm_supported(self.algorithm) && digest_algorithm(self.digest_type).is_some()
+    }
+
+    // Whether this DS is a digest of the key, which belongs to the zone.
+    pub fn matches(&self, zone: &str, key: &DnsKey) -> bool {
+        let Some(algorithm) = digest_algorithm(self.digest_type) else {
+            return false;
+        };
+        if key.key_tag != self.key_tag || key.algorithm != self.algorithm {
+            return false;
+        }
+        let mut data = Vec::new();
+        write_name(&mut data, zone);
+        data.extend_from_slice(&key.rdata);
+        digest::digest(algorithm, &data).as_ref() == self.digest.as_slice()
+    }
+}
+
+// Proof of the names that exist between two names and of the types at the first (RFC
+// 4034 section 4).
+#[derive(Debug, Clone)]
+pub struct Nsec {
+    pub next: String,
+    types: Vec<u8>,
+}
+
+impl Nsec {
+    pub fn parse(record: &Record) -> Option<Nsec> {
+        let rdata = rdata(record)?;
+        let (next, types) = read_name(rdata, 0).ok()?;
+        Some(Nsec {
+            next,
+            types: rdata[types..].to_vec(),
+        })
+    }
+
+    pub fn has_type(&self, record_type: u16) -> bool {
+        bitmap_has_type(&self.types, record_type)
+    }
+}
+
+// The hashed form of NSEC (RFC 5155 section 3).
+#[derive(Debug, Clone)]
+pub struct Nsec3 {
+    pub hash_algorithm: u8,
+    pub flags: u8,
+    pub iterations: u16,
+    pub salt: Vec<u8>,
+    // The next hashed owner name, in base32hex like the owner name's first label.
+    pub next: String,
+    types: Vec<u8>,
+}
+
+impl Nsec3 {
+    pub fn parse(record: &Record) -> Option<Nsec3> {
+        let rdata = rdata(record)?;
+        let salt_length = usize::from(*rdata.get(4)?);
+        let salt = rdata.get(5..5 + salt_length)?.to_vec();
+        let hash_at = 5 + salt_length;
+        let hash_length = usize::from(*rdata.get(hash_at)?);
+        let hash = rdata.get(hash_at + 1..hash_at + 1 + hash_length)?;
+        Some(Nsec3 {
+            hash_algorithm: rdata[0],
+            flags: rdata[1],
+            iterations: u16::from_be_bytes([rdata[2], rdata[3]]),
+            salt,
+            next: base32hex(hash),
+            types: rdata[hash_at + 1 + hash_length..].to_vec(),
+        })
+    }
+
+    pub fn has_type(&self, record_type: u16) -> bool {
+        bitmap_has_type(&self.types, record_type)
+    }
+
+    pub fn opt_out(&self) -> bool {
+        self.flags & NSEC3_OPT_OUT != 0
+    }
+
+    pub fn is_supported(&self) -> bool {
+        self.hash_algorithm == NSEC3_SHA1
+    }
+
+    // The hashed owner name a name would have, in base32hex.
+    pub fn hash(&self, name: &str) -> String {
+        let mut data = Vec::new();
+        write_name(&mut data, name);
+        let mut hash = data;
+        for _ in 0..=self.iterations {
+            hash.extend_from_slice(&self.salt);
+            hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &hash)
+                .as_ref()
+                .to_vec();
+        }
+        base32hex(&hash)
+    }
+}
+
+// Whether a type bitmap (RFC 4034 section 4.1.2) lists a type.
+fn bitmap_has_type(mut bitmap: &[u8], record_type: u16) -> bool {
+    let [window, bit] = record_type.to_be_bytes();
+    while bitmap.len() >= 2 {
+        let length = usize::from(bitmap[1]);
+        let Some(bits) = bitmap.get(2..2 + length) else {
+            return false;
+        };
+        if bitmap[0] == window {
+            return bits
+                .get(usize::from(bit / 8))
+                .is_some_and(|byte| byte & (0x80 >> (bit % 8)) != 0);
+        }
+        bitmap = &bitmap[2 + length..];
+    }
+    false
+}
+
+// The lowercase base32hex encoding without padding (RFC 4648 section 7), as used for
+// NSEC3 owner names. Its ordering follows that of the hashes.
+fn base32hex(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuv";
+    let mut encoded = String::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for &byte in data {
+        buffer = (buffer << 8) | u32::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(ALPHABET[((buffer >> bits) & 0x1F) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(ALPHABET[((buffer << (5 - bits)) & 0x1F) as usize] as char);
+    }
+    encoded
+}
+
+// The key tag of a DNSKEY, computed over its RDATA (RFC 4034 appendix B).
+fn key_tag(rdata: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for (i, &byte) in rdata.iter().enumerate() {
+        sum += if i % 2 == 0 {
+            u32::from(byte) << 8
+        } else {
+            u32::from(byte)
+        };
+    }
+    sum += sum >> 16;
+    (sum & 0xFFFF) as u16
+}
+
+fn digest_algorithm(digest_type: u8) -> Option<&'static digest::Algorithm> {
+    match digest_type {
+        DIGEST_SHA1 => Some(&digest::SHA1_FOR_LEGACY_USE_ONLY),
+        DIGEST_SHA256 => Some(&digest::SHA256),
+        DIGEST_SHA384 => Some(&digest::SHA384),
+        _ => None,
+    }
+}
+
+pub fn algorithm_supported(algorithm: u8) -> bool {
+    matches!(
+        algorithm,
+        ALGORITHM_RSASHA1
+            | ALGORITHM_RSASHA1_NSEC3_SHA1
+            | ALGORITHM_RSASHA256
+            | ALGORITHM_RSASHA512
+            | ALGORITHM_ECDSAP256SHA256
+            | ALGORITHM_ECDSAP384SHA384
+            | ALGORITHM_ED25519
+    )
+}
+
+// The number of labels in a name, the root having none.
+pub fn label_count(name: &str) -> usize {
+    if name.is_empty() {
+        0
+    } else {
+        name.split('.').count()
+    }
+}
+
+// The name made up of the last `count` labels of a name.
+pub fn ancestor(name: &str, count: usize) -> &str {
+    let labels = label_count(name);
+    if count >= labels {
+        return name;
+    }
+    if count == 0 {
+        return "";
+    }
+    let skip = labels - count;
+    let at = name
+        .match_indices('.')
+        .nth(skip - 1)
+        .map(|(i, _)| i + 1)
+        .unwrap_or(0);
+    &name[at..]
+}
+
+// The canonical ordering of names (RFC 4034 section 6.1): label by label from the root,
+// comparing lowercase labels as bytes.
+pub fn canonical_cmp(a: &str, b: &str) -> Ordering {
+    let labels = |name: &str| -> Vec<Vec<u8>> {
+        name.split('.')
+            .filter(|label| !label.is_empty())
+            .rev()
+            .map(|label| label.to_ascii_lowercase().into_bytes())
+            .collect()
+    };
+    labels(a).cmp(&labels(b))
+}
+
+// Check a signature over an RRset against the zone's keys at the given time (seconds
+// since the epoch, modulo 2^32). Returns the number of labels the signature was made
+// over, which is less than the owner's when the RRset was expanded from a wildcard.
+pub fn verify_rrsig(
+    rrset: &[Record],
+    rrsig: &Rrsig,
+    keys: &[DnsKey],
+    now: u32,
+) -> Result<u8, &'static str> {
+    let owner = &rrset[0].name;
+    if rrsig.type_covered != rrset[0].record_type {
+        return Err("Signature covers another type");
+    }
+    if usize::from(rrsig.labels) > label_count(owner) {
+        return Err("Signature has more labels than its owner");
+    }
+    // Serial number arithmetic (RFC 1982), as the times wrap around in 2106
+    if (now.wrapping_sub(rrsig.inception) as i32) < 0 {
+        return Err("Signature is not valid yet");
+    }
+    if (rrsig.expiration.wrapping_sub(now) as i32) < 0 {
+        return Err("Signature has expired");
+    }
+
+    // The data signed is the RRSIG RDATA without the signature, then the RRset in
+    // canonical form and order (RFC 4034 section 3.1.8.1)
+    let mut data = Vec::new();
+    data.extend_from_slice(&rrsig.type_covered.to_be_bytes());
+    data.push(rrsig.algorithm);
+    data.push(rrsig.labels);
+    data.extend_from_slice(&rrsig.original_ttl.to_be_bytes());
+    data.extend_from_slice(&rrsig.expiration.to_be_bytes());
+    data.extend_from_slice(&rrsig.inception.to_be_bytes());
+    data.extend_from_slice(&rrsig.key_tag.to_be_bytes());
+    write_name(&mut data, &rrsig.signer);
+
+    let signed_owner = if usize::from(rrsig.labels) < label_count(owner) {
+        let parent = ancestor(owner, usize::from(rrsig.labels));
+        if parent.is_empty() {
+            "*".to_string()
+        } else {
+            format!("*.{}", parent)
+        }
+    } else {
+        owner.clone()
+    };
+    let mut records: Vec<(Vec<u8>, usize)> = rrset
+        .iter()
+        .map(|record| {
+            let mut wire = Vec::new();
+            Record {
+                name: signed_owner.clone(),
+                ttl: rrsig.original_ttl,
+                ..record.clone()
+            }
+            .write(&mut wire);
+            // The RDATA follows the owner and the ten bytes of type, class, TTL and length
+            let rdata_at = wire.len() - record_rdata_len(&wire);
+            (wire, rdata_at)
+        })
+        .collect();
+    records.sort_by(|(a, a_at), (b, b_at)| a[*a_at..].cmp(&b[*b_at..]));
+    records.dedup_by(|(a, _), (b, _)| a == b);
+    for (wire, _) in &records {
+        data.extend_from_slice(wire);
+    }
+
+    let candidates = keys.iter().filter(|key| {
+        key.signs_zone() && key.key_tag == rrsig.key_tag && key.algorithm == rrsig.algorithm
+    });
+    for key in candidates {
+        if verify_signature(key, &data, &rrsig.signature) {
+            return Ok(rrsig.labels);
+        }
+    }
+    Err("No key verifies the signature")
+}
+
+// The length of the RDATA of a record written in wire format, from its RDLENGTH.
+fn record_rdata_len(wire: &[u8]) -> usize {
+    let mut position = 0;
+    while wire[position] != 0 {
+        position += usize::from(wire[position]) + 1;
+    }
+    let length_at = position + 1 + 8;
+    usize::from(u16::from_be_bytes([wire[length_at], wire[length_at + 1]]))
+}
+
+fn verify_signature(key: &DnsKey, data: &[u8], signature: &[u8]) -> bool {
+    match key.algorithm {
+        ALGORITHM_RSASHA1 | ALGORITHM_RSASHA1_NSEC3_SHA1 => verify_rsa(
+            &signature::RSA_PKCS1_1024_8192_SHA1_FOR_LEGACY_USE_ONLY,
+            &key.public_key,
+            data,
+            signature,
+        ),
+        ALGORITHM_RSASHA256 => verify_rsa(
+            &signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY,
+            &key.public_key,
+            data,
+            signature,
+        ),
+        ALGORITHM_RSASHA512 => verify_rsa(
+            &signature::RSA_PKCS1_1024_8192_SHA512_FOR_LEGACY_USE_ONLY,
+            &key.public_key,
+            data,
+            signature,
+        ),
+        // ECDSA keys are the bare point (RFC 6605), which ring takes uncompressed
+        ALGORITHM_ECDSAP256SHA256 | ALGORITHM_ECDSAP384SHA384 => {
+            let algorithm = if key.algorithm == ALGORITHM_ECDSAP256SHA256 {
+                &signature::ECDSA_P256_SHA256_FIXED
+            } else {
+                &signature::ECDSA_P384_SHA384_FIXED
+            };
+            let mut point = vec![0x04];
+            point.extend_from_slice(&key.public_key);
+            UnparsedPublicKey::new(algorithm, point)
+                .verify(data, signature)
+                .is_ok()
+        }
+        ALGORITHM_ED25519 => UnparsedPublicKey::new(&signature::ED25519, &key.public_key)
+            .verify(data, signature)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+// RSA keys are the exponent length, the exponent and the modulus (RFC 3110).
+fn verify_rsa(
+    algorithm: &'static signature::RsaParameters,
+    public_key: &[u8],
+    data: &[u8],
+    signature: &[u8],
+) -> bool {
+    let (exponent_length, rest) = match public_key {
+        [0, high, low, rest @ ..] => (usize::from(u16::from_be_bytes([*high, *low])), rest),
+        [length, rest @ ..] => (usize::from(*length), rest),
+        [] => return false,
+    };
+    if rest.len() <= exponent_length {
+        return false;
+    }
+    let (e, n) = rest.split_at(exponent_length);
+    RsaPublicKeyComponents { n, e }
+        .verify(algorithm, data, signature)
+        .is_ok()
+}
+
+// The DNSKEY RRset of a zone among the records of a response.
+pub fn zone_keys(zone: &str, records: &[Record]) -> Vec<Record> {
+    records
+        .iter()
+        .filter(|r| r.name == zone && r.record_type == TYPE_DNSKEY)
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::message::{CLASS_IN, TYPE_A, TYPE_AAAA, TYPE_DS, TYPE_MX, TYPE_NSEC, TYPE_RRSIG};
+    use std::net::Ipv4Addr;
+
+    // Records of a small signed tree, made with Ed25519 keys from fixed seeds by a
+    // signer independent of this one: a trust anchor for "example", whose key signs its
+    // DNSKEY RRset and the DS of "sub.example", whose own key signs its DNSKEY RRset and
+    // www.sub.example A 192.0.2.1. The signatures are valid from 2020 to 2080 unless
+    // named otherwise.
+    pub(crate) const EXAMPLE_KEY_TAG: u16 = 60795;
+    pub(crate) const EXAMPLE_DS_DIGEST: &str =
+        "da1f7b0056feab8772ff0e19dd9068b2789db9c564057a20675fe0d70736b2e5";
+    pub(crate) const EXAMPLE_KEY: &str = "\
+        0101030f8a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801\
+        b40f6f5c";
+    pub(crate) const EXAMPLE_KEY_RRSIG: &str = "\
+        00300f0100000e10d09dc3005e0be100ed7b076578616d706c650005fd96edab\
+        a0c541653483fc1ebcc0ae1098096881f32e1364b83d0fdd7b42575a8bdbfe46\
+        c81a003eda598675f68b9173bc629db9b76f83f22e7e9ad70ca40e";
+    pub(crate) const SUB_DS: &str = "\
+        9cb70f02a54a7c82a3ad1e72ed4bca55f6a5b063511265288d521c390772a4ce\
+        828c3263";
+    pub(crate) const SUB_DS_RRSIG: &str = "\
+        002b0f0200000e10d09dc3005e0be100ed7b076578616d706c650087220344bd\
+        2cd8b5ae7417830b5f2772828fdbd2ea659fed3bf0090e1946d3195af57444e3\
+        4d93bb02fcd0a3c7f53642c67933ef1619e453819b2ec63dcd7504";
+    pub(crate) const SUB_KEY: &str = "\
+        0101030f8139770ea87d175f56a35466c34c7ecccb8d8a91b4ee37a25df60f5b\
+        8fc9b394";
+    pub(crate) const SUB_KEY_RRSIG: &str = "\
+        00300f0200000e10d09dc3005e0be1009cb703737562076578616d706c6500c6\
+        8e366d0061d40191227181c80aea3dde52556ed9fc9ecb4ea7f336cf471e7719\
+        42bc5f94f63bcaf27ef0926aa612d563f804744bf89536f09fb6591c5b7308";
+    pub(crate) const WWW_A_RRSIG: &str = "\
+        00010f030000012cd09dc3005e0be1009cb703737562076578616d706c650045\
+        719286cf3e44aae618dee76df81e7143fdb053f8f87df9da82d68a40ee6930be\
+        e1786280d6acce2b1e5785ea538e9b801932aa817575f62af0a9ebfe60f600";
+    // Valid from 2010 to 2020.
+    pub(crate) const WWW_A_RRSIG_EXPIRED: &str = "\
+        00010f030000012c5e0be1004b3d3b009cb703737562076578616d706c65000e\
+        283e38b3a628ff4890a128ff7aaa3de125d99feab1e93e6cc6f79022e9720447\
+        6561e9a1033c4df7073d80aaa0e1ee957f7190c175057b4925b0082451d005";
+    // Made with a key that is not in the zone's DNSKEY RRset.
+    pub(crate) const WWW_A_RRSIG_STRANGER: &str = "\
+        00010f030000012cd09dc3005e0be10020f103737562076578616d706c65007b\
+        86f1ba022c91223e90ebf7dedf2550766d1369970f26232a13219e9364f069e4\
+        6f9e4771556bf9dfa72aebde13ec28dc5f6f3e922c7648f572564779a0a308";
+    // Made with the key of "example", the parent zone, but naming "sub.example".
+    pub(crate) const WWW_A_RRSIG_PARENT_KEY: &str = "\
+        00010f030000012cd09dc3005e0be100ed7b03737562076578616d706c650064\
+        354f2b4da5e556a191a66d031b9ff4b50ea6b15c4d0a3f439f533de1b13a82fd\
+        412f86a382a59a810ffbc76c0a3af2079573be75458c600ee671fa0707fa0f";
+    // Made with the key of "sub.example", but naming "other.example".
+    pub(crate) const WWW_A_RRSIG_OTHER_SIGNER: &str = "\
+        00010f030000012cd09dc3005e0be1009cb7056f74686572076578616d706c65\
+        001b96c578dbec231af4ce595816c53478f087d8daf15ce3210f974ed568e577\
+        5638c5ef8779e4d9659eebd51846c26ad404400ba3c6b908488106ff4ccfc269\
+        06";
+    // *.sub.example A 192.0.2.2, and the NSEC from the wildcard to www.sub.example
+    // with its types A, RRSIG and NSEC.
+    pub(crate) const WILDCARD_A_RRSIG: &str = "\
+        00010f020000012cd09dc3005e0be1009cb703737562076578616d706c6500c1\
+        c54f3bac40bb3c18e02ea7bfb95ffe781b73ca7f602731662f3f0184b740405c\
+        1eb8aa7b1aab0be40650399984d0f1cf621fdd4cbf99b1c19bd4a4a929d80f";
+    pub(crate) const WILDCARD_NSEC: &str = "0377777703737562076578616d706c65000006400000000003";
+    pub(crate) const WILDCARD_NSEC_RRSIG: &str = "\
+        002f0f020000012cd09dc3005e0be1009cb703737562076578616d706c650007\
+        8d244e245e27c7ce3ad606780cc02cd226ae7fda0895e35f951a0b11e5d42ce2\
+        a39735b972377b68f5fa0fc3dc053b27335f9e9d7d8efbfd8417c76e8eeb05";
+    // The NSEC of www.sub.example, with its types A, RRSIG and NSEC, proving it is not
+    // a zone cut.
+    pub(crate) const WWW_NSEC: &str = "03737562076578616d706c65000006400000000003";
+    pub(crate) const WWW_NSEC_RRSIG: &str = "\
+        002f0f030000012cd09dc3005e0be1009cb703737562076578616d706c650083\
+        72b5eeca0e44a7a7f028cd1ececcb399b75750693979cc72e2cfa815d25d2823\
+        2d6c01106539050c021d13799cc30f2e9cec40422a903a41e0314a9d433705";
+    // The NSEC3 of the apex of "example", without salt or extra iterations, listing
+    // NS, SOA, RRSIG, DNSKEY and NSEC3PARAM. The next hash is its own, so that it
+    // covers every other name in the zone. The second is the same with opt-out set.
+    pub(crate) const APEX_NSEC3: &str = "\
+        0100000000141db8efa7dcb348bda7893fca1d8badfdb6996b01000722000000\
+        000290";
+    pub(crate) const APEX_NSEC3_OPT_OUT: &str = "\
+        0101000000141db8efa7dcb348bda7893fca1d8badfdb6996b01000722000000\
+        000290";
+
+    // A time between the inception and expiration of the fixtures' signatures.
+    const NOW: u32 = 1_800_000_000;
+
+    pub(crate) fn record(name: &str, record_type: u16, ttl: u32, rdata: &str) -> Record {
+        Record {
+            name: name.to_string(),
+            record_type,
+            class: CLASS_IN,
+            ttl,
+            data: RecordData::Other(hex(rdata)),
+        }
+    }
+
+    pub(crate) fn a(name: &str, address: Ipv4Addr) -> Record {
+        Record {
+            name: name.to_string(),
+            record_type: TYPE_A,
+            class: CLASS_IN,
+            ttl: 300,
+            data: RecordData::A(address),
+        }
+    }
+
+    fn hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn rrsig(name: &str, rdata: &str) -> Rrsig {
+        Rrsig::parse(&record(name, TYPE_RRSIG, 300, rdata)).unwrap()
+    }
+
+    fn key(name: &str, rdata: &str) -> DnsKey {
+        DnsKey::parse(&record(name, TYPE_DNSKEY, 3600, rdata)).unwrap()
+    }
+
+    fn www() -> Vec<Record> {
+        vec![a("www.sub.example", Ipv4Addr::new(192, 0, 2, 1))]
+    }
+
+    #[test]
+    fn key_tags_and_ds_digests() {
+        let example = key("example", EXAMPLE_KEY);
+        let sub = key("sub.example", SUB_KEY);
+        assert_eq!(example.key_tag, EXAMPLE_KEY_TAG);
+        assert!(example.signs_zone());
+
+        let ds = Ds::parse(&record("sub.example", TYPE_DS, 3600, SUB_DS)).unwrap();
+        assert!(ds.is_supported());
+        assert!(ds.matches("sub.example", &sub));
+        assert!(!ds.matches("other.example", &sub));
+        assert!(!ds.matches("sub.example", &example));
+    }
+
+    #[test]
+    fn verifies_a_signed_rrset() {
+        let keys = [key("sub.example", SUB_KEY)];
+        let rrsig = rrsig("www.sub.example", WWW_A_RRSIG);
+        assert_eq!(verify_rrsig(&www(), &rrsig, &keys, NOW), Ok(3));
+
+        // The TTL a cache has counted down does not matter, as the original is signed
+        let mut aged = www();
+        aged[0].ttl = 10;
+        assert_eq!(verify_rrsig(&aged, &rrsig, &keys, NOW), Ok(3));
+    }
+
+    #[test]
+    fn rejects_signatures_outside_their_validity() {
+        let keys = [key("sub.example", SUB_KEY)];
+        let expired = rrsig("www.sub.example", WWW_A_RRSIG_EXPIRED);
+        assert_eq!(
+            verify_rrsig(&www(), &expired, &keys, NOW),
+            Err("Signature has expired")
+        );
+
+        let valid = rrsig("www.sub.example", WWW_A_RRSIG);
+        assert_eq!(
+            verify_rrsig(&www(), &valid, &keys, valid.inception - 1),
+            Err("Signature is not valid yet")
+        );
+    }
+
+    #[test]
+    fn rejects_other_keys_and_altered_data() {
+        let keys = [key("sub.example", SUB_KEY)];
+        let stranger = rrsig("www.sub.example", WWW_A_RRSIG_STRANGER);
+        assert_eq!(
+            verify_rrsig(&www(), &stranger, &keys, NOW),
+            Err("No key verifies the signature")
+        );
+
+        let valid = rrsig("www.sub.example", WWW_A_RRSIG);
+        let altered = vec![a("www.sub.example", Ipv4Addr::new(192, 0, 2, 99))];
+        assert_eq!(
+            verify_rrsig(&altered, &valid, &keys, NOW),
+            Err("No key verifies the signature")
+        );
+
+        // A revoked key no longer signs anything
+        let mut revoked = keys[0].clone();
+        revoked.flags |= FLAG_REVOKED;
+        assert_eq!(
+            verify_rrsig(&www(), &valid, &[revoked], NOW),
+            Err("No key verifies the signature")
+        );
+    }
+
+    #[test]
+    fn verifies_a_wildcard_expansion() {
+        let keys = [key("sub.example", SUB_KEY)];
+        let rrsig = rrsig("host.sub.example", WILDCARD_A_RRSIG);
+        let expanded = vec![a("host.sub.example", Ipv4Addr::new(192, 0, 2, 2))];
+        // The labels say the RRset was expanded from the wildcard one label up
+        assert_eq!(verify_rrsig(&expanded, &rrsig, &keys, NOW), Ok(2));
+    }
+
+    #[test]
+    fn nsec3_hashes() {
+        // The hashes from RFC 5155 appendix A
+        let nsec3 = Nsec3 {
+            hash_algorithm: NSEC3_SHA1,
+            flags: 0,
+            iterations: 12,
+            salt: vec![0xaa, 0xbb, 0xcc, 0xdd],
+            next: String::new(),
+            types: Vec::new(),
+        };
+        assert_eq!(nsec3.hash("example"), "0p9mhaveqvm6t7vbl5lop2u3t2rp3tom");
+        assert_eq!(nsec3.hash("a.example"), "35mthgpgcu1qg68fab165klnsnk3dpvl");
+        assert_eq!(nsec3.hash("ai.example"), "gjeqe526plbf1g8mklp59enfd789njgi");
+    }
+
+    #[test]
+    fn parses_type_bitmaps() {
+        let nsec = Nsec::parse(&record("*.sub.example", TYPE_NSEC, 300, WILDCARD_NSEC)).unwrap();
+        assert_eq!(nsec.next, "www.sub.example");
+        assert!(nsec.has_type(TYPE_A));
+        assert!(nsec.has_type(TYPE_RRSIG));
+        assert!(nsec.has_type(TYPE_NSEC));
+        assert!(!nsec.has_type(TYPE_AAAA));
+        assert!(!nsec.has_type(TYPE_MX));
+        assert!(!nsec.has_type(TYPE_DNSKEY));
+    }
+
+    #[test]
+    fn canonical_order() {
+        // The example from RFC 4034 section 6.1, without the escaped labels
+        let names = [
+            "example",
+            "a.example",
+            "yljkjljk.a.example",
+            "Z.a.example",
+            "zABC.a.EXAMPLE",
+            "z.example",
+            "*.z.example",
+        ];
+        for pair in names.windows(2) {
+            assert_eq!(
+                canonical_cmp(pair[0], pair[1]),
+                Ordering::Less,
+                "{:?}",
+                pair
+            );
+        }
+        assert_eq!(canonical_cmp("Z.a.example", "z.a.example"), Ordering::Equal);
+    }
+
+    #[test]
+    fn labels_and_ancestors() {
+        assert_eq!(label_count(""), 0);
+        assert_eq!(label_count("www.sub.example"), 3);
+        assert_eq!(ancestor("www.sub.example", 0), "");
+        assert_eq!(ancestor("www.sub.example", 2), "sub.example");
+        assert_eq!(ancestor("www.sub.example", 5), "www.sub.example");
+    }
+}
@@ -0,0 +1,221 @@
+use crate::config::ForwardConfig;
+use crate::message::{parse_message, Message, RCODE_NOERROR, RCODE_NXDOMAIN};
+use crate::rtt::{fastest_first, Rtt};
+use crate::subnet::ClientSubnet;
+use crate::trace;
+use crate::transport::Transport;
+use crate::upstream::QueryOptions;
+use std::error::Error;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// The health of one upstream server.
+struct Upstream {
+    address: String,
+    consecutive_failures: u32,
+    // While set and in the future, the upstream is only tried once the others have failed.
+    backoff_until: Option<Instant>,
+    rtt: Rtt,
+}
+
+// The health of an upstream as reported to the admin interface.
+pub struct UpstreamHealth {
+    pub address: String,
+    pub consecutive_failures: u32,
+    // How much longer the upstream is backed off for, if it is.
+    pub backed_off_for: Option<Duration>,
+    pub rtt: Option<Duration>,
+}
+
+// Forwards queries to a list of upstream servers, over plain DNS, TLS or HTTPS. Each
+// query is tried against the upstreams fastest first, failing over to the next one on
+// timeouts and errors. Upstreams that keep failing are backed off for a while, for
+// longer each time they fail again.
+pub struct Forwarder {
+    upstreams: Mutex<Vec<Upstream>>,
+    // How each upstream is reached, in the same order.
+    transports: Vec<Transport>,
+    query_timeout: Duration,
+    attempts: usize,
+    failures_before_backoff: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+    randomize_case: bool,
+    dnssec_ok: bool,
+}
+
+impl Forwarder {
+    // A forwarder to the given upstreams, retrying and backing them off as configured.
+    // The certificates of encrypted upstreams are also checked against `ca_bundle`, the
+    // contents of a PEM file, if given.
+    pub fn new(
+        upstreams: &[String],
+        config: &ForwardConfig,
+        ca_bundle: Option<&[u8]>,
+        query_timeout: Duration,
+        randomize_case: bool,
+        dnssec_ok: bool,
+    ) -> Result<Self, String> {
+        let transports = upstreams
+            .iter()
+            .map(|address| Transport::new(address, ca_bundle))
+            .collect::<Result<_, _>>()?;
+        Ok(Forwarder {
+            upstreams: Mutex::new(
+                upstreams
+                    .iter()
+                    .map(|address| Upstream {
+                        address: address.clone(),
+                        consecutive_failures: 0,
+                        backoff_until: None,
+                        rtt: Rtt::default(),
+                    })
+                    .collect(),
+            ),
+            transports,
+            query_timeout,
+            attempts: config.attempts.max(1),
+            failures_before_backoff: config.failures_before_backoff.max(1),
+            backoff: Duration::from_secs(config.backoff_secs),
+            max_backoff: Duration::from_secs(config.max_backoff_secs),
+            randomize_case,
+            dnssec_ok,
+        })
+    }
+
+    // Resolve a question through the upstreams, returning the first usable response, or
+    // an error once every attempt has failed. With a client subnet the upstreams are
+    // told where the client is.
+    pub async fn resolve(
+        &self,
+        domain: &str,
+        qtype: u16,
+        client_subnet: Option<ClientSubnet>,
+    ) -> Result<Message, Box<dyn Error + Send + Sync>> {
+        let options = QueryOptions {
+            recursion_desired: true,
+            randomize_case: self.randomize_case,
+            dnssec_ok: self.dnssec_ok,
+            client_subnet,
+        };
+        for attempt in 1..=self.attempts {
+            for (index, address) in self.upstream_order() {
+                let started = Instant::now();
+                let result = query_authoritative_server(
+                    domain,
+                    qtype,
+                    &self.transports[index],
+                    self.query_timeout,
+                    options,
+                )
+                .await;
+                trace::query(&address, domain, qtype, started.elapsed(), &result);
+                match result {
+                    Ok(response) => {
+                        self.record_success(index, started.elapsed());
+                        return Ok(response);
+                    }
+                    Err(e) => {
+                        eprintln!(
+                            "Upstream {} failed for {} (attempt {}): {}",
+                            address, domain, attempt, e
+                        );
+                        self.record_failure(index);
+                    }
+                }
+            }
+        }
+        Err(format!("All upstreams failed to resolve {}", domain).into())
+    }
+
+    // Healthy upstreams by their round-trip times, those not yet timed in their
+    // configured order, then the backed off ones starting with the one whose backoff
+    // ends soonest.
+    fn upstream_order(&self) -> Vec<(usize, String)> {
+        let now = Instant::now();
+        let upstreams = self.upstreams.lock().unwrap();
+        let (healthy, mut backed_off): (Vec<usize>, Vec<usize>) = (0..upstreams.len())
+            .partition(|&i| upstreams[i].backoff_until.is_none_or(|until| until <= now));
+        let mut healthy =
+            fastest_first(healthy.into_iter().map(|i| (i, upstreams[i].rtt)).collect());
+        backed_off.sort_by_key(|&i| upstreams[i].backoff_until);
+        healthy.append(&mut backed_off);
+        healthy
+            .into_iter()
+            .map(|i| (i, upstreams[i].address.clone()))
+            .collect()
+    }
+
+    // The health of every upstream, in their configured order.
+    pub fn health(&self) -> Vec<UpstreamHealth> {
+        let now = Instant::now();
+        self.upstreams
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|upstream| UpstreamHealth {
+                address: upstream.address.clone(),
+                consecutive_failures: upstream.consecutive_failures,
+                backed_off_for: upstream
+                    .backoff_until
+                    .filter(|until| *until > now)
+                    .map(|until| until - now),
+                rtt: upstream.rtt.smoothed(),
+            })
+            .collect()
+    }
+
+    fn record_success(&self, index: usize, rtt: Duration) {
+        let mut upstreams = self.upstreams.lock().unwrap();
+        let upstream = &mut upstreams[index];
+        if upstream.backoff_until.is_some() {
+            println!("Upstream {} has recovered", upstream.address);
+        }
+        upstream.consecutive_failures = 0;
+        upstream.backoff_until = None;
+        upstream.rtt.answered(rtt);
+    }
+
+    fn record_failure(&self, index: usize) {
+        let mut upstreams = self.upstreams.lock().unwrap();
+        let upstream = &mut upstreams[index];
+        upstream.consecutive_failures += 1;
+        upstream.rtt.failed();
+        if upstream.consecutive_failures < self.failures_before_backoff {
+            return;
+        }
+
+        // Double the backoff for every failure past the threshold, up to the maximum.
+        let doublings = (upstream.consecutive_failures - self.failures_before_backoff).min(16);
+        let backoff = self
+            .backoff
+            .saturating_mul(1 << doublings)
+            .min(self.max_backoff);
+        upstream.backoff_until = Some(Instant::now() + backoff);
+        println!(
+            "Backing off upstream {} for {:?} after {} consecutive failures",
+            upstream.address, backoff, upstream.consecutive_failures
+        );
+    }
+}
+
+// Query an upstream DNS server. Returns an error if the server does not answer within
+// the timeout or answers with an error other than NXDOMAIN.
+async fn query_authoritative_server(
+    domain: &str,
+    qtype: u16,
+    transport: &Transport,
+    query_timeout: Duration,
+    options: QueryOptions,
+) -> Result<Message, Box<dyn Error + Send + Sync>> {
+    let response = transport
+        .exchange(domain, qtype, query_timeout, options)
+        .await?;
+    let message = parse_message(&response)?;
+
+    let rcode = message.rcode();
+    if rcode != RCODE_NOERROR && rcode != RCODE_NXDOMAIN {
+        return Err(format!("Server answered with rcode {}", rcode).into());
+    }
+    Ok(message)
+}
@@ -0,0 +1,118 @@
+use crate::message::{Answer, Record, RecordData, CLASS_IN, TYPE_A, TYPE_AAAA, TYPE_PTR};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use tokio::fs;
+
+// The TTL of answers from the hosts file. Kept short so that edits to the file are
+// picked up by clients soon after the resolver restarts.
+const HOSTS_TTL: u32 = 60;
+
+// Names answered locally from a hosts file, with the records each name has: its
+// addresses, and for the reverse name of each address the first name given for it.
+#[derive(Default)]
+pub struct Hosts {
+    names: HashMap<String, Vec<Record>>,
+}
+
+impl Hosts {
+    pub async fn load(path: &str) -> Result<Hosts, String> {
+        let contents = fs::read_to_string(path)
+            .await
+            .map_err(|e| format!("{}: {}", path, e))?;
+        let hosts = Hosts::parse(&contents).map_err(|e| format!("{}: {}", path, e))?;
+        println!(
+            "Loaded {} names from hosts file {}",
+            hosts.names.len(),
+            path
+        );
+        Ok(hosts)
+    }
+
+    // Parse a hosts file: an address and then the names it is for on each line, with
+    // comments starting with '#'.
+    fn parse(contents: &str) -> Result<Hosts, String> {
+        let mut hosts = Hosts::default();
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let mut fields = line.split_whitespace();
+            let Some(address) = fields.next() else {
+                continue;
+            };
+            let address: IpAddr = address
+                .parse()
+                .map_err(|_| format!("line {}: invalid address {}", index + 1, address))?;
+            let names: Vec<String> = fields
+                .map(|name| name.trim_end_matches('.').to_ascii_lowercase())
+                .collect();
+            if names.is_empty() {
+                return Err(format!("line {}: no names for {}", index + 1, address));
+            }
+
+            let (record_type, data) = match address {
+                IpAddr::V4(address) => (TYPE_A, RecordData::A(address)),
+                IpAddr::V6(address) => (TYPE_AAAA, RecordData::AAAA(address)),
+            };
+            for name in &names {
+                hosts.add(name, record_type, data.clone());
+            }
+            // Only the first line for an address names it in reverse
+            let reverse = reverse_name(address);
+            if !hosts.names.contains_key(&reverse) {
+                hosts.add(&reverse, TYPE_PTR, RecordData::PTR(names[0].clone()));
+            }
+        }
+        Ok(hosts)
+    }
+
+    fn add(&mut self, name: &str, record_type: u16, data: RecordData) {
+        let records = self.names.entry(name.to_string()).or_default();
+        let record = Record {
+            name: name.to_string(),
+            record_type,
+            class: CLASS_IN,
+            ttl: HOSTS_TTL,
+            data,
+        };
+        if !records.contains(&record) {
+            records.push(record);
+        }
+    }
+
+    // The answer to a question about a name in the hosts file, which owns the name
+    // outright: asking for a type it has no records of gets NODATA. None for names the
+    // file does not have.
+    pub fn lookup(&self, name: &str, qtype: u16) -> Option<Answer> {
+        let records = self.names.get(name)?;
+        let answers: Vec<Record> = records
+            .iter()
+            .filter(|r| r.record_type == qtype)
+            .cloned()
+            .collect();
+        if answers.is_empty() {
+            Some(Answer::NoData(None))
+        } else {
+            Some(Answer::Records(answers))
+        }
+    }
+}
+
+// The name PTR records for an address live under: in-addr.arpa with the octets of an
+// IPv4 address reversed, or ip6.arpa with the nibbles of an IPv6 address reversed.
+fn reverse_name(address: IpAddr) -> String {
+    match address {
+        IpAddr::V4(address) => {
+            let octets = address.octets();
+            format!(
+                "{}.{}.{}.{}.in-addr.arpa",
+                octets[3], octets[2], octets[1], octets[0]
+            )
+        }
+        IpAddr::V6(address) => {
+            let mut name = String::new();
+            for octet in address.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", octet & 0x0F, octet >> 4));
+            }
+            name + "ip6.arpa"
+        }
+    }
+}
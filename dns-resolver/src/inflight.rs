@@ -0,0 +1,97 @@
+use crate::subnet::ClientSubnet;
+use crate::trace;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+
+type Key = (String, u16, Option<ClientSubnet>);
+type Waiters<T> = Vec<oneshot::Sender<Result<T, String>>>;
+
+// Coalesces identical lookups that are outstanding at the same time, so that only the
+// first of them goes upstream and the others wait for its result (single-flight).
+pub struct InFlight<T> {
+    lookups: Mutex<HashMap<Key, Waiters<T>>>,
+}
+
+// Held by the lookup that goes upstream. If that lookup is abandoned before it finishes,
+// dropping this removes its entry, so the waiters fail instead of waiting forever and the
+// next query for the name starts a fresh lookup. Once the lookup has finished and taken
+// its entry out itself, the entry may already belong to a new lookup, so it is left be.
+struct Leader<'a, T> {
+    in_flight: &'a InFlight<T>,
+    key: Key,
+    finished: bool,
+}
+
+impl<T> Drop for Leader<'_, T> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.in_flight.lookups.lock().unwrap().remove(&self.key);
+        }
+    }
+}
+
+impl<T: Clone> InFlight<T> {
+    pub fn new() -> Self {
+        InFlight {
+            lookups: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Run `lookup` for the name and type, unless a lookup for them is already in flight,
+    // in which case wait for that one's result instead. Lookups for different client
+    // subnets may get different answers, so they are never joined.
+    pub async fn run<F>(
+        &self,
+        domain: &str,
+        qtype: u16,
+        client_subnet: Option<ClientSubnet>,
+        lookup: F,
+    ) -> Result<T, String>
+    where
+        F: Future<Output = Result<T, String>>,
+    {
+        let key = (domain.to_string(), qtype, client_subnet);
+        let waiting = {
+            let mut lookups = self.lookups.lock().unwrap();
+            match lookups.get_mut(&key) {
+                Some(waiters) => {
+                    let (sender, receiver) = oneshot::channel();
+                    waiters.push(sender);
+                    Some(receiver)
+                }
+                None => {
+                    lookups.insert(key.clone(), Vec::new());
+                    None
+                }
+            }
+        };
+
+        if let Some(receiver) = waiting {
+            println!("Joining lookup already in flight for {}", domain);
+            trace::step("joined", domain, "waiting for the lookup already in flight");
+            return receiver
+                .await
+                .unwrap_or_else(|_| Err("The lookup in flight was abandoned".to_string()));
+        }
+
+        let mut leader = Leader {
+            in_flight: self,
+            key,
+            finished: false,
+        };
+        let result = lookup.await;
+        let waiters = self
+            .lookups
+            .lock()
+            .unwrap()
+            .remove(&leader.key)
+            .unwrap_or_default();
+        leader.finished = true;
+        for waiter in waiters {
+            let _ = waiter.send(result.clone());
+        }
+        result
+    }
+}
@@ -0,0 +1,450 @@
+use crate::cache::{DnsCache, Trust};
+use crate::dnssec_records::{ancestor, label_count};
+use crate::message::{
+    is_subdomain, parse_message, Message, Record, RecordData, RCODE_NOERROR, RCODE_NXDOMAIN,
+    TYPE_A, TYPE_DS, TYPE_NS,
+};
+use crate::rtt::{fastest_first, Rtt};
+use crate::trace;
+use crate::upstream::{exchange, QueryOptions};
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// The most referrals followed for one name before giving up.
+const MAX_REFERRALS: usize = 16;
+// How deep resolution may nest when the addresses of name servers have to be looked up.
+const MAX_DEPTH: usize = 4;
+// With QNAME minimisation, the most queries with a shortened name sent for one name, and
+// how many of them add just one label (RFC 9156, section 2.3). Names with more labels
+// than that, such as those under ip6.arpa, have several labels added at a time.
+const MAX_MINIMISE_COUNT: usize = 10;
+const MINIMISE_ONE_LAB: usize = 4;
+// Past this many servers timed, those not heard of lately are forgotten.
+const MAX_TIMED_SERVERS: usize = 4096;
+
+// A name server for a zone, with whatever addresses we know for it.
+#[derive(Debug, Clone)]
+pub struct NameServer {
+    pub name: String,
+    pub addresses: Vec<SocketAddr>,
+}
+
+// Resolves names by walking the DNS hierarchy, starting from the root hints and
+// following referrals down to the servers authoritative for the name. Delegations are
+// kept in the shared cache as NS and glue RRsets of the lowest trust. The servers of a
+// zone are tried fastest first, by the round-trip times learnt from their answers.
+//
+// With QNAME minimisation each zone's servers are only asked about the name one label
+// below the zone (with type A), so that a root server never sees more than the TLD.
+// Names that turn out not to be delegated get one more label until the whole name is
+// asked, with the real type, of the servers authoritative for it.
+pub struct IterativeResolver {
+    root_hints: Vec<NameServer>,
+    cache: Arc<DnsCache>,
+    rtts: Mutex<HashMap<SocketAddr, Rtt>>,
+    query_timeout: Duration,
+    randomize_case: bool,
+    dnssec_ok: bool,
+    qname_minimisation: bool,
+}
+
+type ResolveFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Message, Box<dyn Error + Send + Sync>>> + Send + 'a>>;
+
+impl IterativeResolver {
+    pub fn new(
+        root_hints: Vec<NameServer>,
+        cache: Arc<DnsCache>,
+        query_timeout: Duration,
+        randomize_case: bool,
+        dnssec_ok: bool,
+        qname_minimisation: bool,
+    ) -> Self {
+        IterativeResolver {
+            root_hints,
+            cache,
+            rtts: Mutex::new(HashMap::new()),
+            query_timeout,
+            randomize_case,
+            dnssec_ok,
+            qname_minimisation,
+        }
+    }
+
+    // Resolve a question, returning the final response from an authoritative server.
+    pub async fn resolve(
+        &self,
+        domain: &str,
+        qtype: u16,
+    ) -> Result<Message, Box<dyn Error + Send + Sync>> {
+        self.resolve_at_depth(domain.to_ascii_lowercase(), qtype, 0)
+            .await
+    }
+
+    fn resolve_at_depth(&self, domain: String, qtype: u16, depth: usize) -> ResolveFuture<'_> {
+        Box::pin(async move {
+            if depth > MAX_DEPTH {
+                return Err(format!("Resolution of {} nested too deeply", domain).into());
+            }
+
+            // A DS record lives in the zone above the one it is for, so it is asked of the
+            // parent's servers
+            let (mut zone, mut servers) = match domain.split_once('.') {
+                Some((_, parent)) if qtype == TYPE_DS => self.closest_delegation(parent),
+                None if qtype == TYPE_DS => self.closest_delegation(""),
+                _ => self.closest_delegation(&domain),
+            };
+            // How many labels of the name the servers of the zone are known to need to
+            // see: those of the zone itself, or more once they have said that a longer
+            // name is not delegated
+            let total = label_count(&domain);
+            let mut known = label_count(&zone);
+            let mut minimise = self.qname_minimisation;
+            let mut minimised_queries = 0;
+            for _ in 0..=MAX_REFERRALS + MAX_MINIMISE_COUNT {
+                let shown = if minimise && known < total {
+                    minimised_labels(known, total, minimised_queries)
+                } else {
+                    total
+                };
+                let minimised = shown < total;
+                let (name, asked) = if minimised {
+                    minimised_queries += 1;
+                    (ancestor(&domain, shown), TYPE_A)
+                } else {
+                    (domain.as_str(), qtype)
+                };
+                if minimised {
+                    println!(
+                        "Asking servers for zone '{}' about {} (minimised from {})",
+                        zone, name, domain
+                    );
+                } else {
+                    println!("Asking servers for zone '{}' about {}", zone, domain);
+                }
+
+                // Some servers fail or answer NXDOMAIN for names that only have records
+                // below them. Rather than trust that, the full name is asked instead.
+                let result = self.query_servers(&servers, name, asked, depth).await;
+                if minimised
+                    && result
+                        .as_ref()
+                        .map_or(true, |response| response.rcode() == RCODE_NXDOMAIN)
+                {
+                    eprintln!(
+                        "Minimised query for {} failed in zone '{}', asking for the full name",
+                        name, zone
+                    );
+                    trace::step(
+                        "minimise_fallback",
+                        name,
+                        format_args!("zone '{}' failed the minimised query", zone),
+                    );
+                    minimise = false;
+                    continue;
+                }
+                let mut response = result?;
+
+                match referral(&response, &zone, name, asked) {
+                    Some((child, ns_records, glue)) => {
+                        println!("Referred from '{}' to '{}'", zone, child);
+                        trace::step(
+                            "referral",
+                            name,
+                            format_args!("from zone '{}' to '{}'", zone, child),
+                        );
+                        servers = name_servers(&ns_records, &glue);
+                        self.cache.insert_rrsets(
+                            ns_records.into_iter().chain(glue).collect(),
+                            Trust::Additional,
+                        );
+                        known = label_count(&child);
+                        zone = child;
+                    }
+                    // The shortened name is not delegated, so the same servers are asked
+                    // about a longer one
+                    None if minimised => known = shown,
+                    // The servers are only trusted for names within their zone, so a
+                    // CNAME chain leading out of it ends there, and its target is
+                    // looked up on its own rather than taken from this response
+                    None => {
+                        response.answers.retain(|r| is_subdomain(&r.name, &zone));
+                        return Ok(response);
+                    }
+                }
+            }
+
+            Err(format!("Too many referrals resolving {}", domain).into())
+        })
+    }
+
+    // The deepest zone enclosing the name that we have cached name servers for, with
+    // whatever addresses are cached for them, falling back to the root hints.
+    fn closest_delegation(&self, domain: &str) -> (String, Vec<NameServer>) {
+        let mut candidate = domain;
+        loop {
+            if let Some(ns_records) = self.cache.get_rrset(candidate, TYPE_NS) {
+                let glue: Vec<Record> = ns_records
+                    .iter()
+                    .filter_map(|r| match &r.data {
+                        RecordData::NS(target) => self.cache.get_rrset(target, TYPE_A),
+                        _ => None,
+                    })
+                    .flatten()
+                    .collect();
+                return (candidate.to_string(), name_servers(&ns_records, &glue));
+            }
+            match candidate.split_once('.') {
+                Some((_, parent)) => candidate = parent,
+                None if !candidate.is_empty() => candidate = "",
+                None => break,
+            }
+        }
+        (String::new(), self.root_hints.clone())
+    }
+
+    // Ask each server in turn until one gives a usable answer, the servers we have
+    // addresses for fastest first. Servers we have no address for (no glue) are tried
+    // last, as their addresses have to be resolved first.
+    async fn query_servers(
+        &self,
+        servers: &[NameServer],
+        domain: &str,
+        qtype: u16,
+        depth: usize,
+    ) -> Result<Message, Box<dyn Error + Send + Sync>> {
+        let (known, unknown): (Vec<&NameServer>, Vec<&NameServer>) = servers
+            .iter()
+            .partition(|server| !server.addresses.is_empty());
+        let addresses = known
+            .iter()
+            .flat_map(|server| server.addresses.iter().map(|a| (server.name.as_str(), *a)))
+            .collect();
+        if let Some(response) = self.query_addresses(addresses, domain, qtype).await {
+            return Ok(response);
+        }
+
+        for server in unknown {
+            let addresses = match self
+                .resolve_at_depth(server.name.clone(), TYPE_A, depth + 1)
+                .await
+            {
+                Ok(response) => {
+                    let answer = response.answer(&server.name, TYPE_A);
+                    self.cache
+                        .insert(&server.name, TYPE_A, answer, Trust::of_answer(&response));
+                    addresses_from_answers(&response, &server.name)
+                }
+                Err(e) => {
+                    eprintln!("Failed to resolve name server {}: {}", server.name, e);
+                    continue;
+                }
+            };
+            let addresses = addresses.into_iter().map(|a| (server.name.as_str(), a));
+            if let Some(response) = self
+                .query_addresses(addresses.collect(), domain, qtype)
+                .await
+            {
+                return Ok(response);
+            }
+        }
+        Err(format!("No name server could answer for {}", domain).into())
+    }
+
+    // Ask the addresses of name servers, fastest first, until one gives a usable answer.
+    async fn query_addresses(
+        &self,
+        addresses: Vec<(&str, SocketAddr)>,
+        domain: &str,
+        qtype: u16,
+    ) -> Option<Message> {
+        let addresses = {
+            let rtts = self.rtts.lock().unwrap();
+            let timed = addresses
+                .into_iter()
+                .map(|(name, address)| {
+                    let rtt = rtts.get(&address).copied().unwrap_or_default();
+                    ((name, address), rtt)
+                })
+                .collect();
+            fastest_first(timed)
+        };
+
+        for (name, address) in addresses {
+            match self.query_server(address, domain, qtype).await {
+                Ok(response)
+                    if response.rcode() == RCODE_NOERROR || response.rcode() == RCODE_NXDOMAIN =>
+                {
+                    return Some(response)
+                }
+                Ok(response) => eprintln!(
+                    "Server {} ({}) answered with rcode {}",
+                    name,
+                    address,
+                    response.rcode()
+                ),
+                Err(e) => eprintln!("Query to {} ({}) failed: {}", name, address, e),
+            }
+        }
+        None
+    }
+
+    // Send a single non-recursive query to a server and wait for its response, timing
+    // it. Timeouts, errors and error responses count against the server.
+    async fn query_server(
+        &self,
+        address: SocketAddr,
+        domain: &str,
+        qtype: u16,
+    ) -> Result<Message, Box<dyn Error + Send + Sync>> {
+        let started = Instant::now();
+        let options = QueryOptions {
+            recursion_desired: false,
+            randomize_case: self.randomize_case,
+            dnssec_ok: self.dnssec_ok,
+            client_subnet: None,
+        };
+        let response = exchange(address, domain, qtype, self.query_timeout, options)
+            .await
+            .and_then(|response| Ok(parse_message(&response)?));
+        trace::query(address, domain, qtype, started.elapsed(), &response);
+
+        let mut rtts = self.rtts.lock().unwrap();
+        if rtts.len() >= MAX_TIMED_SERVERS {
+            rtts.retain(|_, rtt| rtt.is_fresh());
+        }
+        let rtt = rtts.entry(address).or_default();
+        match &response {
+            Ok(message)
+                if message.rcode() == RCODE_NOERROR || message.rcode() == RCODE_NXDOMAIN =>
+            {
+                rtt.answered(started.elapsed())
+            }
+            _ => rtt.failed(),
+        }
+        response
+    }
+
+    // The servers timed lately, with their round-trip times.
+    pub fn server_rtts(&self) -> Vec<(SocketAddr, Rtt)> {
+        let mut rtts: Vec<(SocketAddr, Rtt)> = self
+            .rtts
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, rtt)| rtt.is_fresh())
+            .map(|(address, rtt)| (*address, *rtt))
+            .collect();
+        rtts.sort_by_key(|(address, _)| *address);
+        rtts
+    }
+}
+
+// How many labels of a name to show the servers of a zone who are known to need `known`
+// of its `total` labels, after `queries` minimised queries for the name: one more label
+// at first, then the rest spread over the queries left (RFC 9156, section 2.3).
+fn minimised_labels(known: usize, total: usize, queries: usize) -> usize {
+    let remaining = total - known;
+    let added = if queries < MINIMISE_ONE_LAB {
+        1
+    } else if queries < MAX_MINIMISE_COUNT {
+        (remaining / (MAX_MINIMISE_COUNT - queries)).max(1)
+    } else {
+        remaining
+    };
+    known + added.min(remaining)
+}
+
+// If the response is a referral to a zone below the one we asked, return the child zone,
+// its NS records and their glue. Only referrals that move closer to the name are
+// accepted, and only glue within the zone we asked is trusted. A DS question is never
+// referred to the zone the DS is for, as only the parent holds the DS.
+fn referral(
+    response: &Message,
+    zone: &str,
+    domain: &str,
+    qtype: u16,
+) -> Option<(String, Vec<Record>, Vec<Record>)> {
+    if response.rcode() != RCODE_NOERROR
+        || !response.answers.is_empty()
+        || response.is_authoritative()
+    {
+        return None;
+    }
+
+    let child = response
+        .authority
+        .iter()
+        .find(|r| r.record_type == TYPE_NS)?
+        .name
+        .clone();
+    if child == zone
+        || !is_subdomain(&child, zone)
+        || !is_subdomain(domain, &child)
+        || (qtype == TYPE_DS && child == domain)
+    {
+        eprintln!(
+            "Ignoring referral from '{}' to '{}' for {}",
+            zone, child, domain
+        );
+        return None;
+    }
+
+    let ns_records: Vec<Record> = response
+        .authority
+        .iter()
+        .filter(|r| r.record_type == TYPE_NS && r.name == child)
+        .cloned()
+        .collect();
+    let glue = response
+        .additional
+        .iter()
+        .filter(|r| matches!(r.data, RecordData::A(_)) && is_subdomain(&r.name, zone))
+        .filter(|r| {
+            ns_records
+                .iter()
+                .any(|ns| matches!(&ns.data, RecordData::NS(target) if *target == r.name))
+        })
+        .cloned()
+        .collect();
+
+    Some((child, ns_records, glue))
+}
+
+// The name servers named by NS records, with their addresses from the glue.
+fn name_servers(ns_records: &[Record], glue: &[Record]) -> Vec<NameServer> {
+    ns_records
+        .iter()
+        .filter_map(|r| match &r.data {
+            RecordData::NS(target) => Some(NameServer {
+                name: target.clone(),
+                addresses: glue
+                    .iter()
+                    .filter(|g| g.name == *target)
+                    .filter_map(|g| match g.data {
+                        RecordData::A(ip) => Some(SocketAddr::new(IpAddr::V4(ip), 53)),
+                        _ => None,
+                    })
+                    .collect(),
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+fn addresses_from_answers(response: &Message, name: &str) -> Vec<SocketAddr> {
+    response
+        .answers
+        .iter()
+        .filter(|r| r.name == name)
+        .filter_map(|r| match r.data {
+            RecordData::A(ip) => Some(SocketAddr::new(IpAddr::V4(ip), 53)),
+            _ => None,
+        })
+        .collect()
+}
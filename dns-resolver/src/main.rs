@@ -1,273 +1,861 @@
-use std::collections::HashMap;
+mod access;
+mod admin;
+mod cache;
+mod config;
+mod denial;
+mod dnssec;
+mod dnssec_records;
+mod forwarder;
+mod hosts;
+mod inflight;
+mod iterative;
+mod message;
+mod policy;
+mod rtt;
+mod subnet;
+mod trace;
+mod transport;
+mod upstream;
+
+use access::Access;
+use cache::{DnsCache, Trust};
+use config::{ClientSubnetConfig, Config, Mode};
+use dnssec::{Fetch, FetchFuture, Security, Validator};
+use forwarder::Forwarder;
+use hosts::Hosts;
+use inflight::InFlight;
+use iterative::{IterativeResolver, NameServer};
+use message::{
+    is_subdomain, parse_message, read_question, write_name, Answer, Message, Question, Record,
+    RecordData, CLASS_IN, EDNS_PAYLOAD_SIZE, RCODE_NOERROR, RCODE_NXDOMAIN, RCODE_REFUSED,
+    RCODE_SERVFAIL, TYPE_CNAME, TYPE_OPT,
+};
+use policy::{Policies, Rewrite};
 use std::error::Error;
-use std::net::Ipv4Addr;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::net::UdpSocket;
-
-struct CacheEntry {
-    ip_address: Ipv4Addr,
-    valid_until: u64,
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use subnet::ClientSubnet;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::timeout;
+
+// Where the configuration is read from, at startup and when it is reloaded.
+const CONFIG_PATH: &str = "src/config.toml";
+// The largest response sent over UDP to a client that does not use EDNS.
+const MAX_PLAIN_UDP: u16 = 512;
+// How long a client's TCP connection may sit idle before it is closed (RFC 7766).
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Everything the tasks handling client requests share.
+struct Server {
+    resolver: IterativeResolver,
+    cache: Arc<DnsCache>,
+    in_flight: InFlight<(Answer, Security)>,
+    // Validates answers with DNSSEC when enabled.
+    validator: Option<Validator>,
+    // Replaced as a whole when the configuration is reloaded.
+    settings: RwLock<Arc<Settings>>,
+    // Requests being handled at once; beyond this new requests are dropped.
+    outstanding: Arc<Semaphore>,
+    // Client TCP connections open at once; beyond this new connections are closed.
+    tcp_connections: Arc<Semaphore>,
 }
 
-struct DnsCache {
-    entries: HashMap<String, CacheEntry>,
+// The parts of the configuration that can be reloaded without restarting.
+struct Settings {
+    mode: Mode,
+    forwarder: Forwarder,
+    // Zones forwarded to their own upstreams, whatever the mode.
+    forward_zones: Vec<(String, Forwarder)>,
+    // Names answered locally.
+    hosts: Hosts,
+    // Response policies checked before names are looked up.
+    policies: Policies,
+    max_cname_depth: usize,
+    // Which clients may recurse, and how fast.
+    access: Access,
+    // Whether, and how much of, the client's address is passed on to upstreams.
+    client_subnet: ClientSubnetConfig,
 }
 
-// simple DNS Cache implementation
-impl DnsCache {
-    fn new() -> Self {
-        DnsCache {
-            entries: HashMap::new(),
-        }
+impl Server {
+    fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap().clone()
     }
 
-    fn get(&self, domain: &str) -> Option<(Ipv4Addr, u64)> {
-        if let Some(entry) = self.entries.get(domain) {
-            // Check if the entry is still valid
-            let now = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
-            if entry.valid_until > now {
-                return Some((entry.ip_address, entry.valid_until));
-            }
+    // Ask for the answer to a question: from the upstreams of the forward zone the name
+    // is in, if any, or else by walking the hierarchy or through the upstream servers.
+    // Only forwarded queries pass the client's subnet on.
+    async fn query(
+        &self,
+        name: &str,
+        qtype: u16,
+        client_subnet: Option<ClientSubnet>,
+    ) -> Result<Message, Box<dyn Error + Send + Sync>> {
+        let settings = self.settings();
+        if let Some((zone, forwarder)) = settings.forward_zone(name) {
+            // Like the servers of a zone, the upstreams of a forward zone are only trusted
+            // for names within it
+            let mut response = forwarder.resolve(name, qtype, client_subnet).await?;
+            response.answers.retain(|r| is_subdomain(&r.name, zone));
+            return Ok(response);
+        }
+        match settings.mode {
+            Mode::Iterative => self.resolver.resolve(name, qtype).await,
+            Mode::Forward => settings.forwarder.resolve(name, qtype, client_subnet).await,
         }
-        None
     }
+}
 
-    fn insert(&mut self, domain: &str, ip_address: Ipv4Addr, ttl: u32) {
-        let valid_until = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-            + u64::from(ttl);
-        self.entries.insert(
-            domain.to_string(),
-            CacheEntry {
-                ip_address,
-                valid_until,
-            },
-        );
+impl Settings {
+    async fn load(config: &Config) -> Result<Settings, Box<dyn Error + Send + Sync>> {
+        let query_timeout = Duration::from_millis(config.query_timeout_ms);
+        let ca_bundle = match &config.forward.ca_bundle {
+            Some(path) => Some(
+                fs::read(path)
+                    .await
+                    .map_err(|e| format!("{}: {}", path, e))?,
+            ),
+            None => None,
+        };
+        let forwarder = |upstreams: &[String]| {
+            Forwarder::new(
+                upstreams,
+                &config.forward,
+                ca_bundle.as_deref(),
+                query_timeout,
+                config.randomize_case,
+                config.dnssec.enabled,
+            )
+        };
+        let forward_zones = config
+            .forward_zones
+            .iter()
+            .map(|zone| {
+                println!("Forwarding {} to {}", zone.zone, zone.upstreams.join(", "));
+                let name = zone.zone.trim_end_matches('.').to_ascii_lowercase();
+                Ok((name, forwarder(&zone.upstreams)?))
+            })
+            .collect::<Result<_, String>>()?;
+        let hosts = match &config.hosts_file {
+            Some(path) => Hosts::load(path).await?,
+            None => Hosts::default(),
+        };
+        Ok(Settings {
+            mode: config.mode,
+            forwarder: forwarder(&config.forward.upstreams)?,
+            forward_zones,
+            hosts,
+            policies: Policies::load(&config.policy).await?,
+            max_cname_depth: config.max_cname_depth,
+            access: Access::new(&config.access)?,
+            client_subnet: config.client_subnet.clone(),
+        })
+    }
+
+    // Whether a name is resolved through upstream servers rather than iteratively.
+    fn forwards(&self, name: &str) -> bool {
+        self.mode == Mode::Forward || self.forward_zone(name).is_some()
     }
+
+    // The most specific forward zone a name is in, with its forwarder, if any.
+    fn forward_zone(&self, name: &str) -> Option<&(String, Forwarder)> {
+        self.forward_zones
+            .iter()
+            .filter(|(zone, _)| is_subdomain(name, zone))
+            .max_by_key(|(zone, _)| zone.len())
+    }
+}
+
+async fn load_config() -> Result<Config, Box<dyn Error + Send + Sync>> {
+    let config_str = fs::read_to_string(CONFIG_PATH).await?;
+    Ok(toml::from_str(&config_str)?)
 }
 
-// Construct a DNS response given a domain name, its resolved IP address, and TTL.
+// Read the configuration again and put the reloadable parts of it into effect. Queries
+// already being resolved finish with the settings they started with.
+async fn reload_config(server: &Server) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let config = load_config().await?;
+    let settings = Settings::load(&config).await?;
+    println!("Reloaded the configuration in {:?} mode", settings.mode);
+    *server.settings.write().unwrap() = Arc::new(settings);
+    Ok(())
+}
+
+// The validator looks up the records of the chain of trust the same way as any other
+// question, either by walking the hierarchy or through the upstream servers.
+impl Fetch for Server {
+    fn fetch<'a>(&'a self, name: &'a str, qtype: u16) -> FetchFuture<'a> {
+        Box::pin(self.query(name, qtype, None))
+    }
+}
+
+// Construct a DNS response to a question with a response code, the answer records and
+// the authority records (the SOA of a negative answer). `authenticated` sets the AD bit
+// for an answer validated with DNSSEC, and the CD bit of the query is echoed back.
 fn create_dns_response(
     transaction_id: [u8; 2],
-    domain: &str,
-    ip_address: Ipv4Addr,
-    ttl: u32,
+    question: &Question,
+    rcode: u8,
+    answers: &[Record],
+    authority: &[Record],
+    authenticated: bool,
+    checking_disabled: bool,
 ) -> Vec<u8> {
     let mut response = Vec::new();
-    let ip_bytes = ip_address.octets();
-    let ttl_bytes = ttl.to_be_bytes(); // Convert TTL to byte array in big-endian format
 
-    // Transaction ID, Flags, Questions, Answer RRs, Authority RRs, Additional RRs
+    // Transaction ID
     response.extend_from_slice(&transaction_id);
-    response.extend_from_slice(&[0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00]);
 
-    // Question section
-    for label in domain.split('.') {
-        response.push(label.len() as u8);
-        response.extend_from_slice(label.as_bytes());
+    // Flags: Response, Opcode 0 (Standard Query), Authoritative Answer False, Truncated False,
+    // Recursion Desired True, Recursion Available True, Z Reserved, Answer Authenticated,
+    // Checking Disabled, and the Reply Code
+    let mut flags = 0x80 | rcode;
+    if authenticated {
+        flags |= 0x20;
+    }
+    if checking_disabled {
+        flags |= 0x10;
+    }
+    response.extend_from_slice(&[0x81, flags]);
+
+    // Questions: 1, Answer RRs, Authority RRs, Additional RRs: 0
+    response.extend_from_slice(&[0x00, 0x01]);
+    response.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+    response.extend_from_slice(&(authority.len() as u16).to_be_bytes());
+    response.extend_from_slice(&[0x00, 0x00]);
+
+    // Question section, repeating the name as the client sent it
+    write_name(&mut response, &question.name);
+    response.extend_from_slice(&question.qtype.to_be_bytes());
+    response.extend_from_slice(&question.qclass.to_be_bytes());
+
+    // Answer and authority sections
+    for record in answers.iter().chain(authority) {
+        record.write(&mut response);
     }
-    response.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x01]);
-
-    // Answer section
-    response.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01]);
-    response.extend_from_slice(&ttl_bytes);
-    response.extend_from_slice(&[0x00, 0x04]);
-    response.extend_from_slice(&ip_bytes);
 
     response
 }
 
-// Parse the domain name from the DNS query buffer.
-fn parse_domain_name(buf: &[u8], start: usize) -> Result<String, &'static str> {
-    let mut position = start;
-    let mut domain_name = String::new();
+// Find the answer to a question. When the answer is a CNAME chain leading out of the zone
+// that answered, whose records past that point were dropped as untrusted, its target is
+// looked up in turn, and so on up to the configured depth, so that the client gets the
+// whole chain. Each link is cached on its own, and the chain is only secure if every
+// link is. A chain that ends at a name without records of the type, or one that does
+// not exist, is returned along with the negative answer for that name, which is what
+// the client is told (RFC 6604).
+async fn lookup(
+    server: &Arc<Server>,
+    domain: &str,
+    qtype: u16,
+    client_subnet: Option<ClientSubnet>,
+) -> Result<(Vec<Record>, Answer, Security), String> {
+    let max_cname_depth = server.settings().max_cname_depth;
+    let (answer, mut security) = lookup_name(server, domain, qtype, client_subnet).await?;
+    let Answer::Records(mut records) = answer else {
+        return Ok((Vec::new(), answer, security));
+    };
 
-    while position < buf.len() && buf[position] != 0 {
-        let length = buf[position] as usize;
-        position += 1; // move past the length byte
+    loop {
+        let target = match records.last() {
+            Some(Record {
+                record_type: TYPE_CNAME,
+                data: RecordData::CNAME(target),
+                ..
+            }) if qtype != TYPE_CNAME => target.clone(),
+            _ => return Ok((Vec::new(), Answer::Records(records), security)),
+        };
+        if records.iter().any(|r| r.name == target) {
+            return Err(format!("CNAME loop at {} for {}", target, domain));
+        }
+        let links = records
+            .iter()
+            .filter(|r| r.record_type == TYPE_CNAME)
+            .count();
+        if links >= max_cname_depth {
+            return Err(format!(
+                "CNAME chain for {} is longer than {} links",
+                domain, max_cname_depth
+            ));
+        }
+
+        println!("Following CNAME from {} to {}", domain, target);
+        trace::step(
+            "cname",
+            domain,
+            format_args!("following the chain to {}", target),
+        );
+        let (answer, link_security) = lookup_name(server, &target, qtype, client_subnet).await?;
+        security = security.and(link_security);
+        match answer {
+            Answer::Records(more) => records.extend(more),
+            Answer::NoData(_) | Answer::NxDomain(_) => return Ok((records, answer, security)),
+        }
+    }
+}
 
-        // Check for potential out-of-bounds or invalid length
-        if length == 0 || position + length > buf.len() {
-            return Err("Invalid domain name in query");
+// Find the answer to a single name in the cache, or resolve it, unless the hosts file
+// has the name or a response policy rewrites it. Popular answers that are about to
+// expire are refreshed in the background, and when resolution fails an expired answer
+// is served stale if the cache still has one.
+// Forwarded names asked for a client subnet have answers cached apart from the rest, so
+// that an answer for one subnet, or one asked for without a subnet, is never given to a
+// client in another.
+async fn lookup_name(
+    server: &Arc<Server>,
+    domain: &str,
+    qtype: u16,
+    client_subnet: Option<ClientSubnet>,
+) -> Result<(Answer, Security), String> {
+    // Local answers are made up here, so they are never cached or secure
+    let settings = server.settings();
+    if let Some(answer) = settings.hosts.lookup(domain, qtype) {
+        println!("Hosts file: {} -> {}", domain, answer);
+        trace::step("hosts", domain, &answer);
+        return Ok((answer, Security::Insecure));
+    }
+    match settings.policies.check(domain, qtype) {
+        Some(Rewrite::Answer(answer)) => return Ok((answer, Security::Insecure)),
+        Some(Rewrite::Cname(cname)) => {
+            return Ok((Answer::Records(vec![cname]), Security::Insecure))
         }
+        None => {}
+    }
 
-        if !domain_name.is_empty() {
-            domain_name.push('.');
+    // Only forwarded names are looked up for the client's subnet
+    let client_subnet = client_subnet.filter(|_| settings.forwards(domain));
+
+    // Check if the answer is in the cache, among the answers for client subnets if the
+    // name is looked up for one
+    let hit = match client_subnet {
+        Some(subnet) => server.cache.get_scoped(domain, qtype, &subnet),
+        None => server.cache.get(domain, qtype),
+    };
+    if let Some(hit) = hit {
+        println!("Cache hit: {} -> {}", domain, hit.answer);
+        trace::step("cache_hit", domain, &hit.answer);
+        if hit.prefetch {
+            println!("Prefetching {} (type {})", domain, qtype);
+            let server = server.clone();
+            let domain = domain.to_string();
+            tokio::spawn(async move {
+                if let Err(e) = resolve(&server, &domain, qtype, client_subnet).await {
+                    eprintln!("Failed to prefetch {}: {}", domain, e);
+                }
+            });
         }
-        let label = match std::str::from_utf8(&buf[position..position + length]) {
-            Ok(s) => s,
-            Err(_) => return Err("Invalid UTF-8 label in domain name"),
+        let security = if hit.secure {
+            Security::Secure
+        } else {
+            Security::Insecure
         };
-        domain_name.push_str(label);
+        return Ok((hit.answer, security));
+    }
 
-        position += length; // move to the next label
+    match resolve(server, domain, qtype, client_subnet).await {
+        Ok(resolved) => Ok(resolved),
+        Err(e) if client_subnet.is_some() => Err(e),
+        Err(e) => match server.cache.get_stale(domain, qtype) {
+            Some(answer) => {
+                println!("Serving stale answer for {} ({}): {}", domain, e, answer);
+                trace::step("stale", domain, format_args!("{} after: {}", answer, e));
+                Ok((answer, Security::Insecure))
+            }
+            None => Err(e),
+        },
     }
+}
 
-    Ok(domain_name)
+// Resolve a question, validate the answer when DNSSEC is enabled and cache it, joining
+// an identical lookup if one is already in flight. Bogus answers are not cached. Names
+// in forward zones are private and are not validated. An answer the upstream scoped to
+// the client's subnet is only cached for clients in that subnet.
+async fn resolve(
+    server: &Server,
+    domain: &str,
+    qtype: u16,
+    client_subnet: Option<ClientSubnet>,
+) -> Result<(Answer, Security), String> {
+    server
+        .in_flight
+        .run(domain, qtype, client_subnet, async {
+            trace::step(
+                "cache_miss",
+                domain,
+                format_args!("resolving type {}", qtype),
+            );
+            // Resolve the question, either by walking the hierarchy ourselves or by
+            // asking the upstream servers
+            let response = server
+                .query(domain, qtype, client_subnet)
+                .await
+                .map_err(|e| e.to_string())?;
+            let answer = response.answer(domain, qtype);
+            println!("Cache miss: {} -> {}", domain, answer);
+            trace::step("resolved", domain, &answer);
+
+            let security = match &server.validator {
+                Some(validator) if server.settings().forward_zone(domain).is_none() => {
+                    let security = validator.validate(server, &response, domain, qtype).await;
+                    trace::step("validated", domain, format_args!("{:?}", security));
+                    security
+                }
+                _ => Security::Insecure,
+            };
+            let trust = match &security {
+                Security::Bogus(reason) => {
+                    eprintln!("Bogus answer for {} (type {}): {}", domain, qtype, reason);
+                    return Ok((answer, security));
+                }
+                Security::Secure => Trust::Secure,
+                Security::Insecure => Trust::of_answer(&response),
+            };
+            // An answer for a client subnet is cached for the subnet the upstream scoped
+            // it to, or for every client subnet if the upstream did not scope it
+            if let Some(subnet) = client_subnet {
+                let scope = subnet
+                    .scope_of(&response)
+                    .unwrap_or(ClientSubnet::new(subnet.address, 0));
+                println!("Caching {} (type {}) for {}", domain, qtype, scope);
+                let secure = security == Security::Secure;
+                let answer = server
+                    .cache
+                    .insert_scoped(domain, qtype, answer, scope, secure);
+                return Ok((answer, security));
+            }
+            // Insert the answer into the cache
+            Ok((server.cache.insert(domain, qtype, answer, trust), security))
+        })
+        .await
 }
 
-// Query the authoritative DNS server for the IP address of a domain if not found in the cache.
-async fn query_authoritative_server(domain: &str) -> Result<(Ipv4Addr, u32), Box<dyn Error>> {
-    // Connect to the authoritative DNS server
-    let server_addr = "dns-server:53";
-    let socket = UdpSocket::bind("0.0.0.0:0").await?;
-    socket.connect(server_addr).await?;
-
-    // Construct the DNS query message
-    let mut query = Vec::with_capacity(512);
-    query.extend_from_slice(&[0x00, 0x01]); // Transaction ID
-    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]); // Flags and Counts
-    for label in domain.split('.') {
-        query.push(label.len() as u8);
-        query.extend_from_slice(label.as_bytes());
-    }
-    query.push(0); // end of domain name
-    query.extend_from_slice(&[0x00, 0x01, 0x00, 0x01]); // QType and QClass
-
-    socket.send(&query).await?;
-
-    // Receive the DNS response
-    let mut response = [0u8; 512];
-    let _ = socket.recv(&mut response).await?;
-
-    // Check for NXDOMAIN response
-    // The RCODE is the last four bits of the second byte of the flags section
-    // which itself is the second and third bytes of the response
-    let rcode = response[3] & 0x0F;
-    if rcode == 3 {
-        // NXDOMAIN
-        return Err("NXDOMAIN: The domain name does not exist.".into());
-    }
+// The OPT record of a query that uses EDNS, which holds the largest UDP response the
+// client takes in its class and the DO bit in its TTL.
+fn client_opt(request: &[u8]) -> Option<Record> {
+    parse_message(request)
+        .ok()?
+        .additional
+        .into_iter()
+        .find(|r| r.record_type == TYPE_OPT)
+}
 
-    let ip_start = 14 + (domain.len() + 2) + 4 + 10; // Skip to the answer part
-    let ip_address = Ipv4Addr::new(
-        response[ip_start],
-        response[ip_start + 1],
-        response[ip_start + 2],
-        response[ip_start + 3],
-    );
+// Whether a query asks for DNSSEC: the AD bit, or an OPT record with the DO bit. Only
+// such queries get the AD bit in their response (RFC 6840 section 5.8).
+fn wants_dnssec(request: &[u8]) -> bool {
+    request[3] & 0x20 != 0 || client_opt(request).is_some_and(|opt| opt.ttl & 0x8000 != 0)
+}
 
-    // TTL is 6 bytes before the IP address in the answer
-    let ttl_bytes = &response[ip_start - 6..ip_start - 2];
-    let ttl = u32::from_be_bytes(ttl_bytes.try_into()?);
+// The client subnet option of a query, if it has one.
+fn requested_subnet(request: &[u8]) -> Option<ClientSubnet> {
+    match client_opt(request)?.data {
+        RecordData::Other(rdata) => ClientSubnet::from_opt(&rdata).map(|(subnet, _)| subnet),
+        _ => None,
+    }
+}
 
-    println!("Resolved {} to {} with TTL {}", domain, ip_address, ttl);
+// Add an OPT record to a response, for a client that used EDNS, with the UDP payload
+// size we take and the DO bit echoed back (RFC 3225). A client subnet option the client
+// sent is echoed back too, scoped to the whole of its subnet, as the answer may have
+// been meant for that subnet only (RFC 7871 section 7.2.2).
+fn append_opt(response: &mut Vec<u8>, dnssec_ok: bool, client_subnet: Option<ClientSubnet>) {
+    let mut options = Vec::new();
+    if let Some(subnet) = client_subnet {
+        subnet.write_option(subnet.prefix_len, &mut options);
+    }
+    let opt = Record {
+        name: String::new(),
+        record_type: TYPE_OPT,
+        class: EDNS_PAYLOAD_SIZE,
+        ttl: if dnssec_ok { 0x8000 } else { 0 },
+        data: RecordData::Other(options),
+    };
+    opt.write(response);
+    let additional = u16::from_be_bytes([response[10], response[11]]) + 1;
+    response[10..12].copy_from_slice(&additional.to_be_bytes());
+}
 
-    Ok((ip_address, ttl))
+// The largest response a client takes over UDP: 512 bytes, or the payload size it gave
+// with EDNS, up to the size we advertise ourselves.
+fn udp_payload_limit(request: &[u8]) -> usize {
+    let limit = client_opt(request).map_or(MAX_PLAIN_UDP, |opt| opt.class);
+    usize::from(limit.clamp(MAX_PLAIN_UDP, EDNS_PAYLOAD_SIZE))
 }
 
-// Send a DNS response with NXDOMAIN (non-existent domain) to the client.
-async fn send_nxdomain_response(
-    transaction_id: [u8; 2],
-    request: &[u8],
-    request_len: usize,
-    addr: &std::net::SocketAddr,
-    socket: &tokio::net::UdpSocket,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut response = Vec::new();
+// Cut a response that is too large for UDP down to its header and question, with the
+// TC bit set, so that the client asks again over TCP. Our responses do not compress
+// names, so the question ends after the first zero-length label.
+fn truncate_response(response: &[u8]) -> Vec<u8> {
+    let mut end = 12;
+    while response[end] != 0 {
+        end += 1 + usize::from(response[end]);
+    }
+    // The root label, then the type and class
+    end += 5;
+    let mut truncated = response[..end].to_vec();
+    truncated[2] |= 0x02;
+    truncated[6..12].fill(0);
+    truncated
+}
 
-    // Transaction ID
-    response.extend_from_slice(&transaction_id);
+// Save the cache to its snapshot file. The snapshot is written next to the file and
+// then moved over it, so a crash part way through never leaves a half-written snapshot.
+async fn save_snapshot(cache: &DnsCache, path: &str) {
+    let snapshot = cache.snapshot();
+    let temporary = format!("{}.tmp", path);
+    let saved = match fs::write(&temporary, &snapshot).await {
+        Ok(()) => fs::rename(&temporary, path).await,
+        Err(e) => Err(e),
+    };
+    match saved {
+        Ok(()) => println!(
+            "Saved cache snapshot to {} ({} bytes)",
+            path,
+            snapshot.len()
+        ),
+        Err(e) => eprintln!("Failed to save cache snapshot to {}: {}", path, e),
+    }
+}
 
-    // Flags: Response, Opcode 0 (Standard Query), Authoritative Answer False, Truncated False,
-    // Recursion Desired True, Recursion Available False, Z Reserved, Answer Authenticated False,
-    // Non-authenticated data Acceptable, Reply Code NXDOMAIN (3)
-    response.extend_from_slice(&[0x81, 0x83]); // Note: 0x83 indicates NXDOMAIN
+// Wait for the resolver to be asked to stop, by Ctrl-C or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
 
-    // Questions: 1, Answer RRs: 0, Authority RRs: 0, Additional RRs: 0
-    response.extend_from_slice(&[0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+// Take a query from a client if it is within its rate limit and there is room for it,
+// returning the slot it takes up until it is answered.
+fn admit(server: &Server, client_addr: SocketAddr) -> Option<OwnedSemaphorePermit> {
+    if !server.settings().access.within_rate(client_addr.ip()) {
+        return None;
+    }
+    match server.outstanding.clone().try_acquire_owned() {
+        Ok(permit) => Some(permit),
+        Err(_) => {
+            eprintln!(
+                "Dropping query from {}: too many queries outstanding",
+                client_addr
+            );
+            None
+        }
+    }
+}
 
-    // Repeat the question section from the request
-    response.extend_from_slice(&request[12..request_len]);
+// Answer a single client request, over UDP or TCP, returning the response to send back.
+async fn handle_query(
+    server: &Arc<Server>,
+    request: &[u8],
+    client_addr: SocketAddr,
+) -> Option<Vec<u8>> {
+    let question = match read_question(request) {
+        Ok(question) => question,
+        Err(e) => {
+            eprintln!("Failed to parse query from {}: {}", client_addr, e);
+            return None;
+        }
+    };
+    let domain = question.name.to_ascii_lowercase();
+    let qtype = question.qtype;
+    println!("Parsed domain: {} (type {})", domain, qtype);
+
+    // With Checking Disabled the client gets bogus answers too, to debug them
+    let checking_disabled = request[3] & 0x10 != 0;
+    let mut authenticated = false;
+
+    let settings = server.settings();
+    let requested_subnet = requested_subnet(request);
+    let client_subnet =
+        ClientSubnet::for_client(&settings.client_subnet, client_addr.ip(), requested_subnet);
+
+    let (rcode, answers, authority) = if !settings.access.allows(client_addr.ip()) {
+        println!(
+            "Refusing query from {}: not allowed to recurse",
+            client_addr
+        );
+        (RCODE_REFUSED, Vec::new(), Vec::new())
+    } else if question.qclass != CLASS_IN {
+        // Only the Internet class is resolved
+        (RCODE_REFUSED, Vec::new(), Vec::new())
+    } else {
+        match lookup(server, &domain, qtype, client_subnet).await {
+            Ok((_, _, Security::Bogus(_))) if !checking_disabled => {
+                (RCODE_SERVFAIL, Vec::new(), Vec::new())
+            }
+            Ok((chain, answer, security)) => {
+                authenticated = security == Security::Secure && wants_dnssec(request);
+                match answer {
+                    Answer::Records(records) => (RCODE_NOERROR, records, Vec::new()),
+                    // The name, or the end of the chain, exists but has no records of
+                    // this type
+                    Answer::NoData(soa) => (RCODE_NOERROR, chain, Vec::from_iter(soa)),
+                    Answer::NxDomain(soa) => (RCODE_NXDOMAIN, chain, Vec::from_iter(soa)),
+                }
+            }
+            Err(e) => {
+                eprintln!("Failed to resolve {}: {}", domain, e);
+                (RCODE_SERVFAIL, Vec::new(), Vec::new())
+            }
+        }
+    };
+
+    let transaction_id = [request[0], request[1]];
+    let mut response = create_dns_response(
+        transaction_id,
+        &question,
+        rcode,
+        &answers,
+        &authority,
+        authenticated,
+        checking_disabled,
+    );
+    if let Some(opt) = client_opt(request) {
+        append_opt(&mut response, opt.ttl & 0x8000 != 0, requested_subnet);
+    }
+    println!(
+        "Answering {} for domain {} with rcode {} and {} answers",
+        client_addr,
+        domain,
+        rcode,
+        answers.len()
+    );
+    Some(response)
+}
 
-    // Sending the NXDOMAIN response
-    socket.send_to(&response, addr).await?;
+// Answer a query that came over UDP, truncating the response if it is too large for
+// the client to take over UDP.
+async fn handle_udp_query(
+    server: &Arc<Server>,
+    request: &[u8],
+    client_addr: SocketAddr,
+    socket: &UdpSocket,
+) {
+    let Some(mut response) = handle_query(server, request, client_addr).await else {
+        return;
+    };
+    if response.len() > udp_payload_limit(request) {
+        println!(
+            "Response to {} is {} bytes, truncating it",
+            client_addr,
+            response.len()
+        );
+        response = truncate_response(&response);
+    }
+    if let Err(e) = socket.send_to(&response, &client_addr).await {
+        eprintln!("Failed to send response: {}", e);
+    }
+}
 
-    Ok(())
+// Answer the queries a client sends over a TCP connection, each preceded by its length
+// (RFC 1035 section 4.2.2), until the client closes the connection or leaves it idle.
+// A query that is not admitted closes the connection, rather than leaving the client
+// waiting for an answer that will not come. The connection's slot is given back when
+// it closes.
+async fn handle_tcp_connection(
+    server: Arc<Server>,
+    mut stream: TcpStream,
+    client_addr: SocketAddr,
+    _connection: OwnedSemaphorePermit,
+) {
+    loop {
+        let mut length = [0u8; 2];
+        match timeout(TCP_IDLE_TIMEOUT, stream.read_exact(&mut length)).await {
+            Ok(Ok(_)) => {}
+            // Closed by the client, or idle for too long
+            Ok(Err(_)) | Err(_) => return,
+        }
+        let mut request = vec![0u8; usize::from(u16::from_be_bytes(length))];
+        match timeout(TCP_IDLE_TIMEOUT, stream.read_exact(&mut request)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => {
+                eprintln!("Failed to read query from {}: {}", client_addr, e);
+                return;
+            }
+            Err(_) => {
+                eprintln!("Timed out reading query from {}", client_addr);
+                return;
+            }
+        }
+        println!("Received TCP query from {}", client_addr);
+
+        let Some(permit) = admit(&server, client_addr) else {
+            return;
+        };
+        let response = handle_query(&server, &request, client_addr).await;
+        drop(permit);
+        let Some(response) = response else {
+            continue;
+        };
+        let mut message = (response.len() as u16).to_be_bytes().to_vec();
+        message.extend_from_slice(&response);
+        if let Err(e) = stream.write_all(&message).await {
+            eprintln!("Failed to send response to {}: {}", client_addr, e);
+            return;
+        }
+    }
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let resolver_socket = UdpSocket::bind("0.0.0.0:5354").await?;
+async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    // read the config file
+    let config = load_config().await?;
+    println!("Resolving in {:?} mode", config.mode);
+
+    let root_hints = config
+        .root_hints
+        .iter()
+        .map(|hint| NameServer {
+            name: hint.name.clone(),
+            addresses: vec![hint.address],
+        })
+        .collect();
+    let query_timeout = Duration::from_millis(config.query_timeout_ms);
+    let validator = if config.dnssec.enabled {
+        println!("Validating answers with DNSSEC");
+        Some(Validator::new(&config.dnssec)?)
+    } else {
+        None
+    };
+    let settings = Settings::load(&config).await?;
+    let cache = Arc::new(DnsCache::new(&config.cache, config.max_cname_depth));
+
+    // Warm the cache up from the last snapshot, if there is one
+    if let Some(path) = &config.cache.snapshot_path {
+        match fs::read(path).await {
+            Ok(snapshot) => match cache.restore(&snapshot) {
+                Ok(restored) => println!("Restored {} cache entries from {}", restored, path),
+                Err(e) => eprintln!("Failed to restore cache snapshot from {}: {}", path, e),
+            },
+            Err(e) => eprintln!("No cache snapshot loaded from {}: {}", path, e),
+        }
+    }
+    let server = Arc::new(Server {
+        resolver: IterativeResolver::new(
+            root_hints,
+            cache.clone(),
+            query_timeout,
+            config.randomize_case,
+            config.dnssec.enabled,
+            config.qname_minimisation,
+        ),
+        cache,
+        in_flight: InFlight::new(),
+        validator,
+        settings: RwLock::new(Arc::new(settings)),
+        outstanding: Arc::new(Semaphore::new(config.max_outstanding_queries)),
+        tcp_connections: Arc::new(Semaphore::new(config.max_tcp_connections)),
+    });
+
+    // Sweep expired entries out of the cache, and idle clients out of the rate limits, in
+    // the background
+    let cleanup_server = server.clone();
+    let cleanup_interval = Duration::from_secs(config.cache.cleanup_interval_secs.max(1));
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(cleanup_interval);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            cleanup_server.cache.remove_expired();
+            cleanup_server.settings().access.sweep();
+        }
+    });
+
+    // Pick up changes to the policy files
+    let policy_server = server.clone();
+    let reload_interval = Duration::from_secs(config.policy.reload_interval_secs.max(1));
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(reload_interval);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            policy_server.settings().policies.reload().await;
+        }
+    });
+
+    // Save the cache periodically, so that not even a crash loses all of it
+    if let Some(path) = config.cache.snapshot_path.clone() {
+        let snapshot_server = server.clone();
+        let snapshot_interval = Duration::from_secs(config.cache.snapshot_interval_secs.max(1));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(snapshot_interval);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                save_snapshot(&snapshot_server.cache, &path).await;
+            }
+        });
+    }
+
+    if let Some(address) = config.admin_address {
+        let listener = TcpListener::bind(address).await?;
+        println!("Admin interface listening on {}", listener.local_addr()?);
+        tokio::spawn(admin::run(server.clone(), listener));
+    }
+
+    let resolver_socket = Arc::new(UdpSocket::bind("0.0.0.0:5354").await?);
+    let tcp_listener = TcpListener::bind("0.0.0.0:5354").await?;
     println!(
-        "DNS Resolver listening on {}",
+        "DNS Resolver listening on {} (UDP and TCP)",
         resolver_socket.local_addr()?
     );
 
-    let mut cache = DnsCache::new();
-
-    let mut request = [0u8; 512];
+    let mut request = vec![0u8; usize::from(EDNS_PAYLOAD_SIZE)];
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
 
     loop {
-        let (_, client_addr) = resolver_socket.recv_from(&mut request).await?;
-        println!("Received query from {}", client_addr);
-
-        match parse_domain_name(&request, 12) {
-            Ok(domain) => {
-                println!("Parsed domain: {}", domain);
-
-                // Check if the domain is in the cache
-                if let Some((ip_address, ttl)) = cache.get(&domain) {
-                    // Send the cached IP address to the client
-                    println!("Cache hit: {} -> {}", domain, ip_address);
-                    let transaction_id = [request[0], request[1]];
-                    let ttl_u32 = ttl as u32; // Convert u16 to u32
-                    let response =
-                        create_dns_response(transaction_id, &domain, ip_address, ttl_u32);
-                    if let Err(e) = resolver_socket.send_to(&response, &client_addr).await {
-                        eprintln!("Failed to send response: {}", e);
-                    } else {
-                        println!(
-                            "Sent response to {} for domain {} and ip {}",
-                            client_addr, domain, ip_address
-                        );
-                    }
-                } else {
-                    // Query the authoritative server for the IP address
-                    match query_authoritative_server(&domain).await {
-                        Ok((ip_address, ttl)) => {
-                            println!("Cache miss: {} -> {} {}", domain, ip_address, ttl);
-                            // Insert the domain and IP address into the cache
-                            cache.insert(&domain, ip_address, ttl);
-
-                            let transaction_id = [request[0], request[1]];
-                            let response =
-                                create_dns_response(transaction_id, &domain, ip_address, ttl);
-                            if let Err(e) = resolver_socket.send_to(&response, &client_addr).await {
-                                eprintln!("Failed to send response: {}", e);
-                            } else {
-                                println!(
-                                    "Sent response to {} for domain {} and ip {}",
-                                    client_addr, domain, ip_address
-                                );
-                            }
-                        }
-                        Err(_e) => {
-                            // Send a NXDOMAIN response to the client
-                            let transaction_id = [request[0], request[1]];
-                            if let Err(e) = send_nxdomain_response(
-                                transaction_id,
-                                &request,
-                                request.len(),
-                                &client_addr,
-                                &resolver_socket,
-                            )
-                            .await
-                            {
-                                eprintln!("Failed to send NXDOMAIN response: {}", e);
-                            } else {
-                                println!("Sent NXDOMAIN response to {}", client_addr);
+        let (request_len, client_addr) = tokio::select! {
+            received = resolver_socket.recv_from(&mut request) => received?,
+            accepted = tcp_listener.accept() => {
+                match accepted {
+                    Ok((stream, client_addr)) => {
+                        match server.tcp_connections.clone().try_acquire_owned() {
+                            Ok(permit) => {
+                                tokio::spawn(handle_tcp_connection(
+                                    server.clone(),
+                                    stream,
+                                    client_addr,
+                                    permit,
+                                ));
                             }
+                            Err(_) => eprintln!(
+                                "Closing TCP connection from {}: too many connections open",
+                                client_addr
+                            ),
                         }
                     }
+                    Err(e) => eprintln!("Failed to accept TCP connection: {}", e),
                 }
+                continue;
             }
-            Err(e) => eprintln!("Failed to parse domain name: {}", e),
-        }
+            _ = &mut shutdown => break,
+        };
+        println!("Received query from {}", client_addr);
+
+        // Drop queries from clients over their rate limit before they take up a slot
+        let Some(permit) = admit(&server, client_addr) else {
+            continue;
+        };
+
+        // Handle each request in its own task so a slow lookup does not hold up the rest
+        let server = server.clone();
+        let socket = resolver_socket.clone();
+        let request = request[..request_len].to_vec();
+        tokio::spawn(async move {
+            handle_udp_query(&server, &request, client_addr, &socket).await;
+            drop(permit);
+        });
+    }
+
+    println!("Shutting down");
+    if let Some(path) = &config.cache.snapshot_path {
+        save_snapshot(&server.cache, path).await;
     }
+    Ok(())
 }
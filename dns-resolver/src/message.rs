@@ -0,0 +1,461 @@
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+// Record types the resolver decodes. Anything else is carried as raw RDATA.
+pub const TYPE_A: u16 = 1;
+pub const TYPE_NS: u16 = 2;
+pub const TYPE_CNAME: u16 = 5;
+pub const TYPE_SOA: u16 = 6;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_MX: u16 = 15;
+pub const TYPE_AAAA: u16 = 28;
+
+// The EDNS pseudo-record and the DNSSEC record types, carried as raw RDATA and decoded
+// by the validator.
+pub const TYPE_OPT: u16 = 41;
+pub const TYPE_DS: u16 = 43;
+pub const TYPE_RRSIG: u16 = 46;
+pub const TYPE_NSEC: u16 = 47;
+pub const TYPE_DNSKEY: u16 = 48;
+pub const TYPE_NSEC3: u16 = 50;
+
+pub const CLASS_IN: u16 = 1;
+
+// The largest UDP response we advertise with EDNS (the DNS Flag Day 2020 default).
+pub const EDNS_PAYLOAD_SIZE: u16 = 1232;
+
+pub const RCODE_NOERROR: u8 = 0;
+pub const RCODE_SERVFAIL: u8 = 2;
+pub const RCODE_NXDOMAIN: u8 = 3;
+pub const RCODE_REFUSED: u8 = 5;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Soa {
+    pub mname: String,
+    pub rname: String,
+    pub serial: u32,
+    pub refresh: u32,
+    pub retry: u32,
+    pub expire: u32,
+    pub minimum: u32,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordData {
+    A(Ipv4Addr),
+    AAAA(Ipv6Addr),
+    NS(String),
+    CNAME(String),
+    SOA(Soa),
+    PTR(String),
+    MX { preference: u16, exchange: String },
+    // RDATA of a type we do not decode, kept as it came off the wire.
+    Other(Vec<u8>),
+}
+
+// A resource record from a response. Names are lowercase without the trailing dot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub name: String,
+    pub record_type: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub data: RecordData,
+}
+
+impl Record {
+    // Append the record in wire format, without name compression.
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        write_name(buf, &self.name);
+        buf.extend_from_slice(&self.record_type.to_be_bytes());
+        buf.extend_from_slice(&self.class.to_be_bytes());
+        buf.extend_from_slice(&self.ttl.to_be_bytes());
+
+        // Leave room for the RDATA length and fill it in once the RDATA is written
+        let length_at = buf.len();
+        buf.extend_from_slice(&[0, 0]);
+        match &self.data {
+            RecordData::A(ip_address) => buf.extend_from_slice(&ip_address.octets()),
+            RecordData::AAAA(ip_address) => buf.extend_from_slice(&ip_address.octets()),
+            RecordData::NS(name) | RecordData::CNAME(name) | RecordData::PTR(name) => {
+                write_name(buf, name)
+            }
+            RecordData::SOA(soa) => {
+                write_name(buf, &soa.mname);
+                write_name(buf, &soa.rname);
+                for field in [soa.serial, soa.refresh, soa.retry, soa.expire, soa.minimum] {
+                    buf.extend_from_slice(&field.to_be_bytes());
+                }
+            }
+            RecordData::MX {
+                preference,
+                exchange,
+            } => {
+                buf.extend_from_slice(&preference.to_be_bytes());
+                write_name(buf, exchange);
+            }
+            RecordData::Other(rdata) => buf.extend_from_slice(rdata),
+        }
+        let rdlength = (buf.len() - length_at - 2) as u16;
+        buf[length_at..length_at + 2].copy_from_slice(&rdlength.to_be_bytes());
+    }
+}
+
+// The outcome of resolving a question.
+#[derive(Debug, Clone)]
+pub enum Answer {
+    // The records answering the question: the CNAMEs leading on from the name, if any,
+    // then the RRset of the requested type.
+    Records(Vec<Record>),
+    // The name exists but has no records of the requested type. Negative answers carry
+    // the SOA from the authority section, which says how long they may be cached.
+    NoData(Option<Record>),
+    // The name does not exist.
+    NxDomain(Option<Record>),
+}
+
+impl fmt::Display for Answer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Answer::Records(records) => write!(f, "{} records", records.len()),
+            Answer::NoData(_) => write!(f, "NODATA"),
+            Answer::NxDomain(_) => write!(f, "NXDOMAIN"),
+        }
+    }
+}
+
+// A fully parsed DNS message.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub flags: u16,
+    pub answers: Vec<Record>,
+    pub authority: Vec<Record>,
+    pub additional: Vec<Record>,
+}
+
+impl Message {
+    pub fn rcode(&self) -> u8 {
+        (self.flags & 0x000F) as u8
+    }
+
+    pub fn is_authoritative(&self) -> bool {
+        self.flags & 0x0400 != 0
+    }
+
+    // The SOA of the zone enclosing the name from the authority section of a negative
+    // response, with its TTL lowered to the SOA minimum as RFC 2308 requires.
+    fn negative_soa(&self, domain: &str) -> Option<Record> {
+        let mut soa = self
+            .authority
+            .iter()
+            .find(|r| {
+                r.record_type == TYPE_SOA && r.class == CLASS_IN && is_subdomain(domain, &r.name)
+            })?
+            .clone();
+        if let RecordData::SOA(data) = &soa.data {
+            soa.ttl = soa.ttl.min(data.minimum);
+        }
+        Some(soa)
+    }
+
+    // Collect the answer to a question from the answer section, following the CNAMEs in
+    // it from the name to the RRset of the requested type. Records that are not part of
+    // that chain are ignored. The iterative resolver drops the records from outside the
+    // zone that answered, so a chain leading out of it ends in a CNAME whose target the
+    // response has no records for.
+    pub fn answer(&self, domain: &str, qtype: u16) -> Answer {
+        let mut records = Vec::new();
+        let mut name = domain.to_ascii_lowercase();
+        // Bound the chain by the number of records, so a CNAME loop cannot spin forever
+        for _ in 0..=self.answers.len() {
+            let rrset: Vec<Record> = self
+                .answers
+                .iter()
+                .filter(|r| r.name == name && r.record_type == qtype && r.class == CLASS_IN)
+                .cloned()
+                .collect();
+            if !rrset.is_empty() {
+                records.extend(rrset);
+                break;
+            }
+
+            let cname = self
+                .answers
+                .iter()
+                .find(|r| r.name == name && r.record_type == TYPE_CNAME && r.class == CLASS_IN);
+            match cname {
+                Some(record) if !records.contains(record) => {
+                    if let RecordData::CNAME(target) = &record.data {
+                        name = target.clone();
+                    }
+                    records.push(record.clone());
+                }
+                _ => break,
+            }
+        }
+
+        // An NXDOMAIN after a CNAME is about the end of the chain, not the name asked for,
+        // so the chain is the answer for the name, and the end of the chain is looked up
+        // on its own to tell the client it does not exist
+        if !records.is_empty() {
+            Answer::Records(records)
+        } else if self.rcode() == RCODE_NXDOMAIN {
+            Answer::NxDomain(self.negative_soa(domain))
+        } else {
+            Answer::NoData(self.negative_soa(domain))
+        }
+    }
+}
+
+// Parse a complete DNS message, following compression pointers in names.
+pub fn parse_message(buf: &[u8]) -> Result<Message, &'static str> {
+    if buf.len() < 12 {
+        return Err("Message is shorter than a DNS header");
+    }
+    let count = |offset: usize| u16::from_be_bytes([buf[offset], buf[offset + 1]]);
+
+    // Skip over the question section
+    let mut position = 12;
+    for _ in 0..count(4) {
+        let (_, next) = read_name(buf, position)?;
+        if next + 4 > buf.len() {
+            return Err("Question is truncated");
+        }
+        position = next + 4;
+    }
+
+    let mut sections = [Vec::new(), Vec::new(), Vec::new()];
+    for (section, offset) in sections.iter_mut().zip([6, 8, 10]) {
+        for _ in 0..count(offset) {
+            let (record, next) = read_record(buf, position)?;
+            section.push(record);
+            position = next;
+        }
+    }
+    let [answers, authority, additional] = sections;
+
+    Ok(Message {
+        flags: count(2),
+        answers,
+        authority,
+        additional,
+    })
+}
+
+// Decode a possibly compressed domain name starting at `start`, returning the lowercased
+// name and the position just after it in the original buffer.
+pub fn read_name(buf: &[u8], start: usize) -> Result<(String, usize), &'static str> {
+    let (name, next) = read_name_preserving_case(buf, start)?;
+    Ok((name.to_ascii_lowercase(), next))
+}
+
+// The question of a message, with the name exactly as it was sent.
+pub struct Question {
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+// Read the single question of a message without lowercasing the name, so that a response
+// can be checked against the mixed-case name that was sent in the query.
+pub fn read_question(buf: &[u8]) -> Result<Question, &'static str> {
+    if buf.len() < 12 {
+        return Err("Message is shorter than a DNS header");
+    }
+    if u16::from_be_bytes([buf[4], buf[5]]) != 1 {
+        return Err("Message does not have exactly one question");
+    }
+    let (name, next) = read_name_preserving_case(buf, 12)?;
+    let fixed = buf.get(next..next + 4).ok_or("Question is truncated")?;
+    Ok(Question {
+        name,
+        qtype: u16::from_be_bytes([fixed[0], fixed[1]]),
+        qclass: u16::from_be_bytes([fixed[2], fixed[3]]),
+    })
+}
+
+fn read_name_preserving_case(buf: &[u8], start: usize) -> Result<(String, usize), &'static str> {
+    let mut position = start;
+    let mut domain_name = String::new();
+    // Where parsing continues once the first compression pointer has been followed.
+    let mut end = None;
+    let mut jumps = 0;
+
+    loop {
+        let length = *buf
+            .get(position)
+            .ok_or("Domain name runs past the end of the message")?;
+        match length {
+            0 => {
+                position += 1;
+                break;
+            }
+            length if length & 0xC0 == 0xC0 => {
+                let low = *buf
+                    .get(position + 1)
+                    .ok_or("Truncated compression pointer")?;
+                jumps += 1;
+                if jumps > 16 {
+                    return Err("Too many compression pointers in domain name");
+                }
+                end.get_or_insert(position + 2);
+                position = (usize::from(length & 0x3F) << 8) | usize::from(low);
+            }
+            length if length > 63 => return Err("Invalid label length in domain name"),
+            length => {
+                let length = usize::from(length);
+                position += 1; // move past the length byte
+                let label = buf
+                    .get(position..position + length)
+                    .ok_or("Invalid domain name in message")?;
+                let label =
+                    std::str::from_utf8(label).map_err(|_| "Invalid UTF-8 label in domain name")?;
+                if !domain_name.is_empty() {
+                    domain_name.push('.');
+                }
+                domain_name.push_str(label);
+                position += length; // move to the next label
+            }
+        }
+    }
+
+    Ok((domain_name, end.unwrap_or(position)))
+}
+
+pub fn read_record(buf: &[u8], start: usize) -> Result<(Record, usize), &'static str> {
+    let (name, position) = read_name(buf, start)?;
+    let fixed = buf
+        .get(position..position + 10)
+        .ok_or("Resource record is truncated")?;
+    let record_type = u16::from_be_bytes([fixed[0], fixed[1]]);
+    let rdlength = usize::from(u16::from_be_bytes([fixed[8], fixed[9]]));
+    let rdata_start = position + 10;
+    let rdata_end = rdata_start + rdlength;
+    let rdata = buf
+        .get(rdata_start..rdata_end)
+        .ok_or("Resource record data is truncated")?;
+
+    // Names inside RDATA may point anywhere in the message, so decode from the full buffer.
+    let name_in_rdata = |at: usize| -> Result<(String, usize), &'static str> {
+        let (name, next) = read_name(buf, at)?;
+        if next > rdata_end {
+            return Err("Domain name runs past the end of the RDATA");
+        }
+        Ok((name, next))
+    };
+
+    let data = match record_type {
+        TYPE_A => RecordData::A(Ipv4Addr::from(
+            <[u8; 4]>::try_from(rdata).map_err(|_| "Invalid A record")?,
+        )),
+        TYPE_AAAA => RecordData::AAAA(Ipv6Addr::from(
+            <[u8; 16]>::try_from(rdata).map_err(|_| "Invalid AAAA record")?,
+        )),
+        TYPE_NS => RecordData::NS(name_in_rdata(rdata_start)?.0),
+        TYPE_CNAME => RecordData::CNAME(name_in_rdata(rdata_start)?.0),
+        TYPE_PTR => RecordData::PTR(name_in_rdata(rdata_start)?.0),
+        TYPE_MX => {
+            if rdata.len() < 3 {
+                return Err("Invalid MX record");
+            }
+            RecordData::MX {
+                preference: u16::from_be_bytes([rdata[0], rdata[1]]),
+                exchange: name_in_rdata(rdata_start + 2)?.0,
+            }
+        }
+        TYPE_SOA => {
+            let (mname, next) = name_in_rdata(rdata_start)?;
+            let (rname, next) = name_in_rdata(next)?;
+            let fields = &buf[next..rdata_end];
+            if fields.len() != 20 {
+                return Err("Invalid SOA record");
+            }
+            let field = |i: usize| u32::from_be_bytes(fields[i * 4..i * 4 + 4].try_into().unwrap());
+            RecordData::SOA(Soa {
+                mname,
+                rname,
+                serial: field(0),
+                refresh: field(1),
+                retry: field(2),
+                expire: field(3),
+                minimum: field(4),
+            })
+        }
+        _ => RecordData::Other(rdata.to_vec()),
+    };
+
+    let record = Record {
+        name,
+        record_type,
+        class: u16::from_be_bytes([fixed[2], fixed[3]]),
+        ttl: u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]),
+        data,
+    };
+    Ok((record, rdata_end))
+}
+
+// Construct a DNS query message for a single question. With `dnssec_ok` the query carries
+// an EDNS OPT record with the DO bit set, asking for the DNSSEC records of the answer.
+// Any EDNS options, already encoded, go in the OPT record too.
+pub fn build_query(
+    transaction_id: u16,
+    domain: &str,
+    qtype: u16,
+    recursion_desired: bool,
+    dnssec_ok: bool,
+    options: &[u8],
+) -> Vec<u8> {
+    let edns = dnssec_ok || !options.is_empty();
+    let mut query = Vec::with_capacity(512);
+    query.extend_from_slice(&transaction_id.to_be_bytes());
+    let flags: u16 = if recursion_desired { 0x0100 } else { 0x0000 };
+    query.extend_from_slice(&flags.to_be_bytes());
+    query.extend_from_slice(&[0x00, 0x01, 0x00, 0x00, 0x00, 0x00]); // Counts
+    query.extend_from_slice(&u16::from(edns).to_be_bytes());
+    write_name(&mut query, domain);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    if edns {
+        // OPT: root owner, the UDP payload size we accept in the class, the DO bit in the
+        // TTL, then the options
+        let ttl: u32 = if dnssec_ok { 0x0000_8000 } else { 0 };
+        query.push(0);
+        query.extend_from_slice(&TYPE_OPT.to_be_bytes());
+        query.extend_from_slice(&EDNS_PAYLOAD_SIZE.to_be_bytes());
+        query.extend_from_slice(&ttl.to_be_bytes());
+        query.extend_from_slice(&(options.len() as u16).to_be_bytes());
+        query.extend_from_slice(options);
+    }
+    query
+}
+
+// Split records into RRsets, the records sharing a name, type and class.
+pub fn group_rrsets(records: &[Record]) -> Vec<Vec<Record>> {
+    let mut rrsets: Vec<Vec<Record>> = Vec::new();
+    for record in records {
+        let same_rrset = |r: &Record| {
+            r.name == record.name && r.record_type == record.record_type && r.class == record.class
+        };
+        match rrsets.iter_mut().find(|rrset| same_rrset(&rrset[0])) {
+            Some(rrset) => rrset.push(record.clone()),
+            None => rrsets.push(vec![record.clone()]),
+        }
+    }
+    rrsets
+}
+
+// Encode a domain name as a sequence of length-prefixed labels.
+pub fn write_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0); // end of domain name
+}
+
+// Whether a name is equal to or below a zone. The root zone encloses everything.
+pub fn is_subdomain(name: &str, zone: &str) -> bool {
+    zone.is_empty() || name == zone || name.ends_with(&format!(".{}", zone))
+}
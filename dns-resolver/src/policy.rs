@@ -0,0 +1,412 @@
+use crate::config::PolicyConfig;
+use crate::message::{
+    Answer, Record, RecordData, CLASS_IN, TYPE_A, TYPE_AAAA, TYPE_CNAME, TYPE_MX, TYPE_PTR,
+};
+use crate::trace;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::SystemTime;
+use tokio::fs;
+
+// The TTL of local data written without one and with no $TTL before it.
+const DEFAULT_TTL: u32 = 300;
+
+// What a policy does with a name that triggers it, written as in RPZ zones: a CNAME to
+// the root for NXDOMAIN, to "*." for NODATA, to "rpz-passthru." to leave the name
+// alone, or any other records to answer with instead.
+#[derive(Debug, Clone)]
+enum Action {
+    NxDomain,
+    NoData,
+    Passthru,
+    LocalData(Vec<Record>),
+}
+
+// The triggers of a policy file. A wildcard trigger "*.example.com" is kept under
+// "example.com" and matches the names below it, but not example.com itself.
+#[derive(Default)]
+struct Rules {
+    exact: HashMap<String, Action>,
+    wildcards: HashMap<String, Action>,
+}
+
+impl Rules {
+    // The action for a name: its own trigger if it has one, else the wildcard of its
+    // closest ancestor that has one.
+    fn find(&self, name: &str) -> Option<&Action> {
+        if let Some(action) = self.exact.get(name) {
+            return Some(action);
+        }
+        let mut parent = name;
+        while !parent.is_empty() {
+            parent = parent.split_once('.').map_or("", |(_, rest)| rest);
+            if let Some(action) = self.wildcards.get(parent) {
+                return Some(action);
+            }
+        }
+        None
+    }
+
+    fn len(&self) -> usize {
+        self.exact.len() + self.wildcards.len()
+    }
+}
+
+// A policy file and the triggers last loaded from it.
+struct PolicyZone {
+    name: String,
+    path: String,
+    rules: RwLock<Rules>,
+    // When the file was last modified as of the last load, to tell when to reload it.
+    modified: Mutex<Option<SystemTime>>,
+    hits: AtomicU64,
+}
+
+// How a policy rewrites the answer to a question.
+pub enum Rewrite {
+    // Answer with this instead of resolving the name.
+    Answer(Answer),
+    // Answer with a CNAME, whose target is then looked up as usual.
+    Cname(Record),
+}
+
+impl fmt::Display for Rewrite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rewrite::Answer(answer) => write!(f, "{}", answer),
+            Rewrite::Cname(Record {
+                data: RecordData::CNAME(target),
+                ..
+            }) => write!(f, "CNAME to {}", target),
+            Rewrite::Cname(_) => write!(f, "CNAME"),
+        }
+    }
+}
+
+// Response policy zones (RPZ): files of names to block or rewrite, checked in order
+// before a name is looked up. The first policy with a trigger for the name decides what
+// happens to it, so a passthru in an earlier policy exempts a name from later ones.
+pub struct Policies {
+    zones: Vec<PolicyZone>,
+}
+
+impl Policies {
+    // Load every policy file. A file that cannot be loaded at startup is an error, so a
+    // typo does not leave names silently unblocked.
+    pub async fn load(config: &PolicyConfig) -> Result<Policies, String> {
+        let policies = Policies {
+            zones: config
+                .zones
+                .iter()
+                .map(|zone| PolicyZone {
+                    name: zone.name.clone(),
+                    path: zone.path.clone(),
+                    rules: RwLock::new(Rules::default()),
+                    modified: Mutex::new(None),
+                    hits: AtomicU64::new(0),
+                })
+                .collect(),
+        };
+        for zone in &policies.zones {
+            load_zone(zone).await?;
+        }
+        Ok(policies)
+    }
+
+    // Reload the policy files that changed since they were last loaded. A file that
+    // fails to load keeps the triggers it had before.
+    pub async fn reload(&self) {
+        for zone in &self.zones {
+            let modified = fs::metadata(&zone.path)
+                .await
+                .and_then(|metadata| metadata.modified())
+                .ok();
+            if modified == *zone.modified.lock().unwrap() {
+                continue;
+            }
+            if let Err(e) = load_zone(zone).await {
+                eprintln!("Failed to reload policy {}: {}", zone.name, e);
+                // Try again next time rather than waiting for the file to change again
+                *zone.modified.lock().unwrap() = None;
+            }
+        }
+    }
+
+    // How often each policy has matched a name since the configuration was last loaded.
+    pub fn hits(&self) -> Vec<(String, u64)> {
+        self.zones
+            .iter()
+            .map(|zone| (zone.name.clone(), zone.hits.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    // How the policies rewrite the answer to a question, or None if the name should be
+    // looked up as usual.
+    pub fn check(&self, name: &str, qtype: u16) -> Option<Rewrite> {
+        for zone in &self.zones {
+            let rules = zone.rules.read().unwrap();
+            let Some(action) = rules.find(name) else {
+                continue;
+            };
+            let hits = zone.hits.fetch_add(1, Ordering::Relaxed) + 1;
+            let rewrite = match action {
+                Action::NxDomain => Some(Rewrite::Answer(Answer::NxDomain(None))),
+                Action::NoData => Some(Rewrite::Answer(Answer::NoData(None))),
+                Action::Passthru => None,
+                Action::LocalData(records) => Some(local_data(records, name, qtype)),
+            };
+            match &rewrite {
+                Some(rewrite) => {
+                    println!(
+                        "Policy {} matched {} (type {}): {} ({} hits)",
+                        zone.name, name, qtype, rewrite, hits
+                    );
+                    trace::step(
+                        "policy",
+                        name,
+                        format_args!("{} rewrote the answer: {}", zone.name, rewrite),
+                    );
+                }
+                None => {
+                    println!(
+                        "Policy {} passed {} (type {}) through ({} hits)",
+                        zone.name, name, qtype, hits
+                    );
+                    trace::step("policy", name, format_args!("{} passed through", zone.name));
+                }
+            }
+            return rewrite;
+        }
+        None
+    }
+}
+
+// Answer a question from the local data of a trigger, with the records renamed to the
+// name asked for so that wildcard triggers answer for each name below them.
+fn local_data(records: &[Record], name: &str, qtype: u16) -> Rewrite {
+    let renamed = |record: &Record| Record {
+        name: name.to_string(),
+        ..record.clone()
+    };
+    let answers: Vec<Record> = records
+        .iter()
+        .filter(|r| r.record_type == qtype)
+        .map(renamed)
+        .collect();
+    if !answers.is_empty() {
+        return Rewrite::Answer(Answer::Records(answers));
+    }
+    match records.iter().find(|r| r.record_type == TYPE_CNAME) {
+        Some(cname) => Rewrite::Cname(renamed(cname)),
+        None => Rewrite::Answer(Answer::NoData(None)),
+    }
+}
+
+async fn load_zone(zone: &PolicyZone) -> Result<(), String> {
+    let modified = fs::metadata(&zone.path)
+        .await
+        .and_then(|metadata| metadata.modified())
+        .ok();
+    let contents = fs::read_to_string(&zone.path)
+        .await
+        .map_err(|e| format!("{}: {}", zone.path, e))?;
+    let rules = parse_rules(&contents).map_err(|e| format!("{}: {}", zone.path, e))?;
+    println!(
+        "Loaded policy {} from {} ({} triggers)",
+        zone.name,
+        zone.path,
+        rules.len()
+    );
+    *zone.rules.write().unwrap() = rules;
+    *zone.modified.lock().unwrap() = modified;
+    Ok(())
+}
+
+// Parse a policy file in the zone file format of RPZ. Owner names are the trigger names,
+// written relative to the policy zone; absolute names have the $ORIGIN stripped off.
+// The SOA and NS records of the zone itself are skipped.
+fn parse_rules(contents: &str) -> Result<Rules, String> {
+    let mut rules = Rules::default();
+    let mut origin: Option<String> = None;
+    let mut default_ttl = DEFAULT_TTL;
+    let mut owner: Option<String> = None;
+
+    for (number, line) in logical_lines(contents) {
+        let error = |message: String| format!("line {}: {}", number, message);
+        let mut tokens: Vec<&str> = line.split_whitespace().collect();
+        if tokens.is_empty() {
+            continue;
+        }
+
+        match tokens[0] {
+            "$ORIGIN" => {
+                let name = tokens
+                    .get(1)
+                    .ok_or_else(|| error("missing origin".into()))?;
+                origin = Some(normalise_name(name));
+                continue;
+            }
+            "$TTL" => {
+                default_ttl = tokens
+                    .get(1)
+                    .and_then(|ttl| ttl.parse().ok())
+                    .ok_or_else(|| error("invalid $TTL".into()))?;
+                continue;
+            }
+            _ => {}
+        }
+
+        // A line starting with whitespace continues with the previous owner
+        if !line.starts_with(char::is_whitespace) {
+            owner = Some(trigger_name(tokens.remove(0), origin.as_deref()));
+        }
+        let name = owner
+            .clone()
+            .ok_or_else(|| error("record without an owner".into()))?;
+
+        let mut ttl = default_ttl;
+        let mut tokens = tokens.into_iter().peekable();
+        if let Some(value) = tokens.peek().and_then(|t| t.parse().ok()) {
+            ttl = value;
+            tokens.next();
+        }
+        if tokens.peek().is_some_and(|t| t.eq_ignore_ascii_case("IN")) {
+            tokens.next();
+        }
+        let record_type = tokens
+            .next()
+            .ok_or_else(|| error("missing record type".into()))?
+            .to_ascii_uppercase();
+        let rdata: Vec<&str> = tokens.collect();
+
+        // The zone's own records, not triggers
+        if name.is_empty() || record_type == "SOA" || record_type == "NS" {
+            continue;
+        }
+        let action = parse_action(&name, ttl, &record_type, &rdata).map_err(error)?;
+        add_action(&mut rules, &name, action).map_err(error)?;
+    }
+
+    Ok(rules)
+}
+
+fn parse_action(name: &str, ttl: u32, record_type: &str, rdata: &[&str]) -> Result<Action, String> {
+    let field = |index: usize| {
+        rdata
+            .get(index)
+            .copied()
+            .ok_or_else(|| format!("missing data for {} record", record_type))
+    };
+    let absolute = |target: &str| match target.strip_suffix('.') {
+        Some(target) => Ok(target.to_ascii_lowercase()),
+        None => Err(format!("{} must be an absolute name", target)),
+    };
+
+    let (record_type, data) = match record_type {
+        "CNAME" => match field(0)? {
+            "." => return Ok(Action::NxDomain),
+            "*." => return Ok(Action::NoData),
+            target if target.eq_ignore_ascii_case("rpz-passthru.") => return Ok(Action::Passthru),
+            target => (TYPE_CNAME, RecordData::CNAME(absolute(target)?)),
+        },
+        "A" => {
+            let address = field(0)?.parse().map_err(|_| "invalid IPv4 address")?;
+            (TYPE_A, RecordData::A(address))
+        }
+        "AAAA" => {
+            let address = field(0)?.parse().map_err(|_| "invalid IPv6 address")?;
+            (TYPE_AAAA, RecordData::AAAA(address))
+        }
+        "PTR" => (TYPE_PTR, RecordData::PTR(absolute(field(0)?)?)),
+        "MX" => {
+            let preference = field(0)?.parse().map_err(|_| "invalid MX preference")?;
+            let exchange = absolute(field(1)?)?;
+            (
+                TYPE_MX,
+                RecordData::MX {
+                    preference,
+                    exchange,
+                },
+            )
+        }
+        other => return Err(format!("unsupported record type {}", other)),
+    };
+
+    Ok(Action::LocalData(vec![Record {
+        name: name.to_string(),
+        record_type,
+        class: CLASS_IN,
+        ttl,
+        data,
+    }]))
+}
+
+// Add an action to a trigger. Local data records for the same trigger accumulate; any
+// other action must be the only one the trigger has.
+fn add_action(rules: &mut Rules, name: &str, action: Action) -> Result<(), String> {
+    let (map, key) = match name.strip_prefix('*') {
+        Some(parent) => (&mut rules.wildcards, parent.trim_start_matches('.')),
+        None => (&mut rules.exact, name),
+    };
+    match (map.get_mut(key), action) {
+        (None, action) => {
+            map.insert(key.to_string(), action);
+            Ok(())
+        }
+        (Some(Action::LocalData(records)), Action::LocalData(more)) => {
+            if more.iter().any(|r| r.record_type == TYPE_CNAME)
+                || records.iter().any(|r| r.record_type == TYPE_CNAME)
+            {
+                return Err(format!("CNAME for {} alongside other data", name));
+            }
+            records.extend(more);
+            Ok(())
+        }
+        _ => Err(format!("conflicting actions for {}", name)),
+    }
+}
+
+// The trigger an owner name stands for. "@" and the origin itself are the zone apex,
+// returned as the empty name.
+fn trigger_name(owner: &str, origin: Option<&str>) -> String {
+    if owner == "@" {
+        return String::new();
+    }
+    let Some(absolute) = owner.strip_suffix('.') else {
+        return owner.to_ascii_lowercase();
+    };
+    let absolute = absolute.to_ascii_lowercase();
+    match origin {
+        Some(origin) if absolute == origin => String::new(),
+        Some(origin) => absolute
+            .strip_suffix(&format!(".{}", origin))
+            .map_or(absolute.clone(), str::to_string),
+        None => absolute,
+    }
+}
+
+fn normalise_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+// The lines of a zone file with comments removed and parenthesised records joined into
+// one line, each with the number of the line it starts on.
+fn logical_lines(contents: &str) -> Vec<(usize, String)> {
+    let mut lines = Vec::new();
+    let mut pending: Option<(usize, String)> = None;
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.split(';').next().unwrap_or("");
+        let (number, mut joined) = pending.take().unwrap_or((index + 1, String::new()));
+        joined.push_str(line);
+        joined.push(' ');
+        if joined.matches('(').count() > joined.matches(')').count() {
+            pending = Some((number, joined));
+        } else {
+            lines.push((number, joined.replace(['(', ')'], " ")));
+        }
+    }
+    lines.extend(pending);
+    lines
+}
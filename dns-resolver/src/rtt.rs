@@ -0,0 +1,83 @@
+use rand::Rng;
+use std::time::{Duration, Instant};
+
+// The round-trip time assumed for a server that has not answered yet. As in Unbound,
+// this is high enough that a server known to be fast is preferred over an unknown one,
+// but low enough that an unknown one is preferred over a server known to be slow.
+const UNKNOWN_RTT: Duration = Duration::from_millis(376);
+// The highest estimate a server can get, however often it fails.
+const MAX_RTT: Duration = Duration::from_secs(60);
+// What is learnt about a server is forgotten after this long without news of it, since
+// the network may have changed in the meantime.
+const RTT_EXPIRY: Duration = Duration::from_secs(900);
+// How often a server other than the fastest is tried first, to keep learning how fast
+// the others are.
+const EXPLORE_PERCENT: u32 = 5;
+
+// What we know of how quickly a server answers: its smoothed round-trip time, and how
+// many queries in a row it has failed to answer.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rtt {
+    smoothed: Option<Duration>,
+    failures: u32,
+    updated: Option<Instant>,
+}
+
+impl Rtt {
+    // Fold the round-trip time of an answer into the average the way TCP smooths its
+    // estimate (RFC 6298), so that one slow answer does not dominate. An answer also
+    // clears any penalty for failures.
+    pub fn answered(&mut self, rtt: Duration) {
+        self.smoothed = Some(match self.smoothed() {
+            Some(smoothed) => smoothed * 7 / 8 + rtt / 8,
+            None => rtt,
+        });
+        self.failures = 0;
+        self.updated = Some(Instant::now());
+    }
+
+    // Note a query the server did not answer usably. Each failure in a row doubles its
+    // estimate, so a server that keeps timing out drops to the back.
+    pub fn failed(&mut self) {
+        self.smoothed = self.smoothed();
+        self.failures = if self.is_fresh() {
+            self.failures + 1
+        } else {
+            1
+        };
+        self.updated = Some(Instant::now());
+    }
+
+    // The smoothed round-trip time of the server's answers, if it has answered lately.
+    pub fn smoothed(&self) -> Option<Duration> {
+        self.smoothed.filter(|_| self.is_fresh())
+    }
+
+    // How long the server is expected to take to answer, with any penalty for failures.
+    pub fn estimate(&self) -> Duration {
+        let failures = if self.is_fresh() { self.failures } else { 0 };
+        self.smoothed()
+            .unwrap_or(UNKNOWN_RTT)
+            .saturating_mul(1 << failures.min(16))
+            .min(MAX_RTT)
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        self.updated
+            .is_some_and(|updated| updated.elapsed() < RTT_EXPIRY)
+    }
+}
+
+// Order servers by how fast they are expected to answer, keeping the given order
+// between servers that are equally fast. Now and then a random one of the slower
+// servers goes first instead, so that a server which has got faster is noticed.
+pub fn fastest_first<T>(mut servers: Vec<(T, Rtt)>) -> Vec<T> {
+    servers.sort_by_key(|(_, rtt)| rtt.estimate());
+    let mut rng = rand::thread_rng();
+    if servers.len() > 1 && rng.gen_ratio(EXPLORE_PERCENT, 100) {
+        let explored = rng.gen_range(1..servers.len());
+        let server = servers.remove(explored);
+        servers.insert(0, server);
+    }
+    servers.into_iter().map(|(server, _)| server).collect()
+}
@@ -0,0 +1,173 @@
+use crate::config::ClientSubnetConfig;
+use crate::message::{Message, RecordData, TYPE_OPT};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// The EDNS option code of a client subnet (RFC 7871).
+const OPTION_CLIENT_SUBNET: u16 = 8;
+// The address families the option uses, from the IANA address family numbers.
+const FAMILY_IPV4: u16 = 1;
+const FAMILY_IPV6: u16 = 2;
+
+// The subnet of the client a query is asked on behalf of: its address cut down to the
+// first `prefix_len` bits, the rest being zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ClientSubnet {
+    pub address: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl ClientSubnet {
+    pub fn new(address: IpAddr, prefix_len: u8) -> ClientSubnet {
+        let address = address.to_canonical();
+        let prefix_len = prefix_len.min(if address.is_ipv4() { 32 } else { 128 });
+        ClientSubnet {
+            address: mask(address, prefix_len),
+            prefix_len,
+        }
+    }
+
+    // The subnet to pass on for a client: the one it asked for itself, if any, but no
+    // longer than configured, or else its own address cut down to the configured length.
+    // None when client subnets are off, or the client asked for its address to be kept
+    // private with a prefix of 0.
+    pub fn for_client(
+        config: &ClientSubnetConfig,
+        client: IpAddr,
+        requested: Option<ClientSubnet>,
+    ) -> Option<ClientSubnet> {
+        if !config.enabled {
+            return None;
+        }
+        let (address, prefix_len) = match requested {
+            Some(requested) if requested.prefix_len == 0 => return None,
+            Some(requested) => (requested.address, requested.prefix_len),
+            None => (client.to_canonical(), u8::MAX),
+        };
+        let max_len = if address.is_ipv4() {
+            config.ipv4_prefix_len
+        } else {
+            config.ipv6_prefix_len
+        };
+        Some(ClientSubnet::new(address, prefix_len.min(max_len)))
+    }
+
+    // Whether an address, or every address of a longer subnet, is in this subnet.
+    pub fn contains(&self, other: &ClientSubnet) -> bool {
+        other.prefix_len >= self.prefix_len
+            && other.address.is_ipv4() == self.address.is_ipv4()
+            && mask(other.address, self.prefix_len) == self.address
+    }
+
+    // The client subnet option in EDNS, with the scope the answer holds for: the family,
+    // the source and scope prefix lengths, then only as many bytes of the address as
+    // the source prefix covers.
+    pub fn write_option(&self, scope_prefix_len: u8, buf: &mut Vec<u8>) {
+        let (family, octets) = match self.address {
+            IpAddr::V4(address) => (FAMILY_IPV4, address.octets().to_vec()),
+            IpAddr::V6(address) => (FAMILY_IPV6, address.octets().to_vec()),
+        };
+        let address = &octets[..usize::from(self.prefix_len).div_ceil(8)];
+        buf.extend_from_slice(&OPTION_CLIENT_SUBNET.to_be_bytes());
+        buf.extend_from_slice(&(4 + address.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&family.to_be_bytes());
+        buf.push(self.prefix_len);
+        buf.push(scope_prefix_len);
+        buf.extend_from_slice(address);
+    }
+
+    // The client subnet option among the options of an OPT record, with the scope
+    // prefix length it gives. None if there is none, or it is malformed.
+    pub fn from_opt(rdata: &[u8]) -> Option<(ClientSubnet, u8)> {
+        let mut position = 0;
+        while let Some(header) = rdata.get(position..position + 4) {
+            let code = u16::from_be_bytes([header[0], header[1]]);
+            let length = usize::from(u16::from_be_bytes([header[2], header[3]]));
+            let data = rdata.get(position + 4..position + 4 + length)?;
+            if code == OPTION_CLIENT_SUBNET {
+                return parse_option(data);
+            }
+            position += 4 + length;
+        }
+        None
+    }
+
+    // The subnet an upstream's answer to a query with this subnet holds for, if it says
+    // the answer depends on the client's subnet at all. The scope is capped at the
+    // length we sent, as we know no more of the client's address than that.
+    pub fn scope_of(&self, response: &Message) -> Option<ClientSubnet> {
+        let (subnet, scope_prefix_len) = response_option(response)?;
+        if subnet != *self || scope_prefix_len == 0 {
+            return None;
+        }
+        Some(ClientSubnet::new(
+            self.address,
+            scope_prefix_len.min(self.prefix_len),
+        ))
+    }
+}
+
+impl fmt::Display for ClientSubnet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_len)
+    }
+}
+
+// The client subnet option of a response, if it has one.
+fn response_option(response: &Message) -> Option<(ClientSubnet, u8)> {
+    let opt = response
+        .additional
+        .iter()
+        .find(|r| r.record_type == TYPE_OPT)?;
+    match &opt.data {
+        RecordData::Other(rdata) => ClientSubnet::from_opt(rdata),
+        _ => None,
+    }
+}
+
+fn parse_option(data: &[u8]) -> Option<(ClientSubnet, u8)> {
+    let family = u16::from_be_bytes([*data.first()?, *data.get(1)?]);
+    let source_prefix_len = *data.get(2)?;
+    let scope_prefix_len = *data.get(3)?;
+    let address = &data[4..];
+    if address.len() != usize::from(source_prefix_len).div_ceil(8) {
+        return None;
+    }
+    let address = match family {
+        FAMILY_IPV4 if source_prefix_len <= 32 => {
+            let mut octets = [0u8; 4];
+            octets[..address.len()].copy_from_slice(address);
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        FAMILY_IPV6 if source_prefix_len <= 128 => {
+            let mut octets = [0u8; 16];
+            octets[..address.len()].copy_from_slice(address);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    Some((
+        ClientSubnet::new(address, source_prefix_len),
+        scope_prefix_len,
+    ))
+}
+
+// An address with every bit past the first `prefix_len` cleared.
+fn mask(address: IpAddr, prefix_len: u8) -> IpAddr {
+    match address {
+        IpAddr::V4(address) => {
+            let bits = u32::from(address);
+            let mask = u32::MAX
+                .checked_shl(32 - u32::from(prefix_len))
+                .unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(bits & mask))
+        }
+        IpAddr::V6(address) => {
+            let bits = u128::from(address);
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(prefix_len))
+                .unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(bits & mask))
+        }
+    }
+}
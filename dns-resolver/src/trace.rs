@@ -0,0 +1,117 @@
+use crate::message::Message;
+use serde::Serialize;
+use std::fmt;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// The steps taken to answer a question, recorded while it is traced. The trace is kept
+// per task, so every part of the lookup can add to it without it being passed around,
+// and lookups of other tasks, such as prefetches, are left out of it. Recording a step
+// costs nothing when no trace is being taken.
+struct Trace {
+    started: Instant,
+    steps: Mutex<Vec<Step>>,
+}
+
+tokio::task_local! {
+    static TRACE: Trace;
+}
+
+// One step of a trace: a local answer, a policy match, a cache lookup, a query to an
+// upstream or authoritative server with its response code and how long it took, and so
+// on. `event` says which.
+#[derive(Debug, Serialize)]
+pub struct Step {
+    // Milliseconds since the trace started.
+    at_ms: f64,
+    event: &'static str,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    qtype: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rcode: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<f64>,
+    detail: String,
+}
+
+// Run a lookup while tracing it, returning its result with the steps it took and how
+// long it took overall.
+pub async fn traced<F: Future>(lookup: F) -> (F::Output, Vec<Step>, Duration) {
+    let trace = Trace {
+        started: Instant::now(),
+        steps: Mutex::new(Vec::new()),
+    };
+    TRACE
+        .scope(trace, async {
+            let output = lookup.await;
+            TRACE.with(|trace| {
+                let steps = std::mem::take(&mut *trace.steps.lock().unwrap());
+                (output, steps, trace.started.elapsed())
+            })
+        })
+        .await
+}
+
+// Record a step about a name, if the lookup is being traced.
+pub fn step(event: &'static str, name: &str, detail: impl fmt::Display) {
+    record(|started| Step {
+        at_ms: millis(started.elapsed()),
+        event,
+        name: name.to_string(),
+        qtype: None,
+        server: None,
+        rcode: None,
+        duration_ms: None,
+        detail: detail.to_string(),
+    });
+}
+
+// Record a query sent to a server, with the response code it answered with or why it
+// failed, if the lookup is being traced.
+pub fn query<E: fmt::Display>(
+    server: impl fmt::Display,
+    name: &str,
+    qtype: u16,
+    elapsed: Duration,
+    result: &Result<Message, E>,
+) {
+    record(|started| {
+        let (rcode, detail) = match result {
+            Ok(response) => (
+                Some(response.rcode()),
+                format!(
+                    "{} answers, {} authority records",
+                    response.answers.len(),
+                    response.authority.len()
+                ),
+            ),
+            Err(e) => (None, e.to_string()),
+        };
+        Step {
+            at_ms: millis(started.elapsed().saturating_sub(elapsed)),
+            event: "query",
+            name: name.to_string(),
+            qtype: Some(qtype),
+            server: Some(server.to_string()),
+            rcode,
+            duration_ms: Some(millis(elapsed)),
+            detail,
+        }
+    });
+}
+
+fn record(step: impl FnOnce(Instant) -> Step) {
+    let _ = TRACE.try_with(|trace| {
+        let step = step(trace.started);
+        trace.steps.lock().unwrap().push(step);
+    });
+}
+
+// A duration in milliseconds, to the microsecond.
+pub fn millis(duration: Duration) -> f64 {
+    (duration.as_secs_f64() * 1_000_000.0).round() / 1000.0
+}
@@ -0,0 +1,347 @@
+use crate::upstream::{exchange, Query, QueryOptions};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio::time::{timeout, timeout_at, Instant};
+use tokio_native_tls::{native_tls, TlsConnector, TlsStream};
+
+// The port DNS over TLS is served on unless the upstream says otherwise (RFC 7858).
+const DOT_PORT: u16 = 853;
+// How long a connection to an encrypted upstream is kept open without queries.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+// The media type of DNS messages carried over HTTPS (RFC 8484).
+const DNS_MESSAGE: &str = "application/dns-message";
+const TLS_TIMED_OUT: &str = "Timed out waiting for response over TLS";
+// The line each certificate of a PEM bundle ends with.
+const PEM_END: &str = "-----END CERTIFICATE-----";
+
+// How queries reach an upstream, going by the form of its address:
+//
+//   host:port                  plain DNS over UDP, and TCP for truncated answers
+//   tls://host[:port]          DNS over TLS (RFC 7858)
+//   https://host[:port]/path   DNS over HTTPS (RFC 8484)
+//
+// The encrypted transports keep their connections open and send queries on them while
+// earlier ones are still being answered, rather than waiting for each in turn.
+pub enum Transport {
+    Plain(String),
+    Tls(TlsUpstream),
+    Https(HttpsUpstream),
+}
+
+impl Transport {
+    // The transport for an upstream address. The certificates of encrypted upstreams
+    // are checked against the system's CA certificates, and those of `ca_bundle`, the
+    // contents of a PEM file, if given.
+    pub fn new(address: &str, ca_bundle: Option<&[u8]>) -> Result<Transport, String> {
+        if let Some(server) = address.strip_prefix("tls://") {
+            Ok(Transport::Tls(TlsUpstream::new(server, ca_bundle)?))
+        } else if address.starts_with("https://") {
+            Ok(Transport::Https(HttpsUpstream::new(address, ca_bundle)?))
+        } else if address.contains("://") {
+            Err(format!("Unsupported upstream {}", address))
+        } else {
+            Ok(Transport::Plain(address.to_string()))
+        }
+    }
+
+    // Send a query to the upstream and wait for a response that answers it.
+    pub async fn exchange(
+        &self,
+        domain: &str,
+        qtype: u16,
+        query_timeout: Duration,
+        options: QueryOptions,
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let (query, response) = match self {
+            Transport::Plain(address) => {
+                return exchange(address.as_str(), domain, qtype, query_timeout, options).await
+            }
+            Transport::Tls(upstream) => {
+                let query = Query::new(domain, qtype, options);
+                let response = upstream.send(&query.message, query_timeout).await?;
+                (query, response)
+            }
+            Transport::Https(upstream) => {
+                let query = Query::new(domain, qtype, options);
+                let response = upstream.send(&query.message, query_timeout).await?;
+                (query, response)
+            }
+        };
+        query.check(&response)?;
+        Ok(response)
+    }
+}
+
+// An upstream reached over TLS, with the connection to it if one is open.
+pub struct TlsUpstream {
+    address: String,
+    // The name the upstream's certificate has to be for.
+    server_name: String,
+    connector: TlsConnector,
+    connection: tokio::sync::Mutex<Option<Arc<TlsConnection>>>,
+}
+
+// An open TLS connection. Queries are written to it as they come, and a task reading
+// from it hands each response to the query with its transaction ID, in whatever order
+// the upstream answers them.
+struct TlsConnection {
+    writer: tokio::sync::Mutex<WriteHalf<TlsStream<TcpStream>>>,
+    // The queries waiting for their responses, by transaction ID.
+    pending: Mutex<HashMap<u16, oneshot::Sender<Vec<u8>>>>,
+    // Cleared once the connection is closed, by either side.
+    open: AtomicBool,
+}
+
+impl TlsUpstream {
+    fn new(server: &str, ca_bundle: Option<&[u8]>) -> Result<TlsUpstream, String> {
+        let (host, port) = match server.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                let port = port
+                    .parse()
+                    .map_err(|_| format!("Invalid port in upstream tls://{}", server))?;
+                (host, port)
+            }
+            _ => (server, DOT_PORT),
+        };
+        let mut builder = native_tls::TlsConnector::builder();
+        for certificate in pem_certificates(ca_bundle)? {
+            let certificate = native_tls::Certificate::from_pem(certificate.as_bytes())
+                .map_err(|e| format!("Invalid CA certificate: {}", e))?;
+            builder.add_root_certificate(certificate);
+        }
+        let connector = builder
+            .build()
+            .map_err(|e| format!("Failed to set up TLS for {}: {}", server, e))?;
+        Ok(TlsUpstream {
+            address: format!("{}:{}", host, port),
+            server_name: host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            connector: TlsConnector::from(connector),
+            connection: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    // Send a query over the open connection, or a new one if there is none, and wait
+    // for its response. A query whose connection closes before it is answered is sent
+    // again once, on a new connection, as the upstream may have just closed it for
+    // being idle. Queries sharing a connection may have picked the same transaction ID,
+    // so a query whose ID is already in flight is sent with another one, and its
+    // response given back its own.
+    async fn send(
+        &self,
+        query: &[u8],
+        query_timeout: Duration,
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let deadline = Instant::now() + query_timeout;
+        let transaction_id = u16::from_be_bytes([query[0], query[1]]);
+        let mut message = (query.len() as u16).to_be_bytes().to_vec();
+        message.extend_from_slice(query);
+
+        for attempt in 1..=2 {
+            let connection = timeout_at(deadline, self.connection())
+                .await
+                .map_err(|_| TLS_TIMED_OUT)??;
+            let (sender, receiver) = oneshot::channel();
+            // The reader closes the connection under the same lock, so a query is either
+            // registered while the connection is open, or sent on a new one
+            let sent_id = {
+                let mut pending = connection.pending.lock().unwrap();
+                if connection.open.load(Ordering::Relaxed) {
+                    let mut sent_id = transaction_id;
+                    while pending.contains_key(&sent_id) {
+                        sent_id = rand::random();
+                    }
+                    pending.insert(sent_id, sender);
+                    Some(sent_id)
+                } else {
+                    None
+                }
+            };
+            let Some(sent_id) = sent_id else {
+                if attempt == 1 {
+                    continue;
+                }
+                return Err("TLS connection closed before the query was sent".into());
+            };
+            message[2..4].copy_from_slice(&sent_id.to_be_bytes());
+
+            let written = timeout_at(deadline, async {
+                connection.writer.lock().await.write_all(&message).await
+            })
+            .await;
+            match written {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    connection.open.store(false, Ordering::Relaxed);
+                    connection.pending.lock().unwrap().remove(&sent_id);
+                    if attempt == 1 {
+                        continue;
+                    }
+                    return Err(e.into());
+                }
+                // Part of the query may have been written, so nothing more can be sent on
+                // the connection
+                Err(_) => {
+                    connection.open.store(false, Ordering::Relaxed);
+                    connection.pending.lock().unwrap().remove(&sent_id);
+                    return Err(TLS_TIMED_OUT.into());
+                }
+            }
+
+            match timeout_at(deadline, receiver).await {
+                Ok(Ok(mut response)) => {
+                    response[..2].copy_from_slice(&query[..2]);
+                    return Ok(response);
+                }
+                // The connection closed before the upstream answered
+                Ok(Err(_)) if attempt == 1 => continue,
+                Ok(Err(_)) => return Err("TLS connection closed before the response".into()),
+                Err(_) => {
+                    connection.pending.lock().unwrap().remove(&sent_id);
+                    return Err(TLS_TIMED_OUT.into());
+                }
+            }
+        }
+        Err("TLS connection closed before the response".into())
+    }
+
+    // The open connection to the upstream, connecting first if there is none. Queries
+    // sent meanwhile wait for the connection rather than opening their own.
+    async fn connection(&self) -> Result<Arc<TlsConnection>, Box<dyn Error + Send + Sync>> {
+        let mut connection = self.connection.lock().await;
+        if let Some(open) = connection
+            .as_ref()
+            .filter(|c| c.open.load(Ordering::Relaxed))
+        {
+            return Ok(open.clone());
+        }
+
+        let stream = TcpStream::connect(&self.address).await?;
+        let stream = self.connector.connect(&self.server_name, stream).await?;
+        println!("Connected to upstream {} over TLS", self.address);
+        let (reader, writer) = tokio::io::split(stream);
+        let opened = Arc::new(TlsConnection {
+            writer: tokio::sync::Mutex::new(writer),
+            pending: Mutex::new(HashMap::new()),
+            open: AtomicBool::new(true),
+        });
+        tokio::spawn(read_responses(reader, opened.clone(), self.address.clone()));
+        *connection = Some(opened.clone());
+        Ok(opened)
+    }
+}
+
+// Read the responses that come in on a TLS connection, each preceded by its length,
+// handing each to the query waiting for it, until the connection closes or has sat idle
+// for long enough. Queries still waiting then find their responses will not come.
+async fn read_responses(
+    mut reader: ReadHalf<TlsStream<TcpStream>>,
+    connection: Arc<TlsConnection>,
+    address: String,
+) {
+    let pending = &connection.pending;
+    loop {
+        let mut length = [0u8; 2];
+        match timeout(IDLE_TIMEOUT, reader.read_exact(&mut length)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(_)) => break,
+            Err(_) => {
+                let pending = pending.lock().unwrap();
+                if pending.is_empty() {
+                    connection.open.store(false, Ordering::Relaxed);
+                    break;
+                }
+                continue;
+            }
+        }
+        let mut response = vec![0u8; usize::from(u16::from_be_bytes(length))];
+        if reader.read_exact(&mut response).await.is_err() || response.len() < 2 {
+            break;
+        }
+        let transaction_id = u16::from_be_bytes([response[0], response[1]]);
+        match pending.lock().unwrap().remove(&transaction_id) {
+            Some(waiter) => {
+                let _ = waiter.send(response);
+            }
+            None => eprintln!("Dropping response from {} for no query in flight", address),
+        }
+    }
+    {
+        let mut pending = pending.lock().unwrap();
+        connection.open.store(false, Ordering::Relaxed);
+        pending.clear();
+    }
+    let _ = connection.writer.lock().await.shutdown().await;
+    println!("Closed TLS connection to upstream {}", address);
+}
+
+// An upstream reached over HTTPS. The HTTP client keeps connections open between
+// queries, and over HTTP/2 sends concurrent queries on the same connection.
+pub struct HttpsUpstream {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl HttpsUpstream {
+    fn new(url: &str, ca_bundle: Option<&[u8]>) -> Result<HttpsUpstream, String> {
+        let mut builder = reqwest::Client::builder().pool_idle_timeout(IDLE_TIMEOUT);
+        for certificate in pem_certificates(ca_bundle)? {
+            let certificate = reqwest::Certificate::from_pem(certificate.as_bytes())
+                .map_err(|e| format!("Invalid CA certificate: {}", e))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+        let client = builder
+            .build()
+            .map_err(|e| format!("Failed to set up HTTPS for {}: {}", url, e))?;
+        Ok(HttpsUpstream {
+            url: url.to_string(),
+            client,
+        })
+    }
+
+    // POST the query and return the body of the response.
+    async fn send(
+        &self,
+        query: &[u8],
+        query_timeout: Duration,
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let response = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, DNS_MESSAGE)
+            .header(reqwest::header::ACCEPT, DNS_MESSAGE)
+            .body(query.to_vec())
+            .timeout(query_timeout)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(format!("Upstream answered with HTTP status {}", response.status()).into());
+        }
+        Ok(response.bytes().await?.to_vec())
+    }
+}
+
+// The certificates of a PEM bundle, each with its BEGIN and END lines.
+fn pem_certificates(bundle: Option<&[u8]>) -> Result<Vec<String>, String> {
+    let Some(bundle) = bundle else {
+        return Ok(Vec::new());
+    };
+    let bundle = std::str::from_utf8(bundle).map_err(|_| "CA bundle is not PEM".to_string())?;
+    let certificates: Vec<String> = bundle
+        .split_inclusive(PEM_END)
+        .filter(|block| block.ends_with(PEM_END))
+        .map(|block| block.trim_start().to_string())
+        .collect();
+    if certificates.is_empty() {
+        return Err("CA bundle has no certificates".to_string());
+    }
+    Ok(certificates)
+}
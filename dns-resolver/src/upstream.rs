@@ -0,0 +1,212 @@
+use crate::message::{build_query, read_question, CLASS_IN};
+use crate::subnet::ClientSubnet;
+use rand::Rng;
+use std::error::Error;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use tokio::time::{timeout_at, Instant};
+
+// How many random source ports to try before letting the OS pick one.
+const PORT_ATTEMPTS: usize = 8;
+// The largest UDP datagram, so that no response is cut short by our buffer.
+const MAX_UDP_RESPONSE: usize = 65535;
+
+// What a query asks of the server beyond its question.
+#[derive(Clone, Copy)]
+pub struct QueryOptions {
+    pub recursion_desired: bool,
+    // Send the name in random case (0x20 encoding).
+    pub randomize_case: bool,
+    // Ask for the DNSSEC records of the answer.
+    pub dnssec_ok: bool,
+    // The subnet of the client the query is asked for, passed on with EDNS.
+    pub client_subnet: Option<ClientSubnet>,
+}
+
+// A query ready to be sent, with what its response has to match.
+pub struct Query {
+    pub message: Vec<u8>,
+    transaction_id: u16,
+    // The name as sent, in whatever case it was sent in.
+    name: String,
+    qtype: u16,
+    exact_case: bool,
+}
+
+impl Query {
+    // Build a query with a random transaction ID. With `randomize_case` the letters of
+    // the name are sent in random case (0x20 encoding) and the server has to echo them
+    // back exactly. With `dnssec_ok` the server is asked for the DNSSEC records of the
+    // answer too.
+    pub fn new(domain: &str, qtype: u16, options: QueryOptions) -> Query {
+        let transaction_id: u16 = rand::random();
+        let name = if options.randomize_case {
+            mix_case(domain)
+        } else {
+            domain.to_string()
+        };
+        let mut edns_options = Vec::new();
+        if let Some(subnet) = options.client_subnet {
+            subnet.write_option(0, &mut edns_options);
+        }
+        let message = build_query(
+            transaction_id,
+            &name,
+            qtype,
+            options.recursion_desired,
+            options.dnssec_ok,
+            &edns_options,
+        );
+        Query {
+            message,
+            transaction_id,
+            name,
+            qtype,
+            exact_case: options.randomize_case,
+        }
+    }
+
+    // Whether a response answers this query: same transaction ID and the same question,
+    // with the name in exactly the same case when 0x20 encoding is in use.
+    pub fn check(&self, response: &[u8]) -> Result<(), &'static str> {
+        let question = read_question(response)?;
+        if u16::from_be_bytes([response[0], response[1]]) != self.transaction_id {
+            return Err("Transaction ID does not match");
+        }
+        if response[2] & 0x80 == 0 {
+            return Err("Message is not a response");
+        }
+        let name_matches = if self.exact_case {
+            question.name == self.name
+        } else {
+            question.name.eq_ignore_ascii_case(&self.name)
+        };
+        if !name_matches {
+            return Err("Question name does not match");
+        }
+        if question.qtype != self.qtype || question.qclass != CLASS_IN {
+            return Err("Question type or class does not match");
+        }
+        Ok(())
+    }
+}
+
+// Send a single query to a server over UDP and wait for its response.
+//
+// Every query goes out from a random source port with a random transaction ID, and
+// responses whose ID or question do not match are dropped, so a forged answer has to
+// guess both to be accepted.
+//
+// A response with the TC bit set did not fit in a datagram, so the query is asked again
+// over TCP to get all of it.
+pub async fn exchange(
+    server_addr: impl ToSocketAddrs + Copy,
+    domain: &str,
+    qtype: u16,
+    query_timeout: Duration,
+    options: QueryOptions,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let query = Query::new(domain, qtype, options);
+    let check = |response: &[u8]| query.check(response);
+
+    let response = exchange_udp(server_addr, &query.message, query_timeout, &check, domain).await?;
+    if response[2] & 0x02 == 0 {
+        return Ok(response);
+    }
+    println!(
+        "Response for {} is truncated, asking again over TCP",
+        domain
+    );
+    let response = exchange_tcp(server_addr, &query.message, query_timeout).await?;
+    query.check(&response)?;
+    Ok(response)
+}
+
+// Send a query over UDP and wait for a response that passes the check.
+async fn exchange_udp(
+    server_addr: impl ToSocketAddrs,
+    query: &[u8],
+    query_timeout: Duration,
+    check: &impl Fn(&[u8]) -> Result<(), &'static str>,
+    domain: &str,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let socket = bind_random_port().await?;
+    socket.connect(server_addr).await?;
+    socket.send(query).await?;
+
+    // Keep listening until a matching response arrives or the time is up
+    let deadline = Instant::now() + query_timeout;
+    let mut response = vec![0u8; MAX_UDP_RESPONSE];
+    loop {
+        let len = timeout_at(deadline, socket.recv(&mut response))
+            .await
+            .map_err(|_| "Timed out waiting for response")??;
+        match check(&response[..len]) {
+            Ok(()) => {
+                response.truncate(len);
+                return Ok(response);
+            }
+            Err(e) => eprintln!(
+                "Dropping response from {} for {}: {}",
+                socket.peer_addr()?,
+                domain,
+                e
+            ),
+        }
+    }
+}
+
+// Send a query over TCP and read back its response, both preceded by their length.
+async fn exchange_tcp(
+    server_addr: impl ToSocketAddrs,
+    query: &[u8],
+    query_timeout: Duration,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let deadline = Instant::now() + query_timeout;
+    let timed_out = |_| "Timed out waiting for response over TCP";
+    let mut stream = timeout_at(deadline, TcpStream::connect(server_addr))
+        .await
+        .map_err(timed_out)??;
+
+    let mut message = (query.len() as u16).to_be_bytes().to_vec();
+    message.extend_from_slice(query);
+    stream.write_all(&message).await?;
+
+    let mut length = [0u8; 2];
+    timeout_at(deadline, stream.read_exact(&mut length))
+        .await
+        .map_err(timed_out)??;
+    let mut response = vec![0u8; usize::from(u16::from_be_bytes(length))];
+    timeout_at(deadline, stream.read_exact(&mut response))
+        .await
+        .map_err(timed_out)??;
+    Ok(response)
+}
+
+// Bind a socket to a random unprivileged port.
+async fn bind_random_port() -> io::Result<UdpSocket> {
+    for _ in 0..PORT_ATTEMPTS {
+        let port = rand::thread_rng().gen_range(1024..=u16::MAX);
+        if let Ok(socket) = UdpSocket::bind(("0.0.0.0", port)).await {
+            return Ok(socket);
+        }
+    }
+    UdpSocket::bind("0.0.0.0:0").await
+}
+
+// Flip each letter of the name to upper or lower case at random.
+fn mix_case(domain: &str) -> String {
+    let mut rng = rand::thread_rng();
+    domain
+        .chars()
+        .map(|c| {
+            if rng.gen() {
+                c.to_ascii_uppercase()
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .collect()
+}
@@ -1,179 +1,362 @@
+mod message;
+mod record;
+mod store;
+mod update;
+mod zone;
+
+use message::{
+    build_format_error, build_response, parse_query, Query, Response, MAX_TCP, OPCODE_QUERY,
+    OPCODE_UPDATE, RCODE_FORMERR, RCODE_NOERROR, RCODE_NOTAUTH, RCODE_NOTIMP, RCODE_NXDOMAIN,
+    RCODE_REFUSED, RCODE_SERVFAIL,
+};
+use record::{RecordType, CLASS_IN};
+use serde::Deserialize;
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{self, BufRead, Error};
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
-use tokio::net::UdpSocket;
-
-// Load A records (domain to IP mappings) and their TTLs from a specified file.
-async fn load_a_records_from_file(
-    file_path: &Path,
-) -> io::Result<HashMap<String, (Ipv4Addr, u32)>> {
-    let file = File::open(file_path)?;
-    let buf = io::BufReader::new(file);
-    let mut a_records = HashMap::new();
-
-    for line in buf.lines() {
-        // Parsing each line to extract domain, IP address, and TTL.
-        let line = line?;
-        let parts: Vec<&str> = line.split('=').collect();
-        if parts.len() == 2 {
-            let domain = parts[0];
-            let rest: Vec<&str> = parts[1].split(',').collect();
-            if rest.len() == 2 {
-                // Converting string IP to Ipv4Addr and string TTL to u32.
-                let ip_address = rest[0]
-                    .parse()
-                    .map_err(|_| Error::new(io::ErrorKind::InvalidData, "Invalid IP address"))?;
-                let ttl = rest[1]
-                    .parse()
-                    .map_err(|_| Error::new(io::ErrorKind::InvalidData, "Invalid TTL"))?;
-                // Storing the parsed data in a HashMap.
-                a_records.insert(domain.to_string(), (ip_address, ttl));
-            }
-        }
-    }
+use store::ZoneStore;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{self, timeout, Duration};
+use update::apply_update;
+use zone::{Catalog, Change, Lookup, Zone};
 
-    Ok(a_records)
-}
+// The largest DNS message, bounded by the 16-bit length of UDP datagrams and TCP frames.
+const MAX_MESSAGE: usize = 65535;
+// How long a TCP connection may sit idle before it is closed (RFC 7766).
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+// TCP messages waiting for the main loop to handle them.
+const TCP_QUEUE: usize = 64;
 
-// Construct a DNS response given a domain name, its resolved IP address, and TTL.
-fn create_dns_response(
-    transaction_id: [u8; 2],
-    domain: &str,
-    ip_address: Ipv4Addr,
-    ttl: u32,
-) -> Vec<u8> {
-    let mut response = Vec::new();
-    let ip_bytes = ip_address.octets();
-    let ttl_bytes = ttl.to_be_bytes(); // Convert TTL to byte array in big-endian format
-
-    // Transaction ID, Flags, Questions, Answer RRs, Authority RRs, Additional RRs
-    response.extend_from_slice(&transaction_id);
-    response.extend_from_slice(&[0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00]);
-
-    // Question section
-    for label in domain.split('.') {
-        response.push(label.len() as u8);
-        response.extend_from_slice(label.as_bytes());
-    }
-    response.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x01]);
+// A message received over TCP, handed to the main loop, which owns the zones, with where
+// to send the response. No response is sent to a message that cannot be answered.
+struct TcpMessage {
+    message: Vec<u8>,
+    addr: SocketAddr,
+    respond: oneshot::Sender<Option<Vec<u8>>>,
+}
 
-    // Answer section
-    response.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01]);
-    response.extend_from_slice(&ttl_bytes);
-    response.extend_from_slice(&[0x00, 0x04]);
-    response.extend_from_slice(&ip_bytes);
+#[derive(Deserialize)]
+struct Config {
+    #[serde(default = "default_listen")]
+    listen: String,
+    store: StoreConfig,
+    zones: Vec<ZoneConfig>,
+}
 
-    response
+fn default_listen() -> String {
+    "0.0.0.0:53".to_string()
 }
 
-// Parse the domain name from the DNS query buffer.
-fn parse_domain_name(buf: &[u8], start: usize) -> Result<String, &'static str> {
-    let mut position = start;
-    let mut domain_name = String::new();
+#[derive(Deserialize)]
+struct StoreConfig {
+    dir: String,
+    // Compact a zone once this many changes have been journaled since its last snapshot.
+    compact_after: usize,
+    // How often zones with journaled changes are compacted regardless.
+    compact_interval_secs: u64,
+}
 
-    while position < buf.len() && buf[position] != 0 {
-        let length = buf[position] as usize;
-        position += 1; // move past the length byte
+#[derive(Deserialize)]
+struct ZoneConfig {
+    origin: String,
+    file: String,
+    // Clients allowed to change the zone with dynamic updates.
+    #[serde(default)]
+    allow_update: Vec<IpAddr>,
+}
 
-        // Check for potential out-of-bounds or invalid length
-        if length == 0 || position + length > buf.len() {
-            return Err("Invalid domain name in query");
-        }
+// The zones being served along with the on-disk store backing each of them.
+struct Server {
+    catalog: Catalog,
+    stores: HashMap<String, ZoneStore>,
+    allow_update: HashMap<String, Vec<IpAddr>>,
+    compact_after: usize,
+}
 
-        if !domain_name.is_empty() {
-            domain_name.push('.');
-        }
-        let label = match std::str::from_utf8(&buf[position..position + length]) {
-            Ok(s) => s,
-            Err(_) => return Err("Invalid UTF-8 label in domain name"),
-        };
-        domain_name.push_str(label);
+// Load every zone listed in the config, each seeded from its own zone file and recovered
+// from its snapshot and journal.
+fn load_server(config: &Config) -> Result<Server, Box<dyn std::error::Error>> {
+    let mut zones = Vec::new();
+    let mut stores = HashMap::new();
+    let mut allow_update = HashMap::new();
+    for zone_config in &config.zones {
+        let file_zone = Zone::load(&zone_config.origin, Path::new(&zone_config.file))
+            .map_err(|e| format!("Failed to load zone {}: {}", zone_config.origin, e))?;
+        println!("Loaded zone {} from {}", file_zone.origin, zone_config.file);
+        let (zone, store) = ZoneStore::open(Path::new(&config.store.dir), file_zone)
+            .map_err(|e| format!("Failed to open store for {}: {}", zone_config.origin, e))?;
+        stores.insert(zone.origin.clone(), store);
+        allow_update.insert(zone.origin.clone(), zone_config.allow_update.clone());
+        zones.push(zone);
+    }
+    Ok(Server {
+        catalog: Catalog::new(zones),
+        stores,
+        allow_update,
+        compact_after: config.store.compact_after,
+    })
+}
 
-        position += length; // move to the next label
+// Answer a query from the zone with the longest origin enclosing the question name.
+// Names outside every zone we serve are refused rather than denied.
+fn answer_query(catalog: &Catalog, query: &Query) -> Response {
+    if query.qclass != CLASS_IN {
+        return Response::error(RCODE_REFUSED);
     }
 
-    Ok(domain_name)
+    let zone = match catalog.find_zone(&query.domain) {
+        Some(zone) => zone,
+        None => return Response::error(RCODE_REFUSED),
+    };
+
+    match zone.lookup(&query.domain, RecordType::from_u16(query.qtype)) {
+        Lookup::Answer(answers) => Response {
+            rcode: RCODE_NOERROR,
+            authoritative: true,
+            answers,
+            ..Default::default()
+        },
+        Lookup::NoData => Response {
+            rcode: RCODE_NOERROR,
+            authoritative: true,
+            authority: vec![zone.negative_soa()],
+            ..Default::default()
+        },
+        Lookup::NxDomain => Response {
+            rcode: RCODE_NXDOMAIN,
+            authoritative: true,
+            authority: vec![zone.negative_soa()],
+            ..Default::default()
+        },
+        Lookup::Referral { ns, glue } => Response {
+            rcode: RCODE_NOERROR,
+            authoritative: false,
+            authority: ns,
+            additional: glue,
+            ..Default::default()
+        },
+    }
 }
 
-// Send a DNS response with NXDOMAIN (non-existent domain) to the client.
-async fn send_nxdomain_response(
-    transaction_id: [u8; 2],
-    request: &[u8],
-    request_len: usize,
-    addr: &std::net::SocketAddr,
-    socket: &tokio::net::UdpSocket,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut response = Vec::new();
+// Apply a dynamic update to the zone it names. The changes and the new SOA serial are
+// synced to the journal before the update is acknowledged; if that fails the zone is
+// rolled back so memory never gets ahead of the disk.
+fn handle_update(server: &mut Server, query: &Query, buf: &[u8], addr: &SocketAddr) -> Response {
+    if query.qclass != CLASS_IN || query.qtype != RecordType::SOA.to_u16() {
+        return Response::error(RCODE_FORMERR);
+    }
+    let (zone, store) = match (
+        server.catalog.zone_mut(&query.domain),
+        server.stores.get_mut(&query.domain),
+    ) {
+        (Some(zone), Some(store)) => (zone, store),
+        _ => return Response::error(RCODE_NOTAUTH),
+    };
+    let allowed = server
+        .allow_update
+        .get(&zone.origin)
+        .is_some_and(|allowed| allowed.contains(&addr.ip()));
+    if !allowed {
+        println!("Refused update to {} from {}", zone.origin, addr);
+        return Response::error(RCODE_REFUSED);
+    }
+
+    let mut changes = match apply_update(zone, buf, query) {
+        Ok(changes) if changes.is_empty() => return Response::error(RCODE_NOERROR),
+        Ok(changes) => changes,
+        Err(rcode) => return Response::error(rcode),
+    };
+    for change in zone.serial_increment() {
+        zone.apply(&change);
+        changes.push(change);
+    }
+
+    if let Err(e) = store.append(zone.serial(), &changes) {
+        eprintln!("Failed to journal update to {}: {}", zone.origin, e);
+        for change in changes.iter().rev() {
+            zone.apply(&match change {
+                Change::Add(record) => Change::Delete(record.clone()),
+                Change::Delete(record) => Change::Add(record.clone()),
+            });
+        }
+        return Response::error(RCODE_SERVFAIL);
+    }
+    println!(
+        "Applied {} change(s) to {}, now at serial {}",
+        changes.len() - 2,
+        zone.origin,
+        zone.serial()
+    );
 
-    // Transaction ID
-    response.extend_from_slice(&transaction_id);
+    if store.pending() >= server.compact_after {
+        if let Err(e) = store.compact(zone) {
+            eprintln!("Failed to compact zone {}: {}", zone.origin, e);
+        }
+    }
+    Response::error(RCODE_NOERROR)
+}
 
-    // Flags: Response, Opcode 0 (Standard Query), Authoritative Answer False, Truncated False,
-    // Recursion Desired True, Recursion Available False, Z Reserved, Answer Authenticated False,
-    // Non-authenticated data Acceptable, Reply Code NXDOMAIN (3)
-    response.extend_from_slice(&[0x81, 0x83]); // Note: 0x83 indicates NXDOMAIN
+// Handle a DNS message from a client, returning the response to send back. Over UDP the
+// response is cut down to what the client takes, and over TCP to what fits in a frame.
+fn handle_message(
+    server: &mut Server,
+    message: &[u8],
+    addr: &SocketAddr,
+    tcp: bool,
+) -> Option<Vec<u8>> {
+    match parse_query(message) {
+        Ok(query) => {
+            println!("Parsed domain: {} (type {})", query.domain, query.qtype);
+            let response = match query.opcode {
+                OPCODE_QUERY => answer_query(&server.catalog, &query),
+                OPCODE_UPDATE => handle_update(server, &query, message, addr),
+                _ => Response::error(RCODE_NOTIMP),
+            };
+            println!(
+                "Answering {} with rcode {} and {} answer(s)",
+                query.domain,
+                response.rcode,
+                response.answers.len()
+            );
+            let max_size = if tcp {
+                MAX_TCP
+            } else {
+                query.max_udp_response()
+            };
+            Some(build_response(&query, &response, max_size))
+        }
+        Err(e) => {
+            eprintln!("Failed to parse query: {}", e);
+            build_format_error(message)
+        }
+    }
+}
 
-    // Questions: 1, Answer RRs: 0, Authority RRs: 0, Additional RRs: 0
-    response.extend_from_slice(&[0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+// Accept TCP connections, serving each in its own task.
+async fn accept_tcp(listener: TcpListener, messages: mpsc::Sender<TcpMessage>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                tokio::spawn(serve_tcp_connection(stream, addr, messages.clone()));
+            }
+            Err(e) => eprintln!("Failed to accept TCP connection: {}", e),
+        }
+    }
+}
 
-    // Repeat the question section from the request
-    response.extend_from_slice(&request[12..request_len]);
+// Answer the messages a client sends over a TCP connection, each preceded by its length
+// (RFC 1035 section 4.2.2), until the client closes the connection or leaves it idle.
+async fn serve_tcp_connection(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    messages: mpsc::Sender<TcpMessage>,
+) {
+    loop {
+        let mut length = [0u8; 2];
+        match timeout(TCP_IDLE_TIMEOUT, stream.read_exact(&mut length)).await {
+            Ok(Ok(_)) => {}
+            // Closed by the client, or idle for too long
+            Ok(Err(_)) | Err(_) => return,
+        }
+        let mut message = vec![0u8; usize::from(u16::from_be_bytes(length))];
+        match timeout(TCP_IDLE_TIMEOUT, stream.read_exact(&mut message)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => {
+                eprintln!("Failed to read message from {}: {}", addr, e);
+                return;
+            }
+            Err(_) => {
+                eprintln!("Timed out reading message from {}", addr);
+                return;
+            }
+        }
+        println!("Received TCP query from {}", addr);
 
-    // Sending the NXDOMAIN response
-    socket.send_to(&response, addr).await?;
+        let (respond, response) = oneshot::channel();
+        let message = TcpMessage {
+            message,
+            addr,
+            respond,
+        };
+        if messages.send(message).await.is_err() {
+            return;
+        }
+        let Ok(Some(response)) = response.await else {
+            return;
+        };
+        let mut framed = (response.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(&response);
+        if let Err(e) = stream.write_all(&framed).await {
+            eprintln!("Failed to send response to {}: {}", addr, e);
+            return;
+        }
+    }
+}
 
-    Ok(())
+// Fold the journal of every zone with outstanding changes into a new snapshot.
+fn compact_zones(server: &mut Server) {
+    for (origin, store) in server.stores.iter_mut() {
+        if store.pending() == 0 {
+            continue;
+        }
+        if let Some(zone) = server.catalog.zone_mut(origin) {
+            if let Err(e) = store.compact(zone) {
+                eprintln!("Failed to compact zone {}: {}", origin, e);
+            }
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Load the A records from "src/domain.txt".
-    let a_records = load_a_records_from_file(Path::new("src/domain.txt")).await?;
+    // Load the zones listed in the config file, "src/config.toml" unless DNS_SERVER_CONFIG
+    // points elsewhere so several servers can be run from the same source.
+    let config_path =
+        std::env::var("DNS_SERVER_CONFIG").unwrap_or_else(|_| "src/config.toml".to_string());
+    let config_str = fs::read_to_string(&config_path).await?;
+    let config: Config = toml::from_str(&config_str)?;
+    let mut server = load_server(&config)?;
 
-    // Bind the server to UDP port 53 and listens for incoming DNS queries.
-    let socket = UdpSocket::bind("0.0.0.0:53").await?;
-    println!("DNS Server listening on {}", socket.local_addr()?);
+    // Bind the server to port 53 (by default) and listen for incoming DNS queries over
+    // UDP, and over TCP for answers too large for UDP.
+    let socket = UdpSocket::bind(&config.listen).await?;
+    let listener = TcpListener::bind(&config.listen).await?;
+    println!(
+        "DNS Server listening on {} (UDP and TCP)",
+        socket.local_addr()?
+    );
+    let (tcp_sender, mut tcp_messages) = mpsc::channel(TCP_QUEUE);
+    tokio::spawn(accept_tcp(listener, tcp_sender));
 
-    let mut buf = [0u8; 512]; // Buffer to store incoming DNS queries.
+    // Buffer to store incoming DNS messages, large enough for the biggest UDP datagram so
+    // that UPDATE messages with many records are never cut short.
+    let mut buf = vec![0u8; MAX_MESSAGE];
+    let mut compaction = time::interval(Duration::from_secs(config.store.compact_interval_secs));
 
     loop {
-        let (_, addr) = socket.recv_from(&mut buf).await?;
+        let (len, addr) = tokio::select! {
+            received = socket.recv_from(&mut buf) => received?,
+            Some(tcp) = tcp_messages.recv() => {
+                let response = handle_message(&mut server, &tcp.message, &tcp.addr, true);
+                let _ = tcp.respond.send(response);
+                continue;
+            }
+            _ = compaction.tick() => {
+                compact_zones(&mut server);
+                continue;
+            }
+        };
         println!("Received query from {}", addr);
 
-        match parse_domain_name(&buf, 12) {
-            // Start parsing after the header
-            Ok(domain) => {
-                println!("Parsed domain: {}", domain);
-                match a_records.get(&domain) {
-                    Some((ip_address, ttl)) => {
-                        let transaction_id = [buf[0], buf[1]];
-                        let response =
-                            create_dns_response(transaction_id, &domain, *ip_address, *ttl);
-                        if let Err(e) = socket.send_to(&response, &addr).await {
-                            eprintln!("Failed to send response: {}", e);
-                        } else {
-                            println!(
-                                "Sent response to {} for domain {} and ip {}",
-                                addr, domain, ip_address
-                            );
-                        }
-                    }
-                    None => {
-                        let transaction_id = [buf[0], buf[1]];
-                        if let Err(e) =
-                            send_nxdomain_response(transaction_id, &buf, buf.len(), &addr, &socket)
-                                .await
-                        {
-                            eprintln!("Failed to send NXDOMAIN response: {}", e);
-                        } else {
-                            println!("Sent NXDOMAIN response to {}", addr);
-                        }
-                    }
-                }
-            }
-            Err(e) => eprintln!("Failed to parse domain name: {}", e),
+        let Some(response) = handle_message(&mut server, &buf[..len], &addr, false) else {
+            continue;
+        };
+
+        if let Err(e) = socket.send_to(&response, &addr).await {
+            eprintln!("Failed to send response: {}", e);
         }
     }
 }
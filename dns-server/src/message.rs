@@ -0,0 +1,355 @@
+use crate::record::{read_name, RawRecord, Record};
+
+// Response codes used by the server.
+pub const RCODE_NOERROR: u8 = 0;
+pub const RCODE_FORMERR: u8 = 1;
+pub const RCODE_SERVFAIL: u8 = 2;
+pub const RCODE_NXDOMAIN: u8 = 3;
+pub const RCODE_NOTIMP: u8 = 4;
+pub const RCODE_REFUSED: u8 = 5;
+pub const RCODE_YXDOMAIN: u8 = 6;
+pub const RCODE_YXRRSET: u8 = 7;
+pub const RCODE_NXRRSET: u8 = 8;
+pub const RCODE_NOTAUTH: u8 = 9;
+pub const RCODE_NOTZONE: u8 = 10;
+
+// Standard query and dynamic update (RFC 2136) opcodes.
+pub const OPCODE_QUERY: u8 = 0;
+pub const OPCODE_UPDATE: u8 = 5;
+
+// The largest UDP response to a client without EDNS (RFC 1035 section 4.2.1).
+pub const MAX_PLAIN_UDP: usize = 512;
+// The largest UDP response we send to a client with EDNS, however large it says it can
+// take, and advertise in our own OPT record (the DNS Flag Day 2020 default).
+pub const EDNS_PAYLOAD_SIZE: u16 = 1232;
+// The largest response sent over TCP, bounded by its 16-bit length prefix.
+pub const MAX_TCP: usize = 65535;
+const TYPE_OPT: u16 = 41;
+// The size of our OPT record: the root name, type, payload size, TTL and empty RDATA.
+const OPT_LEN: usize = 11;
+
+// The parts of an incoming DNS query the server cares about.
+pub struct Query {
+    pub transaction_id: [u8; 2],
+    pub opcode: u8,
+    pub recursion_desired: bool,
+    // The question name, lowercased for lookups.
+    pub domain: String,
+    pub qtype: u16,
+    pub qclass: u16,
+    // The raw question section, echoed back in the response to preserve case.
+    pub question: Vec<u8>,
+    // Where the sections after the question start, used to read UPDATE messages.
+    pub end_of_question: usize,
+    // The largest UDP response the client takes, from its OPT record if it sent one
+    // (RFC 6891).
+    pub udp_payload_size: Option<u16>,
+}
+
+impl Query {
+    // The largest response that can be sent to the client over UDP.
+    pub fn max_udp_response(&self) -> usize {
+        match self.udp_payload_size {
+            Some(size) => usize::from(size.clamp(MAX_PLAIN_UDP as u16, EDNS_PAYLOAD_SIZE)),
+            None => MAX_PLAIN_UDP,
+        }
+    }
+}
+
+// Parse the header and the single question of a DNS query. For UPDATE messages the
+// question is the zone section.
+pub fn parse_query(buf: &[u8]) -> Result<Query, &'static str> {
+    if buf.len() < 12 {
+        return Err("Query is shorter than a DNS header");
+    }
+    if buf[2] & 0x80 != 0 {
+        return Err("Message is a response, not a query");
+    }
+    let question_count = u16::from_be_bytes([buf[4], buf[5]]);
+    if question_count != 1 {
+        return Err("Query must contain exactly one question");
+    }
+
+    let (domain, position) = read_name(buf, 12)?;
+    if position + 4 > buf.len() {
+        return Err("Question section is truncated");
+    }
+    let qtype = u16::from_be_bytes([buf[position], buf[position + 1]]);
+    let qclass = u16::from_be_bytes([buf[position + 2], buf[position + 3]]);
+    let udp_payload_size = find_opt(buf, position + 4).map(|opt| opt.class);
+
+    Ok(Query {
+        transaction_id: [buf[0], buf[1]],
+        opcode: (buf[2] >> 3) & 0x0F,
+        recursion_desired: buf[2] & 0x01 != 0,
+        domain,
+        qtype,
+        qclass,
+        question: buf[12..position + 4].to_vec(),
+        end_of_question: position + 4,
+        udp_payload_size,
+    })
+}
+
+// The OPT record among the records after the question, if there is one. The sections
+// are read loosely, as UPDATE messages are read in full later.
+fn find_opt(buf: &[u8], end_of_question: usize) -> Option<RawRecord> {
+    let count = |offset: usize| usize::from(u16::from_be_bytes([buf[offset], buf[offset + 1]]));
+    let mut position = end_of_question;
+    for _ in 0..count(6) + count(8) + count(10) {
+        let (record, next) = RawRecord::read(buf, position).ok()?;
+        if record.record_type == TYPE_OPT {
+            return Some(record);
+        }
+        position = next;
+    }
+    None
+}
+
+// The contents of a response before it is encoded.
+#[derive(Default)]
+pub struct Response {
+    pub rcode: u8,
+    pub authoritative: bool,
+    pub answers: Vec<Record>,
+    pub authority: Vec<Record>,
+    pub additional: Vec<Record>,
+}
+
+impl Response {
+    pub fn error(rcode: u8) -> Self {
+        Response {
+            rcode,
+            ..Default::default()
+        }
+    }
+}
+
+// Construct the DNS response to a query, no larger than `max_size`. Records that do not
+// fit are left out whole. If any of them are answer or authority records, the response
+// is marked truncated (TC) so that the client asks again over TCP; additional records
+// are only a help, so they are left out without it (RFC 2181 section 9). A client that
+// sent an OPT record gets ours back.
+pub fn build_response(query: &Query, response: &Response, max_size: usize) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(MAX_PLAIN_UDP);
+
+    // Flags: Response, Opcode copied from the query, Authoritative Answer when we serve
+    // the zone, Truncated when records were left out, Recursion Desired copied,
+    // Recursion Available False since this server never recurses, and the response code.
+    let mut flags = 0x8000u16 | (u16::from(query.opcode) << 11) | u16::from(response.rcode);
+    if response.authoritative {
+        flags |= 0x0400;
+    }
+    if query.recursion_desired {
+        flags |= 0x0100;
+    }
+
+    // The counts of each section are filled in once it is known how many records fit
+    buf.extend_from_slice(&query.transaction_id);
+    buf.extend_from_slice(&flags.to_be_bytes());
+    buf.extend_from_slice(&1u16.to_be_bytes());
+    buf.extend_from_slice(&[0; 6]);
+
+    // Repeat the question section from the request
+    buf.extend_from_slice(&query.question);
+
+    let opt_len = if query.udp_payload_size.is_some() {
+        OPT_LEN
+    } else {
+        0
+    };
+    let sections = [&response.answers, &response.authority, &response.additional];
+    let mut counts = [0u16; 3];
+    'sections: for (section, records) in sections.into_iter().enumerate() {
+        for record in records {
+            let start = buf.len();
+            record.write(&mut buf);
+            if buf.len() + opt_len > max_size {
+                buf.truncate(start);
+                if section < 2 {
+                    flags |= 0x0200;
+                }
+                break 'sections;
+            }
+            counts[section] += 1;
+        }
+    }
+
+    if query.udp_payload_size.is_some() {
+        buf.push(0);
+        buf.extend_from_slice(&TYPE_OPT.to_be_bytes());
+        buf.extend_from_slice(&EDNS_PAYLOAD_SIZE.to_be_bytes());
+        buf.extend_from_slice(&[0; 6]);
+        counts[2] += 1;
+    }
+
+    buf[2..4].copy_from_slice(&flags.to_be_bytes());
+    for (section, count) in counts.iter().enumerate() {
+        buf[6 + section * 2..8 + section * 2].copy_from_slice(&count.to_be_bytes());
+    }
+    buf
+}
+
+// Construct a FORMERR response for a query we could not parse, echoing only its header.
+pub fn build_format_error(request: &[u8]) -> Option<Vec<u8>> {
+    if request.len() < 12 || request[2] & 0x80 != 0 {
+        return None;
+    }
+    let mut buf = Vec::with_capacity(12);
+    buf.extend_from_slice(&request[0..2]);
+    buf.extend_from_slice(&[0x80 | (request[2] & 0x79), RCODE_FORMERR]);
+    buf.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    Some(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::{write_name, RecordData};
+    use std::net::Ipv4Addr;
+
+    // A query for example.com A with RD set, with an OPT record offering the given
+    // payload size if there is one.
+    fn query(payload_size: Option<u16>) -> Vec<u8> {
+        let mut buf = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        write_name(&mut buf, "Example.com");
+        buf.extend_from_slice(&[0, 1, 0, 1]);
+        if let Some(size) = payload_size {
+            buf[11] = 1;
+            buf.push(0);
+            buf.extend_from_slice(&TYPE_OPT.to_be_bytes());
+            buf.extend_from_slice(&size.to_be_bytes());
+            buf.extend_from_slice(&[0; 6]);
+        }
+        buf
+    }
+
+    // Answers for example.com that take 27 bytes each.
+    fn answers(count: u8) -> Response {
+        Response {
+            authoritative: true,
+            answers: (0..count)
+                .map(|i| Record {
+                    name: "example.com".into(),
+                    ttl: 300,
+                    data: RecordData::A(Ipv4Addr::new(192, 0, 2, i)),
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn count(response: &[u8], section: usize) -> u16 {
+        u16::from_be_bytes([response[6 + section * 2], response[7 + section * 2]])
+    }
+
+    #[test]
+    fn parses_queries() {
+        let query = parse_query(&query(None)).unwrap();
+        assert_eq!(query.transaction_id, [0x12, 0x34]);
+        assert_eq!(query.opcode, OPCODE_QUERY);
+        assert!(query.recursion_desired);
+        assert_eq!(query.domain, "example.com");
+        assert_eq!((query.qtype, query.qclass), (1, 1));
+        // The question keeps the case it was asked in
+        assert_eq!(&query.question[1..8], b"Example");
+        assert_eq!(query.udp_payload_size, None);
+        assert_eq!(query.max_udp_response(), MAX_PLAIN_UDP);
+    }
+
+    #[test]
+    fn reads_the_edns_payload_size() {
+        let sized = |size| parse_query(&query(Some(size))).unwrap().max_udp_response();
+        assert_eq!(sized(4096), usize::from(EDNS_PAYLOAD_SIZE));
+        assert_eq!(sized(1000), 1000);
+        assert_eq!(sized(100), MAX_PLAIN_UDP);
+    }
+
+    #[test]
+    fn rejects_malformed_queries() {
+        let mut response = query(None);
+        response[2] |= 0x80;
+        assert_eq!(
+            parse_query(&response).err(),
+            Some("Message is a response, not a query")
+        );
+        let mut two_questions = query(None);
+        two_questions[5] = 2;
+        assert_eq!(
+            parse_query(&two_questions).err(),
+            Some("Query must contain exactly one question")
+        );
+        let truncated = query(None);
+        assert_eq!(
+            parse_query(&truncated[..truncated.len() - 1]).err(),
+            Some("Question section is truncated")
+        );
+        assert_eq!(
+            parse_query(&truncated[..11]).err(),
+            Some("Query is shorter than a DNS header")
+        );
+    }
+
+    #[test]
+    fn builds_responses() {
+        let query = parse_query(&query(None)).unwrap();
+        let response = build_response(&query, &answers(2), MAX_PLAIN_UDP);
+        assert_eq!(response[..2], [0x12, 0x34]);
+        // QR, AA and RD, without TC
+        assert_eq!(response[2..4], [0x85, 0x00]);
+        assert_eq!(count(&response, 0), 2);
+        assert_eq!(response.len(), 12 + query.question.len() + 2 * 27);
+    }
+
+    #[test]
+    fn truncates_at_whole_records() {
+        let query = parse_query(&query(None)).unwrap();
+        let response = build_response(&query, &answers(20), MAX_PLAIN_UDP);
+        assert!(response.len() <= MAX_PLAIN_UDP);
+        assert_eq!(response[2] & 0x02, 0x02);
+        assert_eq!(count(&response, 0), 17);
+        assert_eq!(response.len(), 12 + query.question.len() + 17 * 27);
+
+        // Over TCP everything fits
+        let response = build_response(&query, &answers(20), MAX_TCP);
+        assert_eq!(response[2] & 0x02, 0);
+        assert_eq!(count(&response, 0), 20);
+    }
+
+    #[test]
+    fn leaves_out_additional_records_without_truncating() {
+        let query = parse_query(&query(None)).unwrap();
+        let mut response = answers(1);
+        response.additional = answers(20).answers;
+        let response = build_response(&query, &response, MAX_PLAIN_UDP);
+        assert_eq!(response[2] & 0x02, 0);
+        assert_eq!((count(&response, 0), count(&response, 2)), (1, 16));
+    }
+
+    #[test]
+    fn answers_edns_with_an_opt_record() {
+        let query = parse_query(&query(Some(4096))).unwrap();
+        let max_size = query.max_udp_response();
+        let response = build_response(&query, &answers(60), max_size);
+        assert!(response.len() <= max_size);
+        assert_eq!(response[2] & 0x02, 0x02);
+        // The OPT record always fits, and comes last
+        assert_eq!(count(&response, 2), 1);
+        let opt = &response[response.len() - OPT_LEN..];
+        assert_eq!(opt[..5], [0, 0, 41, 0x04, 0xD0]);
+        let fitted = usize::from(count(&response, 0));
+        assert_eq!(
+            response.len(),
+            12 + query.question.len() + fitted * 27 + OPT_LEN
+        );
+    }
+
+    #[test]
+    fn answers_unparseable_queries_with_formerr() {
+        let error = build_format_error(&query(None)).unwrap();
+        assert_eq!(error[..2], [0x12, 0x34]);
+        assert_eq!(error[2..4], [0x81, RCODE_FORMERR]);
+        assert_eq!(error.len(), 12);
+        assert!(build_format_error(&[0; 11]).is_none());
+    }
+}
@@ -0,0 +1,754 @@
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+// The record types the server knows how to store, parse and encode.
+// Variants use the DNS mnemonics so they read the same as in zone files.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RecordType {
+    A,
+    NS,
+    CNAME,
+    SOA,
+    PTR,
+    MX,
+    TXT,
+    AAAA,
+    SVCB,
+    HTTPS,
+    CAA,
+}
+
+impl RecordType {
+    pub fn from_u16(value: u16) -> Option<RecordType> {
+        match value {
+            1 => Some(RecordType::A),
+            2 => Some(RecordType::NS),
+            5 => Some(RecordType::CNAME),
+            6 => Some(RecordType::SOA),
+            12 => Some(RecordType::PTR),
+            15 => Some(RecordType::MX),
+            16 => Some(RecordType::TXT),
+            28 => Some(RecordType::AAAA),
+            64 => Some(RecordType::SVCB),
+            65 => Some(RecordType::HTTPS),
+            257 => Some(RecordType::CAA),
+            _ => None,
+        }
+    }
+
+    pub fn to_u16(self) -> u16 {
+        match self {
+            RecordType::A => 1,
+            RecordType::NS => 2,
+            RecordType::CNAME => 5,
+            RecordType::SOA => 6,
+            RecordType::PTR => 12,
+            RecordType::MX => 15,
+            RecordType::TXT => 16,
+            RecordType::AAAA => 28,
+            RecordType::SVCB => 64,
+            RecordType::HTTPS => 65,
+            RecordType::CAA => 257,
+        }
+    }
+
+    // Parse the mnemonic used in zone files, e.g. "AAAA".
+    pub fn from_mnemonic(value: &str) -> Option<RecordType> {
+        match value.to_ascii_uppercase().as_str() {
+            "A" => Some(RecordType::A),
+            "NS" => Some(RecordType::NS),
+            "CNAME" => Some(RecordType::CNAME),
+            "SOA" => Some(RecordType::SOA),
+            "PTR" => Some(RecordType::PTR),
+            "MX" => Some(RecordType::MX),
+            "TXT" => Some(RecordType::TXT),
+            "AAAA" => Some(RecordType::AAAA),
+            "SVCB" => Some(RecordType::SVCB),
+            "HTTPS" => Some(RecordType::HTTPS),
+            "CAA" => Some(RecordType::CAA),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RecordType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Soa {
+    pub mname: String,
+    pub rname: String,
+    pub serial: u32,
+    pub refresh: u32,
+    pub retry: u32,
+    pub expire: u32,
+    pub minimum: u32,
+}
+
+// The well-known SvcParamKeys from RFC 9460 section 14.3.2.
+pub const SVC_PARAM_MANDATORY: u16 = 0;
+pub const SVC_PARAM_ALPN: u16 = 1;
+pub const SVC_PARAM_NO_DEFAULT_ALPN: u16 = 2;
+pub const SVC_PARAM_PORT: u16 = 3;
+pub const SVC_PARAM_IPV4HINT: u16 = 4;
+pub const SVC_PARAM_ECH: u16 = 5;
+pub const SVC_PARAM_IPV6HINT: u16 = 6;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SvcParam {
+    Mandatory(Vec<u16>),
+    Alpn(Vec<String>),
+    NoDefaultAlpn,
+    Port(u16),
+    Ipv4Hint(Vec<Ipv4Addr>),
+    Ech(Vec<u8>),
+    Ipv6Hint(Vec<Ipv6Addr>),
+    // Any other key, written in zone files as keyNNNNN=value.
+    Unknown(u16, Vec<u8>),
+}
+
+impl SvcParam {
+    pub fn key(&self) -> u16 {
+        match self {
+            SvcParam::Mandatory(_) => SVC_PARAM_MANDATORY,
+            SvcParam::Alpn(_) => SVC_PARAM_ALPN,
+            SvcParam::NoDefaultAlpn => SVC_PARAM_NO_DEFAULT_ALPN,
+            SvcParam::Port(_) => SVC_PARAM_PORT,
+            SvcParam::Ipv4Hint(_) => SVC_PARAM_IPV4HINT,
+            SvcParam::Ech(_) => SVC_PARAM_ECH,
+            SvcParam::Ipv6Hint(_) => SVC_PARAM_IPV6HINT,
+            SvcParam::Unknown(key, _) => *key,
+        }
+    }
+
+    // Append the SvcParamValue (without key and length) to the buffer.
+    fn write_value(&self, buf: &mut Vec<u8>) {
+        match self {
+            SvcParam::Mandatory(keys) => {
+                for key in keys {
+                    buf.extend_from_slice(&key.to_be_bytes());
+                }
+            }
+            SvcParam::Alpn(ids) => {
+                for id in ids {
+                    buf.push(id.len() as u8);
+                    buf.extend_from_slice(id.as_bytes());
+                }
+            }
+            SvcParam::NoDefaultAlpn => {}
+            SvcParam::Port(port) => buf.extend_from_slice(&port.to_be_bytes()),
+            SvcParam::Ipv4Hint(ips) => {
+                for ip in ips {
+                    buf.extend_from_slice(&ip.octets());
+                }
+            }
+            SvcParam::Ipv6Hint(ips) => {
+                for ip in ips {
+                    buf.extend_from_slice(&ip.octets());
+                }
+            }
+            SvcParam::Ech(value) | SvcParam::Unknown(_, value) => buf.extend_from_slice(value),
+        }
+    }
+}
+
+// The RDATA shared by SVCB and HTTPS records (RFC 9460). A priority of 0 is
+// AliasMode, anything else is ServiceMode. Params are kept sorted by key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Svcb {
+    pub priority: u16,
+    pub target: String,
+    pub params: Vec<SvcParam>,
+}
+
+impl Svcb {
+    fn write(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.priority.to_be_bytes());
+        write_name(buf, &self.target);
+        for param in &self.params {
+            let mut value = Vec::new();
+            param.write_value(&mut value);
+            buf.extend_from_slice(&param.key().to_be_bytes());
+            buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            buf.extend_from_slice(&value);
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordData {
+    A(Ipv4Addr),
+    NS(String),
+    CNAME(String),
+    SOA(Soa),
+    PTR(String),
+    MX {
+        preference: u16,
+        exchange: String,
+    },
+    TXT(Vec<String>),
+    AAAA(Ipv6Addr),
+    SVCB(Svcb),
+    HTTPS(Svcb),
+    CAA {
+        flags: u8,
+        tag: String,
+        value: Vec<u8>,
+    },
+}
+
+impl RecordData {
+    pub fn record_type(&self) -> RecordType {
+        match self {
+            RecordData::A(_) => RecordType::A,
+            RecordData::NS(_) => RecordType::NS,
+            RecordData::CNAME(_) => RecordType::CNAME,
+            RecordData::SOA(_) => RecordType::SOA,
+            RecordData::PTR(_) => RecordType::PTR,
+            RecordData::MX { .. } => RecordType::MX,
+            RecordData::TXT(_) => RecordType::TXT,
+            RecordData::AAAA(_) => RecordType::AAAA,
+            RecordData::SVCB(_) => RecordType::SVCB,
+            RecordData::HTTPS(_) => RecordType::HTTPS,
+            RecordData::CAA { .. } => RecordType::CAA,
+        }
+    }
+
+    // Append the RDATA (without its length prefix) to the buffer.
+    fn write(&self, buf: &mut Vec<u8>) {
+        match self {
+            RecordData::A(ip) => buf.extend_from_slice(&ip.octets()),
+            RecordData::AAAA(ip) => buf.extend_from_slice(&ip.octets()),
+            RecordData::NS(name) | RecordData::CNAME(name) | RecordData::PTR(name) => {
+                write_name(buf, name)
+            }
+            RecordData::MX {
+                preference,
+                exchange,
+            } => {
+                buf.extend_from_slice(&preference.to_be_bytes());
+                write_name(buf, exchange);
+            }
+            RecordData::TXT(strings) => {
+                for s in strings {
+                    buf.push(s.len() as u8);
+                    buf.extend_from_slice(s.as_bytes());
+                }
+            }
+            RecordData::SOA(soa) => {
+                write_name(buf, &soa.mname);
+                write_name(buf, &soa.rname);
+                for value in [soa.serial, soa.refresh, soa.retry, soa.expire, soa.minimum] {
+                    buf.extend_from_slice(&value.to_be_bytes());
+                }
+            }
+            RecordData::SVCB(svcb) | RecordData::HTTPS(svcb) => svcb.write(buf),
+            RecordData::CAA { flags, tag, value } => {
+                buf.push(*flags);
+                buf.push(tag.len() as u8);
+                buf.extend_from_slice(tag.as_bytes());
+                buf.extend_from_slice(value);
+            }
+        }
+    }
+}
+
+// A single resource record. Names are stored lowercase without the trailing dot,
+// and the class is always IN.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub name: String,
+    pub ttl: u32,
+    pub data: RecordData,
+}
+
+impl Record {
+    pub fn record_type(&self) -> RecordType {
+        self.data.record_type()
+    }
+
+    // Append the full resource record in wire format to the buffer.
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        write_name(buf, &self.name);
+        buf.extend_from_slice(&self.record_type().to_u16().to_be_bytes());
+        buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+        buf.extend_from_slice(&self.ttl.to_be_bytes());
+
+        // Reserve space for RDLENGTH and fill it in once the RDATA is written.
+        let length_at = buf.len();
+        buf.extend_from_slice(&[0x00, 0x00]);
+        self.data.write(buf);
+        let rdlength = (buf.len() - length_at - 2) as u16;
+        buf[length_at..length_at + 2].copy_from_slice(&rdlength.to_be_bytes());
+    }
+}
+
+pub const CLASS_IN: u16 = 1;
+
+// Encode a domain name as a sequence of length-prefixed labels.
+pub fn write_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0); // end of domain name
+}
+
+// Decode a possibly compressed domain name starting at `start`, returning the name and
+// the position just after it in the original buffer.
+pub fn read_name(buf: &[u8], start: usize) -> Result<(String, usize), &'static str> {
+    let mut position = start;
+    let mut domain_name = String::new();
+    // Where parsing continues once the first compression pointer has been followed.
+    let mut end = None;
+    let mut jumps = 0;
+
+    loop {
+        let length = *buf
+            .get(position)
+            .ok_or("Domain name runs past the end of the message")?;
+        match length {
+            0 => {
+                position += 1;
+                break;
+            }
+            length if length & 0xC0 == 0xC0 => {
+                let low = *buf
+                    .get(position + 1)
+                    .ok_or("Truncated compression pointer")?;
+                jumps += 1;
+                if jumps > 16 {
+                    return Err("Too many compression pointers in domain name");
+                }
+                end.get_or_insert(position + 2);
+                position = (usize::from(length & 0x3F) << 8) | usize::from(low);
+            }
+            length if length > 63 => return Err("Invalid label length in domain name"),
+            length => {
+                let length = usize::from(length);
+                position += 1; // move past the length byte
+                let label = buf
+                    .get(position..position + length)
+                    .ok_or("Invalid domain name in message")?;
+                let label =
+                    std::str::from_utf8(label).map_err(|_| "Invalid UTF-8 label in domain name")?;
+                if !domain_name.is_empty() {
+                    domain_name.push('.');
+                }
+                domain_name.push_str(label);
+                position += length; // move to the next label
+            }
+        }
+    }
+
+    Ok((domain_name.to_ascii_lowercase(), end.unwrap_or(position)))
+}
+
+// A resource record as found on the wire, before its RDATA is decoded. Used for the
+// sections of UPDATE messages, where class and RDLENGTH carry meaning of their own.
+pub struct RawRecord {
+    pub name: String,
+    pub record_type: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub rdata_start: usize,
+    pub rdata_end: usize,
+}
+
+impl RawRecord {
+    // Read the resource record starting at `start`, returning it and the position after it.
+    pub fn read(buf: &[u8], start: usize) -> Result<(RawRecord, usize), &'static str> {
+        let (name, position) = read_name(buf, start)?;
+        let fixed = buf
+            .get(position..position + 10)
+            .ok_or("Resource record is truncated")?;
+        let rdlength = usize::from(u16::from_be_bytes([fixed[8], fixed[9]]));
+        let rdata_start = position + 10;
+        let rdata_end = rdata_start + rdlength;
+        if rdata_end > buf.len() {
+            return Err("Resource record data is truncated");
+        }
+
+        let record = RawRecord {
+            name,
+            record_type: u16::from_be_bytes([fixed[0], fixed[1]]),
+            class: u16::from_be_bytes([fixed[2], fixed[3]]),
+            ttl: u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]),
+            rdata_start,
+            rdata_end,
+        };
+        Ok((record, rdata_end))
+    }
+
+    pub fn rdlength(&self) -> usize {
+        self.rdata_end - self.rdata_start
+    }
+
+    // Decode the RDATA into a record, using the full message so compressed names resolve.
+    pub fn to_record(&self, buf: &[u8]) -> Result<Record, &'static str> {
+        let record_type =
+            RecordType::from_u16(self.record_type).ok_or("Unsupported record type")?;
+        let data = RecordData::read(record_type, buf, self.rdata_start, self.rdata_end)?;
+        Ok(Record {
+            name: self.name.clone(),
+            ttl: self.ttl,
+            data,
+        })
+    }
+}
+
+impl RecordData {
+    // Decode the RDATA of a record of the given type from buf[start..end].
+    pub fn read(
+        record_type: RecordType,
+        buf: &[u8],
+        start: usize,
+        end: usize,
+    ) -> Result<RecordData, &'static str> {
+        let rdata = &buf[start..end];
+        let u16_at = |offset: usize| -> Result<u16, &'static str> {
+            rdata
+                .get(offset..offset + 2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                .ok_or("RDATA is truncated")
+        };
+        // Read a name inside the RDATA and make sure it does not run past the record.
+        let name_at = |position: usize| -> Result<(String, usize), &'static str> {
+            let (name, next) = read_name(buf, position)?;
+            if next > end {
+                return Err("Domain name runs past the end of the RDATA");
+            }
+            Ok((name, next))
+        };
+
+        let data = match record_type {
+            RecordType::A => {
+                let octets: [u8; 4] = rdata.try_into().map_err(|_| "Invalid A record")?;
+                RecordData::A(Ipv4Addr::from(octets))
+            }
+            RecordType::AAAA => {
+                let octets: [u8; 16] = rdata.try_into().map_err(|_| "Invalid AAAA record")?;
+                RecordData::AAAA(Ipv6Addr::from(octets))
+            }
+            RecordType::NS => RecordData::NS(name_at(start)?.0),
+            RecordType::CNAME => RecordData::CNAME(name_at(start)?.0),
+            RecordType::PTR => RecordData::PTR(name_at(start)?.0),
+            RecordType::MX => RecordData::MX {
+                preference: u16_at(0)?,
+                exchange: name_at(start + 2)?.0,
+            },
+            RecordType::TXT => {
+                let mut strings = Vec::new();
+                let mut position = 0;
+                while position < rdata.len() {
+                    let length = usize::from(rdata[position]);
+                    let text = rdata
+                        .get(position + 1..position + 1 + length)
+                        .ok_or("TXT string is truncated")?;
+                    strings.push(String::from_utf8_lossy(text).into_owned());
+                    position += 1 + length;
+                }
+                RecordData::TXT(strings)
+            }
+            RecordType::SOA => {
+                let (mname, next) = name_at(start)?;
+                let (rname, next) = name_at(next)?;
+                let fields = buf.get(next..end).ok_or("SOA record is truncated")?;
+                if fields.len() != 20 {
+                    return Err("Invalid SOA record");
+                }
+                let field =
+                    |i: usize| u32::from_be_bytes(fields[i * 4..i * 4 + 4].try_into().unwrap());
+                RecordData::SOA(Soa {
+                    mname,
+                    rname,
+                    serial: field(0),
+                    refresh: field(1),
+                    retry: field(2),
+                    expire: field(3),
+                    minimum: field(4),
+                })
+            }
+            RecordType::SVCB => RecordData::SVCB(Svcb::read(buf, start, end)?),
+            RecordType::HTTPS => RecordData::HTTPS(Svcb::read(buf, start, end)?),
+            RecordType::CAA => {
+                let flags = *rdata.first().ok_or("CAA record is truncated")?;
+                let tag_length = usize::from(*rdata.get(1).ok_or("CAA record is truncated")?);
+                let tag = rdata.get(2..2 + tag_length).ok_or("CAA tag is truncated")?;
+                RecordData::CAA {
+                    flags,
+                    tag: String::from_utf8_lossy(tag).to_ascii_lowercase(),
+                    value: rdata[2 + tag_length..].to_vec(),
+                }
+            }
+        };
+
+        Ok(data)
+    }
+}
+
+impl Svcb {
+    fn read(buf: &[u8], start: usize, end: usize) -> Result<Svcb, &'static str> {
+        let priority = buf
+            .get(start..start + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or("SVCB record is truncated")?;
+        // The target name is never compressed (RFC 9460 section 2.2).
+        let (target, mut position) = read_name(buf, start + 2)?;
+
+        let mut params = Vec::new();
+        while position < end {
+            let header = buf
+                .get(position..position + 4)
+                .ok_or("SvcParam is truncated")?;
+            let key = u16::from_be_bytes([header[0], header[1]]);
+            let length = usize::from(u16::from_be_bytes([header[2], header[3]]));
+            let value = buf
+                .get(position + 4..position + 4 + length)
+                .filter(|_| position + 4 + length <= end)
+                .ok_or("SvcParam value is truncated")?;
+            params.push(SvcParam::read(key, value)?);
+            position += 4 + length;
+        }
+
+        Ok(Svcb {
+            priority,
+            target,
+            params,
+        })
+    }
+}
+
+impl SvcParam {
+    fn read(key: u16, value: &[u8]) -> Result<SvcParam, &'static str> {
+        let param = match key {
+            SVC_PARAM_MANDATORY => SvcParam::Mandatory(
+                value
+                    .chunks_exact(2)
+                    .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                    .collect(),
+            ),
+            SVC_PARAM_ALPN => {
+                let mut ids = Vec::new();
+                let mut position = 0;
+                while position < value.len() {
+                    let length = usize::from(value[position]);
+                    let id = value
+                        .get(position + 1..position + 1 + length)
+                        .ok_or("alpn SvcParam is truncated")?;
+                    ids.push(String::from_utf8_lossy(id).into_owned());
+                    position += 1 + length;
+                }
+                SvcParam::Alpn(ids)
+            }
+            SVC_PARAM_NO_DEFAULT_ALPN => SvcParam::NoDefaultAlpn,
+            SVC_PARAM_PORT => {
+                let port: [u8; 2] = value.try_into().map_err(|_| "Invalid port SvcParam")?;
+                SvcParam::Port(u16::from_be_bytes(port))
+            }
+            SVC_PARAM_IPV4HINT => SvcParam::Ipv4Hint(
+                value
+                    .chunks_exact(4)
+                    .map(|b| Ipv4Addr::new(b[0], b[1], b[2], b[3]))
+                    .collect(),
+            ),
+            SVC_PARAM_IPV6HINT => SvcParam::Ipv6Hint(
+                value
+                    .chunks_exact(16)
+                    .map(|b| Ipv6Addr::from(<[u8; 16]>::try_from(b).unwrap()))
+                    .collect(),
+            ),
+            SVC_PARAM_ECH => SvcParam::Ech(value.to_vec()),
+            _ => SvcParam::Unknown(key, value.to_vec()),
+        };
+        Ok(param)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Write a record and read it back the way a message is read.
+    fn round_trip(record: &Record) -> Record {
+        let mut buf = Vec::new();
+        record.write(&mut buf);
+        let (raw, next) = RawRecord::read(&buf, 0).unwrap();
+        assert_eq!(next, buf.len());
+        assert_eq!(raw.class, CLASS_IN);
+        raw.to_record(&buf).unwrap()
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let data = [
+            RecordData::A(Ipv4Addr::new(192, 0, 2, 1)),
+            RecordData::AAAA("2001:db8::1".parse().unwrap()),
+            RecordData::NS("ns1.example.com".into()),
+            RecordData::CNAME("www.example.com".into()),
+            RecordData::PTR("host.example.com".into()),
+            RecordData::MX {
+                preference: 10,
+                exchange: "mail.example.com".into(),
+            },
+            RecordData::TXT(vec!["hello world".into(), String::new()]),
+            RecordData::SOA(Soa {
+                mname: "ns1.example.com".into(),
+                rname: "admin.example.com".into(),
+                serial: 2026101801,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 300,
+            }),
+        ];
+        for data in data {
+            let record = Record {
+                name: "example.com".into(),
+                ttl: 3600,
+                data,
+            };
+            assert_eq!(round_trip(&record), record);
+        }
+    }
+
+    #[test]
+    fn reads_compressed_names() {
+        // example.com at 0, then www pointing back at it, then a pointer to www
+        let mut buf = Vec::new();
+        write_name(&mut buf, "Example.COM");
+        buf.extend_from_slice(&[3, b'w', b'w', b'w', 0xC0, 0x00]);
+        buf.extend_from_slice(&[0xC0, 13]);
+        assert_eq!(read_name(&buf, 0).unwrap(), ("example.com".into(), 13));
+        assert_eq!(read_name(&buf, 13).unwrap(), ("www.example.com".into(), 19));
+        assert_eq!(read_name(&buf, 19).unwrap(), ("www.example.com".into(), 21));
+    }
+
+    #[test]
+    fn rejects_malformed_names() {
+        // A pointer to itself
+        assert_eq!(
+            read_name(&[0xC0, 0x00], 0),
+            Err("Too many compression pointers in domain name")
+        );
+        assert_eq!(
+            read_name(&[64, b'a'], 0),
+            Err("Invalid label length in domain name")
+        );
+        assert_eq!(
+            read_name(&[3, b'w', b'w'], 0),
+            Err("Invalid domain name in message")
+        );
+        assert_eq!(
+            read_name(&[3, b'w', b'w', b'w'], 0),
+            Err("Domain name runs past the end of the message")
+        );
+        assert_eq!(read_name(&[0xC0], 0), Err("Truncated compression pointer"));
+    }
+
+    #[test]
+    fn rejects_malformed_records() {
+        let mut buf = Vec::new();
+        Record {
+            name: "example.com".into(),
+            ttl: 300,
+            data: RecordData::CNAME("www.example.com".into()),
+        }
+        .write(&mut buf);
+
+        // Cut short, the RDATA runs past the end of the message
+        assert_eq!(
+            RawRecord::read(&buf[..buf.len() - 1], 0).err(),
+            Some("Resource record data is truncated")
+        );
+        assert_eq!(
+            RawRecord::read(&buf[..16], 0).err(),
+            Some("Resource record is truncated")
+        );
+
+        // With RDLENGTH one short, the name in the RDATA runs past it
+        let rdlength_at = 13 + 8;
+        buf[rdlength_at + 1] -= 1;
+        let (raw, _) = RawRecord::read(&buf, 0).unwrap();
+        assert_eq!(
+            raw.to_record(&buf),
+            Err("Domain name runs past the end of the RDATA")
+        );
+
+        // An A record with the wrong length
+        let mut buf = Vec::new();
+        write_name(&mut buf, "example.com");
+        buf.extend_from_slice(&[0, 1, 0, 1, 0, 0, 1, 44, 0, 3, 192, 0, 2]);
+        let (raw, _) = RawRecord::read(&buf, 0).unwrap();
+        assert_eq!(raw.to_record(&buf), Err("Invalid A record"));
+    }
+
+    #[test]
+    fn service_bindings_and_caa_round_trip() {
+        let svcb = Svcb {
+            priority: 1,
+            target: "svc.example.com".into(),
+            params: vec![
+                SvcParam::Mandatory(vec![SVC_PARAM_ALPN]),
+                SvcParam::Alpn(vec!["h2".into(), "h3".into()]),
+                SvcParam::NoDefaultAlpn,
+                SvcParam::Port(853),
+                SvcParam::Ipv4Hint(vec![Ipv4Addr::new(192, 0, 2, 1)]),
+                SvcParam::Ech(vec![1, 2, 3]),
+                SvcParam::Ipv6Hint(vec!["2001:db8::1".parse().unwrap()]),
+                SvcParam::Unknown(65000, b"custom".to_vec()),
+            ],
+        };
+        let data = [
+            RecordData::SVCB(svcb.clone()),
+            RecordData::HTTPS(Svcb {
+                priority: 0,
+                target: String::new(),
+                params: Vec::new(),
+            }),
+            RecordData::CAA {
+                flags: 0,
+                tag: "issue".into(),
+                value: b"letsencrypt.org".to_vec(),
+            },
+        ];
+        for data in data {
+            let record = Record {
+                name: "example.com".into(),
+                ttl: 3600,
+                data,
+            };
+            assert_eq!(round_trip(&record), record);
+        }
+    }
+
+    #[test]
+    fn rejects_svc_params_past_the_rdata() {
+        let record = Record {
+            name: "example.com".into(),
+            ttl: 3600,
+            data: RecordData::HTTPS(Svcb {
+                priority: 1,
+                target: String::new(),
+                params: vec![SvcParam::Port(443)],
+            }),
+        };
+        let mut buf = Vec::new();
+        record.write(&mut buf);
+        // Claim a longer port value than the RDATA holds
+        let length_at = buf.len() - 3;
+        buf[length_at] = 3;
+        let (raw, _) = RawRecord::read(&buf, 0).unwrap();
+        assert_eq!(raw.to_record(&buf), Err("SvcParam value is truncated"));
+
+        // A port of the wrong size
+        buf[length_at] = 1;
+        let (raw, _) = RawRecord::read(&buf, 0).unwrap();
+        assert_eq!(raw.to_record(&buf), Err("Invalid port SvcParam"));
+    }
+}
@@ -0,0 +1,472 @@
+use crate::record::{RawRecord, Record};
+use crate::zone::{Change, Zone};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Error, Write};
+use std::path::{Path, PathBuf};
+
+// Identifies a snapshot file and the version of its layout.
+const SNAPSHOT_MAGIC: &[u8; 8] = b"DNSZSNP1";
+
+const OP_DELETE: u8 = 0;
+const OP_ADD: u8 = 1;
+
+// The on-disk state of one zone: a snapshot of every record at some serial, and a
+// write-ahead journal of the changes made since. Every change is appended and synced
+// to the journal before it is acknowledged, and compaction folds the journal back into
+// a fresh snapshot.
+//
+// Snapshot layout: magic, u32 record count, the records in wire format, u32 CRC-32.
+// Journal entry layout: u32 payload length, u32 CRC-32 of the payload, then the payload
+// of u32 serial, u16 change count and, per change, a u8 op followed by the record.
+pub struct ZoneStore {
+    snapshot_path: PathBuf,
+    journal: File,
+    // Journal entries written since the last snapshot.
+    pending: usize,
+}
+
+impl ZoneStore {
+    // Open the store for a zone and recover the zone from its snapshot and journal. The
+    // zone file only seeds the store, unless it has been edited to a newer serial than
+    // the stored zone, in which case it replaces the stored state.
+    pub fn open(dir: &Path, file_zone: Zone) -> io::Result<(Zone, ZoneStore)> {
+        fs::create_dir_all(dir)?;
+        let file_name = if file_zone.origin.is_empty() {
+            "root".to_string()
+        } else {
+            file_zone.origin.clone()
+        };
+        let snapshot_path = dir.join(format!("{}.snapshot", file_name));
+        let journal_path = dir.join(format!("{}.journal", file_name));
+
+        let stored = if snapshot_path.exists() {
+            let mut zone = read_snapshot(&snapshot_path, &file_zone.origin)?;
+            let replayed = replay_journal(&journal_path, &mut zone)?;
+            println!(
+                "Recovered zone {} at serial {} ({} journal entries replayed)",
+                zone.origin,
+                zone.serial(),
+                replayed
+            );
+            Some((zone, replayed))
+        } else {
+            None
+        };
+
+        let (zone, pending, reseed) = match stored {
+            Some((zone, _)) if serial_gt(file_zone.serial(), zone.serial()) => {
+                println!(
+                    "Zone file for {} has serial {}, newer than stored serial {}; reloading",
+                    zone.origin,
+                    file_zone.serial(),
+                    zone.serial()
+                );
+                (file_zone, 0, true)
+            }
+            Some((zone, replayed)) => (zone, replayed, false),
+            None => (file_zone, 0, true),
+        };
+
+        let journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&journal_path)?;
+        let mut store = ZoneStore {
+            snapshot_path,
+            journal,
+            pending,
+        };
+        if reseed {
+            store.compact(&zone)?;
+        }
+
+        Ok((zone, store))
+    }
+
+    // Append a set of changes that moved the zone to `serial`, and make sure they have
+    // reached the disk before returning.
+    pub fn append(&mut self, serial: u32, changes: &[Change]) -> io::Result<()> {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&serial.to_be_bytes());
+        payload.extend_from_slice(&(changes.len() as u16).to_be_bytes());
+        for change in changes {
+            let (op, record) = match change {
+                Change::Add(record) => (OP_ADD, record),
+                Change::Delete(record) => (OP_DELETE, record),
+            };
+            payload.push(op);
+            record.write(&mut payload);
+        }
+
+        let mut entry = Vec::with_capacity(payload.len() + 8);
+        entry.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        entry.extend_from_slice(&crc32(&payload).to_be_bytes());
+        entry.extend_from_slice(&payload);
+
+        self.journal.write_all(&entry)?;
+        self.journal.sync_data()?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    // The number of journal entries that compaction would fold into the snapshot.
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    // Write the zone as a new snapshot and empty the journal. The snapshot is written to
+    // a temporary file and renamed into place, so a crash leaves either the old snapshot
+    // and journal or the new snapshot; journal entries already in the snapshot are
+    // skipped by serial on replay.
+    pub fn compact(&mut self, zone: &Zone) -> io::Result<()> {
+        let mut contents = Vec::new();
+        contents.extend_from_slice(SNAPSHOT_MAGIC);
+        let records: Vec<&Record> = zone.all_records().collect();
+        contents.extend_from_slice(&(records.len() as u32).to_be_bytes());
+        for record in records {
+            record.write(&mut contents);
+        }
+        let checksum = crc32(&contents);
+        contents.extend_from_slice(&checksum.to_be_bytes());
+
+        let temp_path = self.snapshot_path.with_extension("snapshot.tmp");
+        let mut temp = File::create(&temp_path)?;
+        temp.write_all(&contents)?;
+        temp.sync_all()?;
+        fs::rename(&temp_path, &self.snapshot_path)?;
+        // The rename only survives a crash once the directory is synced, and until then
+        // the journal is still needed
+        let dir = self
+            .snapshot_path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        File::open(dir)?.sync_all()?;
+
+        self.journal.set_len(0)?;
+        self.journal.sync_all()?;
+        self.pending = 0;
+        println!(
+            "Compacted zone {} at serial {} into {}",
+            zone.origin,
+            zone.serial(),
+            self.snapshot_path.display()
+        );
+        Ok(())
+    }
+}
+
+fn invalid_data(message: String) -> Error {
+    Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_snapshot(path: &Path, origin: &str) -> io::Result<Zone> {
+    let contents = fs::read(path)?;
+    if contents.len() < SNAPSHOT_MAGIC.len() + 8 || &contents[..8] != SNAPSHOT_MAGIC {
+        return Err(invalid_data(format!(
+            "{} is not a zone snapshot",
+            path.display()
+        )));
+    }
+    let (body, checksum) = contents.split_at(contents.len() - 4);
+    if crc32(body).to_be_bytes() != checksum {
+        return Err(invalid_data(format!(
+            "Checksum mismatch in {}",
+            path.display()
+        )));
+    }
+
+    let count = u32::from_be_bytes(body[8..12].try_into().unwrap());
+    let mut position = 12;
+    let mut records = Vec::new();
+    for _ in 0..count {
+        let (raw, next) = RawRecord::read(body, position).map_err(|e| invalid_data(e.into()))?;
+        records.push(raw.to_record(body).map_err(|e| invalid_data(e.into()))?);
+        position = next;
+    }
+
+    Zone::from_records(origin, records)
+}
+
+// Apply the journal entries newer than the zone's serial. A torn or corrupt entry can
+// only come from a crash while appending, so the journal is truncated there.
+fn replay_journal(path: &Path, zone: &mut Zone) -> io::Result<usize> {
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let mut position = 0;
+    let mut replayed = 0;
+    while position < contents.len() {
+        let entry = match read_journal_entry(&contents, position) {
+            Ok(entry) => entry,
+            Err(e) => {
+                eprintln!(
+                    "Truncating journal {} at byte {}: {}",
+                    path.display(),
+                    position,
+                    e
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(path)?
+                    .set_len(position as u64)?;
+                break;
+            }
+        };
+        position = entry.next;
+
+        if !serial_gt(entry.serial, zone.serial()) {
+            continue;
+        }
+        for change in &entry.changes {
+            zone.apply(change);
+        }
+        replayed += 1;
+    }
+
+    Ok(replayed)
+}
+
+struct JournalEntry {
+    serial: u32,
+    changes: Vec<Change>,
+    next: usize,
+}
+
+fn read_journal_entry(contents: &[u8], position: usize) -> Result<JournalEntry, &'static str> {
+    let header = contents
+        .get(position..position + 8)
+        .ok_or("Entry header is truncated")?;
+    let length = u32::from_be_bytes(header[0..4].try_into().unwrap()) as usize;
+    let checksum = u32::from_be_bytes(header[4..8].try_into().unwrap());
+    let payload = contents
+        .get(position + 8..position + 8 + length)
+        .ok_or("Entry is truncated")?;
+    if crc32(payload) != checksum || payload.len() < 6 {
+        return Err("Entry checksum mismatch");
+    }
+
+    let serial = u32::from_be_bytes(payload[0..4].try_into().unwrap());
+    let count = u16::from_be_bytes([payload[4], payload[5]]);
+    let mut offset = 6;
+    let mut changes = Vec::new();
+    for _ in 0..count {
+        let op = *payload.get(offset).ok_or("Change is truncated")?;
+        let (raw, next) = RawRecord::read(payload, offset + 1)?;
+        let record = raw.to_record(payload)?;
+        changes.push(match op {
+            OP_ADD => Change::Add(record),
+            OP_DELETE => Change::Delete(record),
+            _ => return Err("Unknown change op"),
+        });
+        offset = next;
+    }
+
+    Ok(JournalEntry {
+        serial,
+        changes,
+        next: position + 8 + length,
+    })
+}
+
+// Whether serial `a` is greater than `b` under RFC 1982 serial number arithmetic.
+pub fn serial_gt(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < 0x8000_0000
+}
+
+// CRC-32 (IEEE) used to detect torn and corrupt writes.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::RecordData;
+    use std::net::Ipv4Addr;
+
+    const ZONE: &str = "@ 300 IN SOA ns1 admin 1 7200 3600 1209600 300\n";
+
+    // A store directory of its own for each test, emptied first.
+    fn store_dir(test: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("dns-server-store-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn file_zone() -> Zone {
+        Zone::parse("example.com", ZONE).unwrap()
+    }
+
+    fn host(number: u8) -> Record {
+        Record {
+            name: format!("host{}.example.com", number),
+            ttl: 300,
+            data: RecordData::A(Ipv4Addr::new(192, 0, 2, number)),
+        }
+    }
+
+    // Add a host to the zone with the next serial, as an UPDATE would, returning the
+    // size of the journal entry written.
+    fn add_host(zone: &mut Zone, store: &mut ZoneStore, number: u8) -> u64 {
+        let before = store.journal.metadata().unwrap().len();
+        let mut changes = zone.serial_increment();
+        changes.push(Change::Add(host(number)));
+        for change in &changes {
+            zone.apply(change);
+        }
+        store.append(zone.serial(), &changes).unwrap();
+        store.journal.metadata().unwrap().len() - before
+    }
+
+    fn journal_path(dir: &Path) -> PathBuf {
+        dir.join("example.com.journal")
+    }
+
+    fn has_host(zone: &Zone, number: u8) -> bool {
+        zone.records_at(&host(number).name).contains(&host(number))
+    }
+
+    #[test]
+    fn recovers_from_snapshot_and_journal() {
+        let dir = store_dir("recover");
+        let (mut zone, mut store) = ZoneStore::open(&dir, file_zone()).unwrap();
+        add_host(&mut zone, &mut store, 1);
+        add_host(&mut zone, &mut store, 2);
+        assert_eq!(store.pending(), 2);
+        drop(store);
+
+        let (zone, store) = ZoneStore::open(&dir, file_zone()).unwrap();
+        assert_eq!(zone.serial(), 3);
+        assert!(has_host(&zone, 1) && has_host(&zone, 2));
+        assert_eq!(store.pending(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn skips_journal_entries_already_compacted() {
+        let dir = store_dir("compacted");
+        let (mut zone, mut store) = ZoneStore::open(&dir, file_zone()).unwrap();
+        add_host(&mut zone, &mut store, 1);
+        let journal = fs::read(journal_path(&dir)).unwrap();
+        store.compact(&zone).unwrap();
+        assert_eq!(fs::metadata(journal_path(&dir)).unwrap().len(), 0);
+        drop(store);
+
+        // As if the journal had not been emptied before a crash
+        fs::write(journal_path(&dir), journal).unwrap();
+        let (zone, store) = ZoneStore::open(&dir, file_zone()).unwrap();
+        assert_eq!(zone.serial(), 2);
+        assert!(has_host(&zone, 1));
+        assert_eq!(store.pending(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncates_a_torn_tail_entry() {
+        let dir = store_dir("torn");
+        let (mut zone, mut store) = ZoneStore::open(&dir, file_zone()).unwrap();
+        let first = add_host(&mut zone, &mut store, 1);
+        let second = add_host(&mut zone, &mut store, 2);
+        drop(store);
+
+        // Only part of the second entry reached the disk
+        let journal = OpenOptions::new()
+            .write(true)
+            .open(journal_path(&dir))
+            .unwrap();
+        journal.set_len(first + second / 2).unwrap();
+        drop(journal);
+
+        let (zone, _) = ZoneStore::open(&dir, file_zone()).unwrap();
+        assert_eq!(zone.serial(), 2);
+        assert!(has_host(&zone, 1) && !has_host(&zone, 2));
+        assert_eq!(fs::metadata(journal_path(&dir)).unwrap().len(), first);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncates_at_a_checksum_mismatch() {
+        let dir = store_dir("checksum");
+        let (mut zone, mut store) = ZoneStore::open(&dir, file_zone()).unwrap();
+        let first = add_host(&mut zone, &mut store, 1);
+        add_host(&mut zone, &mut store, 2);
+        add_host(&mut zone, &mut store, 3);
+        drop(store);
+
+        // Corrupt the last byte of the second entry's payload; the third is dropped
+        // with it, as it may depend on the second. The two are the same size.
+        let mut journal = fs::read(journal_path(&dir)).unwrap();
+        let second_end = journal.len() - (journal.len() - first as usize) / 2;
+        journal[second_end - 1] ^= 0xFF;
+        fs::write(journal_path(&dir), journal).unwrap();
+
+        let (zone, store) = ZoneStore::open(&dir, file_zone()).unwrap();
+        assert_eq!(zone.serial(), 2);
+        assert!(has_host(&zone, 1) && !has_host(&zone, 2) && !has_host(&zone, 3));
+        assert_eq!(store.pending(), 1);
+        assert_eq!(fs::metadata(journal_path(&dir)).unwrap().len(), first);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_a_corrupt_snapshot() {
+        let dir = store_dir("snapshot");
+        drop(ZoneStore::open(&dir, file_zone()).unwrap());
+        let path = dir.join("example.com.snapshot");
+        let mut snapshot = fs::read(&path).unwrap();
+        snapshot[12] ^= 0xFF;
+        fs::write(&path, snapshot).unwrap();
+
+        let error = ZoneStore::open(&dir, file_zone()).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("Checksum mismatch"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn newer_zone_file_replaces_the_store() {
+        let dir = store_dir("reseed");
+        let (mut zone, mut store) = ZoneStore::open(&dir, file_zone()).unwrap();
+        add_host(&mut zone, &mut store, 1);
+        drop(store);
+
+        let edited = ZONE.replace(" 1 7200", " 5 7200");
+        let (zone, store) =
+            ZoneStore::open(&dir, Zone::parse("example.com", &edited).unwrap()).unwrap();
+        assert_eq!(zone.serial(), 5);
+        assert!(!has_host(&zone, 1));
+        assert_eq!(store.pending(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn serial_arithmetic() {
+        assert!(serial_gt(2, 1));
+        assert!(!serial_gt(1, 1));
+        assert!(!serial_gt(1, 2));
+        // Serials wrap around
+        assert!(serial_gt(0, u32::MAX));
+        assert!(!serial_gt(u32::MAX, 0));
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+}
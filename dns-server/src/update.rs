@@ -0,0 +1,238 @@
+use crate::message::{
+    Query, RCODE_FORMERR, RCODE_NOTZONE, RCODE_NXDOMAIN, RCODE_NXRRSET, RCODE_YXDOMAIN,
+    RCODE_YXRRSET,
+};
+use crate::record::{RawRecord, Record, RecordType, CLASS_IN};
+use crate::zone::{is_in_zone, Change, Zone};
+
+const CLASS_NONE: u16 = 254;
+const CLASS_ANY: u16 = 255;
+const TYPE_ANY: u16 = 255;
+
+// Apply a dynamic update (RFC 2136) to a zone. Prerequisites are checked and every
+// update is validated before the zone is touched, so on error the zone is unchanged.
+// Returns the changes that actually modified the zone, not including the SOA serial.
+pub fn apply_update(zone: &mut Zone, buf: &[u8], query: &Query) -> Result<Vec<Change>, u8> {
+    let prerequisite_count = u16::from_be_bytes([buf[6], buf[7]]);
+    let update_count = u16::from_be_bytes([buf[8], buf[9]]);
+
+    let mut position = query.end_of_question;
+    let mut prerequisites = Vec::new();
+    for _ in 0..prerequisite_count {
+        let (raw, next) = RawRecord::read(buf, position).map_err(|_| RCODE_FORMERR)?;
+        prerequisites.push(raw);
+        position = next;
+    }
+    let mut updates = Vec::new();
+    for _ in 0..update_count {
+        let (raw, next) = RawRecord::read(buf, position).map_err(|_| RCODE_FORMERR)?;
+        updates.push(raw);
+        position = next;
+    }
+
+    check_prerequisites(zone, buf, &prerequisites)?;
+    let updates = prescan_updates(zone, buf, &updates)?;
+
+    let mut changes = Vec::new();
+    for update in updates {
+        match update {
+            Update::Add(record) => add_record(zone, record, &mut changes),
+            Update::DeleteName(name) => {
+                let records: Vec<Record> = zone
+                    .records_at(&name)
+                    .iter()
+                    .filter(|r| !is_protected_apex_type(zone, r))
+                    .cloned()
+                    .collect();
+                for record in records {
+                    commit(zone, Change::Delete(record), &mut changes);
+                }
+            }
+            Update::DeleteRRset(name, record_type) => {
+                let records: Vec<Record> = zone
+                    .records_at(&name)
+                    .iter()
+                    .filter(|r| r.record_type() == record_type)
+                    .filter(|r| !is_protected_apex_type(zone, r))
+                    .cloned()
+                    .collect();
+                for record in records {
+                    commit(zone, Change::Delete(record), &mut changes);
+                }
+            }
+            Update::DeleteRecord(record) => delete_record(zone, record, &mut changes),
+        }
+    }
+
+    Ok(changes)
+}
+
+// An update from the update section, once validated.
+enum Update {
+    Add(Record),
+    DeleteName(String),
+    DeleteRRset(String, RecordType),
+    DeleteRecord(Record),
+}
+
+// Check the prerequisite section (RFC 2136 section 3.2).
+fn check_prerequisites(zone: &Zone, buf: &[u8], prerequisites: &[RawRecord]) -> Result<(), u8> {
+    // Value-dependent prerequisites are compared as whole RRsets once they are all read.
+    let mut required: Vec<Record> = Vec::new();
+
+    for raw in prerequisites {
+        if raw.ttl != 0 {
+            return Err(RCODE_FORMERR);
+        }
+        if !is_in_zone(&raw.name, &zone.origin) {
+            return Err(RCODE_NOTZONE);
+        }
+        let existing = zone.records_at(&raw.name);
+        let has_rrset = |record_type: u16| {
+            existing
+                .iter()
+                .any(|r| r.record_type().to_u16() == record_type)
+        };
+
+        match raw.class {
+            CLASS_ANY | CLASS_NONE if raw.rdlength() != 0 => return Err(RCODE_FORMERR),
+            CLASS_ANY if raw.record_type == TYPE_ANY => {
+                if existing.is_empty() {
+                    return Err(RCODE_NXDOMAIN);
+                }
+            }
+            CLASS_ANY => {
+                if !has_rrset(raw.record_type) {
+                    return Err(RCODE_NXRRSET);
+                }
+            }
+            CLASS_NONE if raw.record_type == TYPE_ANY => {
+                if !existing.is_empty() {
+                    return Err(RCODE_YXDOMAIN);
+                }
+            }
+            CLASS_NONE => {
+                if has_rrset(raw.record_type) {
+                    return Err(RCODE_YXRRSET);
+                }
+            }
+            CLASS_IN => {
+                let record = raw.to_record(buf).map_err(|_| RCODE_FORMERR)?;
+                required.push(record);
+            }
+            _ => return Err(RCODE_FORMERR),
+        }
+    }
+
+    for record in &required {
+        // Compare the RRsets as sorted sets of RDATA, ignoring TTLs and duplicates.
+        let rrset_data = |records: &[Record]| {
+            let mut data: Vec<String> = records
+                .iter()
+                .filter(|r| r.name == record.name && r.record_type() == record.record_type())
+                .map(|r| format!("{:?}", r.data))
+                .collect();
+            data.sort();
+            data.dedup();
+            data
+        };
+        if rrset_data(&required) != rrset_data(zone.records_at(&record.name)) {
+            return Err(RCODE_NXRRSET);
+        }
+    }
+
+    Ok(())
+}
+
+// Validate the update section (RFC 2136 section 3.4.1) before anything is applied.
+fn prescan_updates(zone: &Zone, buf: &[u8], updates: &[RawRecord]) -> Result<Vec<Update>, u8> {
+    let mut validated = Vec::new();
+    for raw in updates {
+        if !is_in_zone(&raw.name, &zone.origin) {
+            return Err(RCODE_NOTZONE);
+        }
+        let update = match raw.class {
+            CLASS_IN => Update::Add(raw.to_record(buf).map_err(|_| RCODE_FORMERR)?),
+            CLASS_ANY if raw.ttl != 0 || raw.rdlength() != 0 => return Err(RCODE_FORMERR),
+            CLASS_ANY if raw.record_type == TYPE_ANY => Update::DeleteName(raw.name.clone()),
+            CLASS_ANY => match RecordType::from_u16(raw.record_type) {
+                Some(record_type) => Update::DeleteRRset(raw.name.clone(), record_type),
+                None => return Err(RCODE_FORMERR),
+            },
+            CLASS_NONE if raw.ttl != 0 => return Err(RCODE_FORMERR),
+            CLASS_NONE => Update::DeleteRecord(raw.to_record(buf).map_err(|_| RCODE_FORMERR)?),
+            _ => return Err(RCODE_FORMERR),
+        };
+        validated.push(update);
+    }
+    Ok(validated)
+}
+
+// Apply a change to the zone and keep it if it modified anything.
+fn commit(zone: &mut Zone, change: Change, changes: &mut Vec<Change>) {
+    if zone.apply(&change) {
+        changes.push(change);
+    }
+}
+
+// The SOA and NS records at the apex can only be replaced, never removed wholesale.
+fn is_protected_apex_type(zone: &Zone, record: &Record) -> bool {
+    record.name == zone.origin && matches!(record.record_type(), RecordType::SOA | RecordType::NS)
+}
+
+fn add_record(zone: &mut Zone, record: Record, changes: &mut Vec<Change>) {
+    // The server owns the SOA serial, so SOA records cannot be added by UPDATE.
+    if record.record_type() == RecordType::SOA {
+        return;
+    }
+
+    let existing: Vec<Record> = zone.records_at(&record.name).to_vec();
+    let is_cname = record.record_type() == RecordType::CNAME;
+    let has_cname = existing
+        .iter()
+        .any(|r| r.record_type() == RecordType::CNAME);
+    let has_other = existing
+        .iter()
+        .any(|r| r.record_type() != RecordType::CNAME);
+
+    // A CNAME cannot coexist with other data at the same name (RFC 2136 section 3.4.2.2).
+    if (is_cname && has_other) || (!is_cname && has_cname) {
+        return;
+    }
+
+    for old in existing {
+        let replaced = if is_cname {
+            old.record_type() == RecordType::CNAME
+        } else {
+            old.data == record.data
+        };
+        if replaced {
+            if old == record {
+                return;
+            }
+            commit(zone, Change::Delete(old), changes);
+        }
+    }
+    commit(zone, Change::Add(record), changes);
+}
+
+fn delete_record(zone: &mut Zone, record: Record, changes: &mut Vec<Change>) {
+    if record.record_type() == RecordType::SOA {
+        return;
+    }
+    let existing = zone.records_at(&record.name);
+    if record.record_type() == RecordType::NS && record.name == zone.origin {
+        let ns_count = existing
+            .iter()
+            .filter(|r| r.record_type() == RecordType::NS)
+            .count();
+        if ns_count <= 1 {
+            return;
+        }
+    }
+
+    // Records are matched on their data only, whatever TTL the update carries.
+    if let Some(old) = existing.iter().find(|r| r.data == record.data).cloned() {
+        commit(zone, Change::Delete(old), changes);
+    }
+}
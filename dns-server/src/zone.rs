@@ -0,0 +1,951 @@
+use crate::record::{
+    Record, RecordData, RecordType, Soa, SvcParam, Svcb, SVC_PARAM_ALPN, SVC_PARAM_ECH,
+    SVC_PARAM_IPV4HINT, SVC_PARAM_IPV6HINT, SVC_PARAM_MANDATORY, SVC_PARAM_NO_DEFAULT_ALPN,
+    SVC_PARAM_PORT,
+};
+use base64::Engine;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Error};
+use std::path::Path;
+
+// The maximum number of CNAMEs followed inside a zone when building an answer.
+const MAX_CNAME_CHAIN: usize = 8;
+
+// The outcome of looking a name up in a zone we are authoritative for.
+#[derive(Debug)]
+pub enum Lookup {
+    // The records answering the question, including any CNAMEs followed on the way.
+    Answer(Vec<Record>),
+    // The name exists but has no records of the requested type.
+    NoData,
+    // The name does not exist in the zone.
+    NxDomain,
+    // The name is at or below a delegation to another zone. Carries the NS records at the
+    // zone cut and any addresses we hold for those name servers (glue).
+    Referral { ns: Vec<Record>, glue: Vec<Record> },
+}
+
+// A single change to the contents of a zone, as applied by UPDATE and kept in the journal.
+#[derive(Debug, Clone)]
+pub enum Change {
+    Add(Record),
+    Delete(Record),
+}
+
+// A zone we are authoritative for, with its records grouped by owner name.
+pub struct Zone {
+    pub origin: String,
+    records: HashMap<String, Vec<Record>>,
+}
+
+impl Zone {
+    // Load a zone from a master-file style zone file.
+    pub fn load(origin: &str, file_path: &Path) -> io::Result<Zone> {
+        let contents = fs::read_to_string(file_path)?;
+        Zone::parse(origin, &contents)
+    }
+
+    // Parse the contents of a zone file. Records are validated to lie inside the zone,
+    // and the zone must have exactly one SOA record at its apex.
+    pub fn parse(origin: &str, contents: &str) -> io::Result<Zone> {
+        let origin = normalise_name(origin);
+        Zone::from_records(&origin, parse_zone_file(&origin, contents)?)
+    }
+
+    // Build a zone from a set of records, with the same validation as zone files.
+    pub fn from_records(origin: &str, records: Vec<Record>) -> io::Result<Zone> {
+        let origin = origin.to_string();
+        let mut zone = Zone {
+            origin: origin.clone(),
+            records: HashMap::new(),
+        };
+
+        for record in records {
+            if !is_in_zone(&record.name, &origin) {
+                return Err(invalid_data(format!(
+                    "Record {} is outside of zone {}",
+                    record.name, origin
+                )));
+            }
+            zone.records
+                .entry(record.name.clone())
+                .or_default()
+                .push(record);
+        }
+
+        let soa_count = zone
+            .records
+            .get(&origin)
+            .map(|records| {
+                records
+                    .iter()
+                    .filter(|r| r.record_type() == RecordType::SOA)
+                    .count()
+            })
+            .unwrap_or(0);
+        if soa_count != 1 {
+            return Err(invalid_data(format!(
+                "Zone {} must have exactly one SOA record at its apex",
+                origin
+            )));
+        }
+
+        Ok(zone)
+    }
+
+    // The SOA record at the zone apex.
+    pub fn soa(&self) -> &Record {
+        self.records[&self.origin]
+            .iter()
+            .find(|r| r.record_type() == RecordType::SOA)
+            .expect("zone is validated to have an SOA record")
+    }
+
+    pub fn serial(&self) -> u32 {
+        match &self.soa().data {
+            RecordData::SOA(soa) => soa.serial,
+            _ => unreachable!("soa() only returns SOA records"),
+        }
+    }
+
+    // The changes that replace the SOA with one whose serial is one higher (RFC 1982
+    // serial arithmetic wraps around).
+    pub fn serial_increment(&self) -> Vec<Change> {
+        let old = self.soa().clone();
+        let mut new = old.clone();
+        if let RecordData::SOA(soa) = &mut new.data {
+            soa.serial = soa.serial.wrapping_add(1);
+        }
+        vec![Change::Delete(old), Change::Add(new)]
+    }
+
+    // The records owned by a name, which is empty if the name does not exist.
+    pub fn records_at(&self, name: &str) -> &[Record] {
+        self.records.get(name).map(|r| r.as_slice()).unwrap_or(&[])
+    }
+
+    // Every record in the zone, in no particular order.
+    pub fn all_records(&self) -> impl Iterator<Item = &Record> {
+        self.records.values().flatten()
+    }
+
+    // Apply a change to the zone. Adding a record that is already present, or deleting one
+    // that is not, leaves the zone untouched and returns false.
+    pub fn apply(&mut self, change: &Change) -> bool {
+        match change {
+            Change::Add(record) => {
+                let records = self.records.entry(record.name.clone()).or_default();
+                if records.contains(record) {
+                    return false;
+                }
+                records.push(record.clone());
+                true
+            }
+            Change::Delete(record) => {
+                let records = match self.records.get_mut(&record.name) {
+                    Some(records) => records,
+                    None => return false,
+                };
+                let before = records.len();
+                records.retain(|r| r != record);
+                let removed = records.len() != before;
+                if records.is_empty() {
+                    self.records.remove(&record.name);
+                }
+                removed
+            }
+        }
+    }
+
+    // The SOA record to put in the authority section of negative answers. Its TTL is the
+    // lesser of the SOA TTL and the SOA minimum field (RFC 2308 section 3).
+    pub fn negative_soa(&self) -> Record {
+        let mut soa = self.soa().clone();
+        if let RecordData::SOA(Soa { minimum, .. }) = &soa.data {
+            soa.ttl = soa.ttl.min(*minimum);
+        }
+        soa
+    }
+
+    // Look up the records of a type for a name inside this zone, following CNAMEs
+    // that point at other names in the zone. Names at or below a delegation get a
+    // referral to the child zone's name servers instead.
+    pub fn lookup(&self, name: &str, record_type: Option<RecordType>) -> Lookup {
+        if let Some(cut) = self.find_cut(name) {
+            return self.referral(cut);
+        }
+
+        let mut answers = Vec::new();
+        let mut current = name.to_string();
+
+        for _ in 0..MAX_CNAME_CHAIN {
+            // CNAMEs leading below a delegation are left for the resolver to follow.
+            if !answers.is_empty() && self.find_cut(&current).is_some() {
+                break;
+            }
+            let records = match self.records.get(&current) {
+                Some(records) => records,
+                None if !answers.is_empty() => break,
+                None if self.has_descendants(&current) => return Lookup::NoData,
+                None => return Lookup::NxDomain,
+            };
+
+            let matching: Vec<Record> = records
+                .iter()
+                .filter(|r| Some(r.record_type()) == record_type)
+                .cloned()
+                .collect();
+            if !matching.is_empty() {
+                answers.extend(matching);
+                break;
+            }
+
+            let cname = records.iter().find_map(|r| match &r.data {
+                RecordData::CNAME(target) => Some((r, target)),
+                _ => None,
+            });
+            match cname {
+                Some((record, target)) => {
+                    answers.push(record.clone());
+                    if !is_in_zone(target, &self.origin) {
+                        break;
+                    }
+                    current = target.clone();
+                }
+                None if answers.is_empty() => return Lookup::NoData,
+                None => break,
+            }
+        }
+
+        Lookup::Answer(answers)
+    }
+
+    // Find the highest zone cut between the apex and the name, i.e. the first name on
+    // the way down from the apex that owns NS records. The apex itself is not a cut.
+    fn find_cut<'a>(&self, name: &'a str) -> Option<&'a str> {
+        let relative = match name.strip_suffix(&self.origin) {
+            Some(relative) if !self.origin.is_empty() => relative.strip_suffix('.')?,
+            _ if self.origin.is_empty() => name,
+            _ => return None,
+        };
+        if relative.is_empty() {
+            return None;
+        }
+
+        // Walk the ancestors of the name from the one just below the apex downwards.
+        let label_starts: Vec<usize> = std::iter::once(0)
+            .chain(relative.match_indices('.').map(|(i, _)| i + 1))
+            .collect();
+        label_starts
+            .iter()
+            .rev()
+            .map(|&start| &name[start..])
+            .find(|candidate| {
+                self.records_at(candidate)
+                    .iter()
+                    .any(|r| r.record_type() == RecordType::NS)
+            })
+    }
+
+    // Build a referral to the name servers of the child zone at a cut, along with any
+    // of their addresses held in this zone.
+    fn referral(&self, cut: &str) -> Lookup {
+        let ns: Vec<Record> = self
+            .records_at(cut)
+            .iter()
+            .filter(|r| r.record_type() == RecordType::NS)
+            .cloned()
+            .collect();
+        let glue = ns
+            .iter()
+            .filter_map(|r| match &r.data {
+                RecordData::NS(target) => Some(target),
+                _ => None,
+            })
+            .flat_map(|target| self.records_at(target))
+            .filter(|r| matches!(r.record_type(), RecordType::A | RecordType::AAAA))
+            .cloned()
+            .collect();
+        Lookup::Referral { ns, glue }
+    }
+
+    // Whether any name in the zone sits below the given name, which makes the
+    // name an empty non-terminal rather than non-existent.
+    fn has_descendants(&self, name: &str) -> bool {
+        let suffix = format!(".{}", name);
+        self.records.keys().any(|owner| owner.ends_with(&suffix))
+    }
+}
+
+// The set of zones served, used to find the zone responsible for a query.
+pub struct Catalog {
+    zones: Vec<Zone>,
+}
+
+impl Catalog {
+    pub fn new(zones: Vec<Zone>) -> Self {
+        Catalog { zones }
+    }
+
+    // The zone with exactly this origin, as named in the zone section of an UPDATE.
+    pub fn zone_mut(&mut self, origin: &str) -> Option<&mut Zone> {
+        self.zones.iter_mut().find(|zone| zone.origin == origin)
+    }
+
+    // Find the zone with the longest origin that encloses the name, if any.
+    pub fn find_zone(&self, name: &str) -> Option<&Zone> {
+        self.zones
+            .iter()
+            .filter(|zone| is_in_zone(name, &zone.origin))
+            .max_by_key(|zone| zone.origin.split('.').filter(|l| !l.is_empty()).count())
+    }
+}
+
+// Whether a name is equal to or below the zone origin. The root zone encloses everything.
+pub fn is_in_zone(name: &str, origin: &str) -> bool {
+    origin.is_empty() || name == origin || name.ends_with(&format!(".{}", origin))
+}
+
+// Lowercase a name and drop the trailing dot so names can be compared directly.
+pub fn normalise_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn invalid_data(message: String) -> Error {
+    Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// An entry in a zone file after comments and parentheses have been dealt with.
+struct Entry {
+    line_number: usize,
+    // Entries starting with whitespace reuse the owner name of the previous record.
+    inherits_owner: bool,
+    tokens: Vec<String>,
+}
+
+// Split the zone file into entries, joining lines wrapped in parentheses and
+// stripping comments that are not inside quotes.
+fn tokenize(contents: &str) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut current: Option<Entry> = None;
+    let mut depth = 0;
+
+    for (index, line) in contents.lines().enumerate() {
+        let entry = current.get_or_insert_with(|| Entry {
+            line_number: index + 1,
+            inherits_owner: line.starts_with(' ') || line.starts_with('\t'),
+            tokens: Vec::new(),
+        });
+
+        let mut chars = line.chars().peekable();
+        let mut token = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                ';' => break,
+                '"' => {
+                    for c in chars.by_ref() {
+                        if c == '"' {
+                            break;
+                        }
+                        token.push(c);
+                    }
+                    entry.tokens.push(std::mem::take(&mut token));
+                }
+                '(' | ')' | ' ' | '\t' => {
+                    if !token.is_empty() {
+                        entry.tokens.push(std::mem::take(&mut token));
+                    }
+                    if c == '(' {
+                        depth += 1;
+                    } else if c == ')' {
+                        if depth == 0 {
+                            return Err(invalid_data(format!(
+                                "Unbalanced parentheses on line {}",
+                                index + 1
+                            )));
+                        }
+                        depth -= 1;
+                    }
+                }
+                _ => token.push(c),
+            }
+        }
+        if !token.is_empty() {
+            entry.tokens.push(token);
+        }
+
+        if depth == 0 {
+            if let Some(entry) = current.take() {
+                if !entry.tokens.is_empty() {
+                    entries.push(entry);
+                }
+            }
+        }
+    }
+
+    if depth != 0 {
+        return Err(invalid_data(
+            "Unterminated parentheses at end of zone file".into(),
+        ));
+    }
+
+    Ok(entries)
+}
+
+// Resolve a name from a zone file against the current origin.
+fn resolve_name(name: &str, origin: &str) -> String {
+    if name == "@" {
+        origin.to_string()
+    } else if name.ends_with('.') || origin.is_empty() {
+        normalise_name(name)
+    } else {
+        format!("{}.{}", normalise_name(name), origin)
+    }
+}
+
+// Parse the records in a zone file. Supports $ORIGIN and $TTL directives, "@" for the
+// origin, relative names, omitted owner names and multi-line records in parentheses.
+fn parse_zone_file(origin: &str, contents: &str) -> io::Result<Vec<Record>> {
+    let mut origin = origin.to_string();
+    let mut default_ttl: Option<u32> = None;
+    let mut last_owner: Option<String> = None;
+    let mut records = Vec::new();
+
+    for entry in tokenize(contents)? {
+        let line_number = entry.line_number;
+        let mut tokens = entry.tokens.into_iter();
+
+        let owner = if entry.inherits_owner {
+            last_owner.clone().ok_or_else(|| {
+                invalid_data(format!("No owner name for record on line {}", line_number))
+            })?
+        } else {
+            let first = tokens.next().unwrap_or_default();
+            match first.to_ascii_uppercase().as_str() {
+                "$ORIGIN" => {
+                    let value = tokens.next().ok_or_else(|| {
+                        invalid_data(format!("Missing $ORIGIN value on line {}", line_number))
+                    })?;
+                    origin = resolve_name(&value, &origin);
+                    continue;
+                }
+                "$TTL" => {
+                    let value = tokens.next().unwrap_or_default();
+                    default_ttl = Some(value.parse().map_err(|_| {
+                        invalid_data(format!("Invalid $TTL on line {}", line_number))
+                    })?);
+                    continue;
+                }
+                _ => resolve_name(&first, &origin),
+            }
+        };
+        last_owner = Some(owner.clone());
+
+        // The TTL and class are both optional and may come in either order.
+        let mut ttl = default_ttl;
+        let mut record_type = None;
+        for token in tokens.by_ref() {
+            if let Ok(value) = token.parse::<u32>() {
+                ttl = Some(value);
+            } else if token.eq_ignore_ascii_case("IN") {
+                continue;
+            } else {
+                record_type = Some(token);
+                break;
+            }
+        }
+
+        let record_type = record_type
+            .ok_or_else(|| invalid_data(format!("Missing record type on line {}", line_number)))?;
+        let record_type = RecordType::from_mnemonic(&record_type).ok_or_else(|| {
+            invalid_data(format!(
+                "Unsupported record type {} on line {}",
+                record_type, line_number
+            ))
+        })?;
+        let ttl =
+            ttl.ok_or_else(|| invalid_data(format!("No TTL for record on line {}", line_number)))?;
+        let rdata: Vec<String> = tokens.collect();
+        let data = parse_rdata(record_type, &rdata, &origin)
+            .map_err(|message| invalid_data(format!("{} on line {}", message, line_number)))?;
+
+        records.push(Record {
+            name: owner,
+            ttl,
+            data,
+        });
+    }
+
+    Ok(records)
+}
+
+// Parse the RDATA fields of a record of the given type.
+fn parse_rdata(
+    record_type: RecordType,
+    rdata: &[String],
+    origin: &str,
+) -> Result<RecordData, String> {
+    let field = |index: usize| -> Result<&str, String> {
+        rdata
+            .get(index)
+            .map(|s| s.as_str())
+            .ok_or_else(|| format!("Missing field {} in {} record", index + 1, record_type))
+    };
+    let number = |index: usize| -> Result<u32, String> {
+        field(index)?
+            .parse()
+            .map_err(|_| format!("Invalid number in {} record", record_type))
+    };
+
+    let data = match record_type {
+        RecordType::A => RecordData::A(
+            field(0)?
+                .parse()
+                .map_err(|_| "Invalid IP address".to_string())?,
+        ),
+        RecordType::AAAA => RecordData::AAAA(
+            field(0)?
+                .parse()
+                .map_err(|_| "Invalid IPv6 address".to_string())?,
+        ),
+        RecordType::NS => RecordData::NS(resolve_name(field(0)?, origin)),
+        RecordType::CNAME => RecordData::CNAME(resolve_name(field(0)?, origin)),
+        RecordType::PTR => RecordData::PTR(resolve_name(field(0)?, origin)),
+        RecordType::MX => RecordData::MX {
+            preference: field(0)?
+                .parse()
+                .map_err(|_| "Invalid MX preference".to_string())?,
+            exchange: resolve_name(field(1)?, origin),
+        },
+        RecordType::TXT => {
+            if rdata.is_empty() {
+                return Err("Missing text in TXT record".into());
+            }
+            if rdata.iter().any(|s| s.len() > 255) {
+                return Err("TXT strings must be at most 255 bytes".into());
+            }
+            RecordData::TXT(rdata.to_vec())
+        }
+        RecordType::SOA => RecordData::SOA(Soa {
+            mname: resolve_name(field(0)?, origin),
+            rname: resolve_name(field(1)?, origin),
+            serial: number(2)?,
+            refresh: number(3)?,
+            retry: number(4)?,
+            expire: number(5)?,
+            minimum: number(6)?,
+        }),
+        RecordType::SVCB => RecordData::SVCB(parse_svcb(rdata, origin)?),
+        RecordType::HTTPS => RecordData::HTTPS(parse_svcb(rdata, origin)?),
+        RecordType::CAA => {
+            let flags = field(0)?
+                .parse()
+                .map_err(|_| "Invalid CAA flags".to_string())?;
+            let tag = field(1)?;
+            if tag.is_empty() || tag.len() > 15 || !tag.chars().all(|c| c.is_ascii_alphanumeric()) {
+                return Err(format!("Invalid CAA tag {}", tag));
+            }
+            RecordData::CAA {
+                flags,
+                tag: tag.to_ascii_lowercase(),
+                value: field(2)?.as_bytes().to_vec(),
+            }
+        }
+    };
+
+    Ok(data)
+}
+
+// Parse the RDATA of an SVCB or HTTPS record: a priority, a target name and a list
+// of SvcParams in key=value form (RFC 9460 section 2.1).
+fn parse_svcb(rdata: &[String], origin: &str) -> Result<Svcb, String> {
+    let priority = rdata
+        .first()
+        .ok_or("Missing priority in SVCB record")?
+        .parse()
+        .map_err(|_| "Invalid SVCB priority".to_string())?;
+    let target = match rdata.get(1).map(|s| s.as_str()) {
+        Some(".") => String::new(),
+        Some(name) => resolve_name(name, origin),
+        None => return Err("Missing target in SVCB record".into()),
+    };
+
+    let mut params = Vec::new();
+    for token in &rdata[2..] {
+        let (key, value) = match token.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (token.as_str(), None),
+        };
+        params.push(parse_svc_param(key, value)?);
+    }
+    params.sort_by_key(|param| param.key());
+
+    if params.windows(2).any(|pair| pair[0].key() == pair[1].key()) {
+        return Err("Duplicate SvcParam key in SVCB record".into());
+    }
+    if priority == 0 && !params.is_empty() {
+        return Err("SVCB records in AliasMode must not have SvcParams".into());
+    }
+    let keys: Vec<u16> = params.iter().map(|param| param.key()).collect();
+    for param in &params {
+        match param {
+            SvcParam::Mandatory(mandatory) => {
+                if let Some(missing) = mandatory.iter().find(|key| !keys.contains(key)) {
+                    return Err(format!("Mandatory SvcParam key{} is missing", missing));
+                }
+            }
+            SvcParam::NoDefaultAlpn if !keys.contains(&SVC_PARAM_ALPN) => {
+                return Err("no-default-alpn requires an alpn SvcParam".into());
+            }
+            _ => {}
+        }
+    }
+
+    Ok(Svcb {
+        priority,
+        target,
+        params,
+    })
+}
+
+// Map an SvcParamKey name to its number, accepting the generic keyNNNNN form.
+fn parse_svc_param_key(name: &str) -> Result<u16, String> {
+    match name {
+        "mandatory" => Ok(SVC_PARAM_MANDATORY),
+        "alpn" => Ok(SVC_PARAM_ALPN),
+        "no-default-alpn" => Ok(SVC_PARAM_NO_DEFAULT_ALPN),
+        "port" => Ok(SVC_PARAM_PORT),
+        "ipv4hint" => Ok(SVC_PARAM_IPV4HINT),
+        "ech" => Ok(SVC_PARAM_ECH),
+        "ipv6hint" => Ok(SVC_PARAM_IPV6HINT),
+        _ => name
+            .strip_prefix("key")
+            .and_then(|number| number.parse::<u16>().ok())
+            .filter(|key| *key != 65535)
+            .ok_or_else(|| format!("Unknown SvcParam key {}", name)),
+    }
+}
+
+fn parse_svc_param(name: &str, value: Option<&str>) -> Result<SvcParam, String> {
+    let key = parse_svc_param_key(&name.to_ascii_lowercase())?;
+    let value = match (key, value) {
+        (SVC_PARAM_NO_DEFAULT_ALPN, None) => return Ok(SvcParam::NoDefaultAlpn),
+        (SVC_PARAM_NO_DEFAULT_ALPN, Some(_)) => {
+            return Err("no-default-alpn does not take a value".into())
+        }
+        (_, Some(value)) => value,
+        (_, None) => return Err(format!("SvcParam {} requires a value", name)),
+    };
+    let list = || value.split(',').filter(|item| !item.is_empty());
+
+    let param = match key {
+        SVC_PARAM_MANDATORY => {
+            let mut keys = list()
+                .map(parse_svc_param_key)
+                .collect::<Result<Vec<u16>, String>>()?;
+            keys.sort_unstable();
+            keys.dedup();
+            if keys.is_empty() || keys.contains(&SVC_PARAM_MANDATORY) {
+                return Err("Invalid mandatory SvcParam".into());
+            }
+            SvcParam::Mandatory(keys)
+        }
+        SVC_PARAM_ALPN => {
+            let ids: Vec<String> = list().map(|id| id.to_string()).collect();
+            if ids.is_empty() || ids.iter().any(|id| id.len() > 255) {
+                return Err("Invalid alpn SvcParam".into());
+            }
+            SvcParam::Alpn(ids)
+        }
+        SVC_PARAM_PORT => SvcParam::Port(
+            value
+                .parse()
+                .map_err(|_| "Invalid port SvcParam".to_string())?,
+        ),
+        SVC_PARAM_IPV4HINT => SvcParam::Ipv4Hint(
+            list()
+                .map(|ip| ip.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|_| "Invalid ipv4hint SvcParam".to_string())?,
+        ),
+        SVC_PARAM_IPV6HINT => SvcParam::Ipv6Hint(
+            list()
+                .map(|ip| ip.parse())
+                .collect::<Result<_, _>>()
+                .map_err(|_| "Invalid ipv6hint SvcParam".to_string())?,
+        ),
+        SVC_PARAM_ECH => SvcParam::Ech(
+            base64::engine::general_purpose::STANDARD
+                .decode(value)
+                .map_err(|_| "Invalid base64 in ech SvcParam".to_string())?,
+        ),
+        _ => SvcParam::Unknown(key, value.as_bytes().to_vec()),
+    };
+
+    Ok(param)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const ZONE: &str = "\
+$ORIGIN example.com.
+$TTL 3600
+@       IN  SOA  ns1 admin.example.com. (
+                 2       ; serial
+                 7200 3600 1209600
+                 300 )   ; minimum
+        IN  NS   ns1
+ns1     300 IN A 192.0.2.53
+www     IN  CNAME web
+web     IN  A    192.0.2.1
+alias   IN  CNAME www.example.com.
+out     IN  CNAME www.example.net.
+a.b.c   IN  TXT  \"v=spf1 -all; really\" second
+child   IN  NS   ns.child
+ns.child IN A    192.0.2.99
+";
+
+    fn zone() -> Zone {
+        Zone::parse("Example.COM.", ZONE).unwrap()
+    }
+
+    fn a(name: &str, ttl: u32, address: [u8; 4]) -> Record {
+        Record {
+            name: name.to_string(),
+            ttl,
+            data: RecordData::A(Ipv4Addr::from(address)),
+        }
+    }
+
+    fn parse_error(contents: &str) -> String {
+        match Zone::parse("example.com", contents) {
+            Ok(_) => panic!("Parsed an invalid zone file"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_a_zone_file() {
+        let zone = zone();
+        assert_eq!(zone.origin, "example.com");
+        assert_eq!(zone.serial(), 2);
+        match &zone.soa().data {
+            RecordData::SOA(soa) => {
+                assert_eq!(soa.mname, "ns1.example.com");
+                assert_eq!(soa.rname, "admin.example.com");
+                assert_eq!(soa.expire, 1209600);
+                assert_eq!(soa.minimum, 300);
+            }
+            other => panic!("Unexpected SOA data {:?}", other),
+        }
+
+        // The owner carries on from the SOA, and the TTL from $TTL unless given
+        let ns = &zone.records_at("example.com")[1];
+        assert_eq!(ns.data, RecordData::NS("ns1.example.com".to_string()));
+        assert_eq!(ns.ttl, 3600);
+        assert_eq!(
+            zone.records_at("ns1.example.com"),
+            [a("ns1.example.com", 300, [192, 0, 2, 53])]
+        );
+
+        // Quoted strings keep their spaces and semicolons
+        let txt = &zone.records_at("a.b.c.example.com")[0];
+        assert_eq!(
+            txt.data,
+            RecordData::TXT(vec!["v=spf1 -all; really".into(), "second".into()])
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_zone_files() {
+        let soa = "@ 300 IN SOA ns1 admin 1 2 3 4 5\n";
+        assert!(parse_error("@ 300 IN A 192.0.2.1\n").contains("exactly one SOA"));
+        assert!(parse_error(&format!("{}{}", soa, soa)).contains("exactly one SOA"));
+        assert!(
+            parse_error(&format!("{}www.example.net. 300 A 192.0.2.1\n", soa))
+                .contains("outside of zone")
+        );
+        assert!(parse_error(&format!("{}www A 192.0.2.1\n", soa)).contains("No TTL"));
+        assert!(parse_error(&format!("{}www 300 A 192.0.2\n", soa)).contains("line 2"));
+        assert!(parse_error(&format!("{}www 300 NAPTR 1\n", soa)).contains("Unsupported"));
+        assert!(parse_error("@ 300 IN SOA ns1 admin ( 1 2 3 4 5\n").contains("Unterminated"));
+        assert!(parse_error("@ 300 IN SOA ns1 admin ) 1 2 3 4 5\n").contains("Unbalanced"));
+        assert!(parse_error("  300 IN A 192.0.2.1\n").contains("No owner name"));
+    }
+
+    #[test]
+    fn looks_up_answers() {
+        let zone = zone();
+        match zone.lookup("web.example.com", Some(RecordType::A)) {
+            Lookup::Answer(records) => {
+                assert_eq!(records, [a("web.example.com", 3600, [192, 0, 2, 1])])
+            }
+            other => panic!("Unexpected lookup {:?}", other),
+        }
+
+        // CNAMEs are followed inside the zone, and left for the resolver outside it
+        match zone.lookup("alias.example.com", Some(RecordType::A)) {
+            Lookup::Answer(records) => {
+                let owners: Vec<&str> = records.iter().map(|r| r.name.as_str()).collect();
+                assert_eq!(
+                    owners,
+                    ["alias.example.com", "www.example.com", "web.example.com"]
+                );
+            }
+            other => panic!("Unexpected lookup {:?}", other),
+        }
+        match zone.lookup("out.example.com", Some(RecordType::A)) {
+            Lookup::Answer(records) => assert_eq!(records.len(), 1),
+            other => panic!("Unexpected lookup {:?}", other),
+        }
+    }
+
+    #[test]
+    fn looks_up_negative_answers() {
+        let zone = zone();
+        assert!(matches!(
+            zone.lookup("web.example.com", Some(RecordType::AAAA)),
+            Lookup::NoData
+        ));
+        // b.c.example.com has no records, but a name below it does
+        assert!(matches!(
+            zone.lookup("b.c.example.com", Some(RecordType::A)),
+            Lookup::NoData
+        ));
+        assert!(matches!(
+            zone.lookup("nope.example.com", Some(RecordType::A)),
+            Lookup::NxDomain
+        ));
+        assert_eq!(zone.negative_soa().ttl, 300);
+    }
+
+    #[test]
+    fn refers_to_delegated_zones() {
+        match zone().lookup("www.child.example.com", Some(RecordType::A)) {
+            Lookup::Referral { ns, glue } => {
+                assert_eq!(ns.len(), 1);
+                assert_eq!(glue, [a("ns.child.example.com", 3600, [192, 0, 2, 99])]);
+            }
+            other => panic!("Unexpected lookup {:?}", other),
+        }
+    }
+
+    #[test]
+    fn finds_the_closest_zone() {
+        let soa = "@ 300 IN SOA ns1 admin 1 2 3 4 5\n";
+        let catalog = Catalog::new(vec![
+            Zone::parse("", soa).unwrap(),
+            Zone::parse("example.com", soa).unwrap(),
+            Zone::parse("sub.example.com", soa).unwrap(),
+        ]);
+        let origin = |name: &str| catalog.find_zone(name).map(|zone| zone.origin.clone());
+        assert_eq!(origin("www.sub.example.com").unwrap(), "sub.example.com");
+        assert_eq!(origin("www.example.com").unwrap(), "example.com");
+        assert_eq!(origin("notexample.com").unwrap(), "");
+    }
+
+    fn svcb_error(rdata: &str) -> String {
+        let rdata: Vec<String> = rdata.split_whitespace().map(String::from).collect();
+        parse_svcb(&rdata, "example.com").unwrap_err()
+    }
+
+    #[test]
+    fn parses_service_bindings() {
+        let contents = "\
+@ 300 IN SOA ns1 admin 1 2 3 4 5
+@ 300 IN HTTPS 1 . alpn=h3,h2 port=8443 ipv4hint=192.0.2.1,192.0.2.2 mandatory=port,alpn
+_dns 300 IN SVCB 0 ns1
+svc 300 IN SVCB 2 svc.example.net. alpn=h2 no-default-alpn key65000=custom ech=AQID
+";
+        let zone = Zone::parse("example.com", contents).unwrap();
+        let https = zone
+            .records_at("example.com")
+            .iter()
+            .find(|r| r.record_type() == RecordType::HTTPS)
+            .unwrap();
+        // The apex is the target, and the params are kept sorted by key
+        assert_eq!(
+            https.data,
+            RecordData::HTTPS(Svcb {
+                priority: 1,
+                target: String::new(),
+                params: vec![
+                    SvcParam::Mandatory(vec![SVC_PARAM_ALPN, SVC_PARAM_PORT]),
+                    SvcParam::Alpn(vec!["h3".into(), "h2".into()]),
+                    SvcParam::Port(8443),
+                    SvcParam::Ipv4Hint(vec![
+                        "192.0.2.1".parse().unwrap(),
+                        "192.0.2.2".parse().unwrap()
+                    ]),
+                ],
+            })
+        );
+
+        let alias = &zone.records_at("_dns.example.com")[0];
+        assert_eq!(
+            alias.data,
+            RecordData::SVCB(Svcb {
+                priority: 0,
+                target: "ns1.example.com".into(),
+                params: Vec::new(),
+            })
+        );
+
+        let RecordData::SVCB(svc) = &zone.records_at("svc.example.com")[0].data else {
+            panic!("Expected an SVCB record");
+        };
+        assert_eq!(svc.target, "svc.example.net");
+        assert_eq!(
+            svc.params[1..],
+            [
+                SvcParam::NoDefaultAlpn,
+                SvcParam::Ech(vec![1, 2, 3]),
+                SvcParam::Unknown(65000, b"custom".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_service_bindings() {
+        assert!(svcb_error("1 . alpn=h2 alpn=h3").contains("Duplicate"));
+        assert!(svcb_error("0 . alpn=h2").contains("AliasMode"));
+        assert!(svcb_error("1 . mandatory=port alpn=h2").contains("key3 is missing"));
+        assert!(svcb_error("1 . mandatory=mandatory").contains("Invalid mandatory"));
+        assert!(svcb_error("1 . no-default-alpn").contains("requires an alpn"));
+        assert!(svcb_error("1 . no-default-alpn=x alpn=h2").contains("does not take a value"));
+        assert!(svcb_error("1 . port=http").contains("Invalid port"));
+        assert!(svcb_error("1 . ipv4hint=2001:db8::1").contains("Invalid ipv4hint"));
+        assert!(svcb_error("1 . ech=!!").contains("Invalid base64"));
+        assert!(svcb_error("1 . key65535=x").contains("Unknown SvcParam key"));
+        assert!(svcb_error("1 . color=blue").contains("Unknown SvcParam key"));
+        assert!(svcb_error("1").contains("Missing target"));
+    }
+
+    #[test]
+    fn parses_caa_records() {
+        let data = |rdata: &str| {
+            let rdata: Vec<String> = rdata.split_whitespace().map(String::from).collect();
+            parse_rdata(RecordType::CAA, &rdata, "example.com")
+        };
+        assert_eq!(
+            data("128 ISSUE letsencrypt.org").unwrap(),
+            RecordData::CAA {
+                flags: 128,
+                tag: "issue".into(),
+                value: b"letsencrypt.org".to_vec(),
+            }
+        );
+        assert!(data("0 is-sue ca.example")
+            .unwrap_err()
+            .contains("Invalid CAA tag"));
+        assert!(data("0 issuewildcardtoolong ca.example").is_err());
+        assert!(data("256 issue ca.example")
+            .unwrap_err()
+            .contains("Invalid CAA flags"));
+        assert!(data("0 issue").is_err());
+    }
+}
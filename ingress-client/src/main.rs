@@ -5,6 +5,7 @@ use reqwest::{
     multipart,
 };
 use std::{error::Error, net::Ipv4Addr, fs::File, io::Read};
+use rand::Rng;
 use tokio::net::UdpSocket;
 use url::Url;
 
@@ -41,16 +42,28 @@ struct Args {
     endpoint: String,
 }
 
+// Bind a UDP socket to a random unprivileged port on the loopback interface
+async fn bind_random_port() -> Result<UdpSocket, Box<dyn Error>> {
+    for _ in 0..8 {
+        let port = rand::thread_rng().gen_range(1024..=u16::MAX);
+        if let Ok(socket) = UdpSocket::bind(("127.0.0.1", port)).await {
+            return Ok(socket);
+        }
+    }
+    Ok(UdpSocket::bind("127.0.0.1:0").await?)
+}
+
 // Query the DNS resolver for the IP address of a domain
 async fn query_dns_resolver(domain: &str) -> Result<Ipv4Addr, Box<dyn Error>> {
-    // Connect to the DNS resolver
+    // Connect to the DNS resolver from a random source port
     let resolver_addr = "127.0.0.1:5354";
-    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let socket = bind_random_port().await?;
     socket.connect(resolver_addr).await?;
 
-    // Construct the DNS query message
+    // Construct the DNS query message with a random transaction ID
+    let transaction_id: u16 = rand::random();
     let mut query = Vec::with_capacity(512);
-    query.extend_from_slice(&[0x00, 0x01]); // Transaction ID
+    query.extend_from_slice(&transaction_id.to_be_bytes()); // Transaction ID
     query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]); // Flags and Counts
     for label in domain.split('.') {
         query.push(label.len() as u8);
@@ -61,9 +74,23 @@ async fn query_dns_resolver(domain: &str) -> Result<Ipv4Addr, Box<dyn Error>> {
 
     socket.send(&query).await?;
 
-    // Receive the DNS response
+    // Receive the DNS response, ignoring any whose transaction ID or question
+    // (name, type and class) does not match the query
     let mut response = [0u8; 512];
-    let _ = socket.recv(&mut response).await?;
+    let len = loop {
+        let len = socket.recv(&mut response).await?;
+        let matches = len >= 12
+            && response[0..2] == transaction_id.to_be_bytes()
+            && response[2] & 0x80 != 0
+            && response[4..6] == [0x00, 0x01]
+            && response[12..len]
+                .get(..query.len() - 12)
+                .is_some_and(|question| question.eq_ignore_ascii_case(&query[12..]));
+        if matches {
+            break len;
+        }
+        eprintln!("Ignoring DNS response that does not match the query");
+    };
 
     // Check for NXDOMAIN response
     // The RCODE is the last four bits of the second byte of the flags section
@@ -74,14 +101,48 @@ async fn query_dns_resolver(domain: &str) -> Result<Ipv4Addr, Box<dyn Error>> {
         return Err("NXDOMAIN: The domain name does not exist.".into());
     }
 
-    let ip_start = 14 + (domain.len() + 2) + 4 + 10; // Skip to the answer part
-    let ip_address = Ipv4Addr::new(
-        response[ip_start],
-        response[ip_start + 1],
-        response[ip_start + 2],
-        response[ip_start + 3],
-    );
-    Ok(ip_address)
+    if rcode != 0 {
+        return Err(format!("DNS query failed with rcode {}", rcode).into());
+    }
+
+    // Walk the answer section, which may hold CNAMEs ahead of the address records,
+    // and take the first A record
+    let response = &response[..len];
+    let answer_count = u16::from_be_bytes([response[6], response[7]]);
+    let mut position = skip_name(response, 12)? + 4; // Skip the question
+    for _ in 0..answer_count {
+        position = skip_name(response, position)?;
+        let fixed = response
+            .get(position..position + 10)
+            .ok_or("Truncated DNS answer")?;
+        let record_type = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let rdlength = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        position += 10;
+        if record_type == 1 && rdlength == 4 {
+            let ip: [u8; 4] = response
+                .get(position..position + 4)
+                .ok_or("Truncated DNS answer")?
+                .try_into()?;
+            return Ok(Ipv4Addr::from(ip));
+        }
+        position += rdlength;
+    }
+    Err("No A record in the DNS response".into())
+}
+
+// Skip over a possibly compressed domain name, returning the position after it
+fn skip_name(buf: &[u8], mut position: usize) -> Result<usize, &'static str> {
+    loop {
+        let length = *buf.get(position).ok_or("Truncated domain name")?;
+        if length == 0 {
+            return Ok(position + 1);
+        }
+        if length & 0xC0 == 0xC0 {
+            // A compression pointer ends the name
+            return Ok(position + 2);
+        }
+        position += 1 + length as usize;
+    }
 }
 
 fn extract_host(url_str: &str) -> Result<String, &'static str> {